@@ -1,10 +1,20 @@
 use std::env;
+use std::time::Duration;
+use tunneled::core::acl::{AddressRule, Cidr, ForwardRule, SourceFilter};
+use tunneled::core::compression::Compression;
+use tunneled::core::forward::ConnectionLimits;
+use tunneled::core::health::HealthCheck;
+use tunneled::core::output::OutputFormat;
+use tunneled::core::shared::{TunnelProtocol, parse_duration};
 use libstrawberry::colors::{BOLD, C_RESET, RED, RESET};
 use std::collections::HashMap;
 
 #[derive(Clone)]
 pub enum Command {
     Local,
+    Connect,
+    Forward,
+    Proxy,
     Server,
     Compose,
     Login,
@@ -14,18 +24,45 @@ pub enum Command {
 }
 
 #[derive(Default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct ServerOptions {
     pub min_port: u16,
     pub max_port: u16,
     pub secret: Option<String>,
     pub require_id: bool,
     pub control_port: u16,
+    pub websocket_port: Option<u16>,
+    pub trusted_proxies: Vec<Cidr>,
+    pub quic_port: Option<u16>,
+    pub quic_cert: Option<String>,
+    pub quic_key: Option<String>,
     pub config_file: Option<String>,
     pub verbose_logging: bool,
-    pub tunnels_addr: String
+    pub tunnels_addr: String,
+    pub pid_file: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub max_lifetime: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub connection_limits: ConnectionLimits,
+    pub forward_rules: Vec<ForwardRule>,
+    pub no_compression: bool,
+    pub tui: bool,
+}
+
+/// What `compose` does with the services of a file.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum ComposeAction {
+    #[default]
+    Up,
+    Ps,
+    Logs,
+    Down,
+    Config,
 }
 
 #[derive(Default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct ClientOptions {
     pub host: String,
     pub port: u16,
@@ -35,7 +72,30 @@ pub struct ClientOptions {
     pub control_port: u16,
     pub static_port: Option<u16>,
     pub compose_file: Option<String>,
+    pub compose_action: ComposeAction,
+    pub compose_service: Option<String>,
+    /// Positions of `-d` in the arguments, dropped when compose starts again in the background.
+    pub detach_flags: Vec<usize>,
+    pub follow: bool,
+    pub watch: bool,
+    pub profiles: Vec<String>,
     pub verbose_logging: bool,
+    pub password: Option<String>,
+    pub protocol: TunnelProtocol,
+    pub compression: Option<Compression>,
+    pub listen_port: Option<u16>,
+    pub ttl: Option<Duration>,
+    pub connection_limits: ConnectionLimits,
+    pub health_check: Option<HealthCheck>,
+    pub unix_socket: Option<String>,
+    pub share: Option<String>,
+    pub weight: Option<u32>,
+    pub forward_target: Option<String>,
+    pub proxy_rules: Vec<AddressRule>,
+    pub sources: SourceFilter,
+    pub output: OutputFormat,
+    pub assignments_file: Option<String>,
+    pub outbound_proxy: Option<String>,
 }
 
 #[derive(Default)]
@@ -44,11 +104,11 @@ pub struct Options {
     pub client_options: ClientOptions,
 }
 
+#[allow(clippy::struct_field_names)]
 pub struct Args {
     pub args: Vec<String>,
     pub command: Command,
     pub command_str: String,
-    pub options: Options,
 }
 
 impl Args {
@@ -60,11 +120,13 @@ impl Args {
             args: args.clone(),
             command: Command::None,
             command_str: args.first().cloned().unwrap_or_default(),
-            options: Options::default(),
         };
 
         let command_map = HashMap::from([
             ("local", Command::Local),
+            ("connect", Command::Connect),
+            ("forward", Command::Forward),
+            ("proxy", Command::Proxy),
             ("server", Command::Server),
             ("login", Command::Login),
             ("about", Command::About),
@@ -80,7 +142,8 @@ impl Args {
         result
     }
 
-    pub fn collect_options(&mut self) -> Options {
+    #[allow(clippy::too_many_lines)]
+    pub fn collect_options(&self) -> Options {
         let mut options = Options {
             server_options: ServerOptions {
                 min_port: 1024,
@@ -99,6 +162,7 @@ impl Args {
         };
 
         let mut iter = self.args.iter().skip(1);
+        let mut compose_action = None;
 
         while let Some(arg) = iter.next() {
             match arg.as_str() {
@@ -107,7 +171,7 @@ impl Args {
                 "-cp" | "--control-port" => parse_u16(
                     iter.next(),
                     match &self.command {
-                        Command::Local => &mut options.client_options.control_port,
+                        Command::Local | Command::Forward | Command::Proxy => &mut options.client_options.control_port,
                         Command::Server => &mut options.server_options.control_port,
                         _ => continue,
                     },
@@ -119,6 +183,7 @@ impl Args {
                     }
                 }
                 "-h" | "--address" => parse_string(iter.next(), &mut options.client_options.host, "address"),
+                "--unix" => parse_optional_string(iter.next(), &mut options.client_options.unix_socket, "unix socket path"),
                 "-f" | "--file" => parse_file(
                     iter.next(),
                     match &self.command {
@@ -131,7 +196,7 @@ impl Args {
                 "-s" | "--secret" => parse_optional_string(
                     iter.next(),
                     match &self.command {
-                        Command::Local => &mut options.client_options.secret,
+                        Command::Local | Command::Forward | Command::Proxy => &mut options.client_options.secret,
                         Command::Server => &mut options.server_options.secret,
                         _ => continue,
                     },
@@ -140,12 +205,162 @@ impl Args {
                 "--min-port" => parse_u16(iter.next(), &mut options.server_options.min_port, "minimum port"),
                 "--max-port" => parse_u16(iter.next(), &mut options.server_options.max_port, "maximum port"),
                 "-a" | "--auth" => options.client_options.auth = true,
+                "-pw" | "--password" => parse_optional_string(iter.next(), &mut options.client_options.password, "password"),
+                "--protocol" => match iter.next().map(String::as_str) {
+                    Some("tcp") => options.client_options.protocol = TunnelProtocol::Tcp,
+                    Some("http") => options.client_options.protocol = TunnelProtocol::Http,
+                    Some(other) => {
+                        eprintln!("{RED}{BOLD} ! {RESET} Invalid protocol: {other} (expected tcp or http){C_RESET}");
+                        std::process::exit(1);
+                    }
+                    None => eprintln!("{RED}{BOLD} ! {RESET} Missing protocol{C_RESET}"),
+                },
+                "--compression" => match iter.next() {
+                    Some(name) => if let Some(compression) = Compression::parse(name) {
+                        options.client_options.compression = Some(compression);
+                    } else {
+                        eprintln!("{RED}{BOLD} ! {RESET} Invalid compression: {name} (expected zstd or deflate){C_RESET}");
+                        std::process::exit(1);
+                    },
+                    None => eprintln!("{RED}{BOLD} ! {RESET} Missing compression{C_RESET}"),
+                },
+                "--no-compression" => options.server_options.no_compression = true,
+                "-l" | "--listen" => {
+                    if let Some(port) = parse_optional_u16(iter.next(), "listen port") {
+                        options.client_options.listen_port = Some(port);
+                    }
+                }
                 "-v" | "--verbose" => {
                     options.server_options.verbose_logging = true;
                     options.client_options.verbose_logging = true;
                 },
                 "-id" | "--require-id" => options.server_options.require_id = true,
+                "--ws-port" => {
+                    if let Some(port) = parse_optional_u16(iter.next(), "WebSocket port") {
+                        options.server_options.websocket_port = Some(port);
+                    }
+                }
+                "--trusted-proxy" => parse_network(iter.next(), &mut options.server_options.trusted_proxies),
+                "--quic-port" => {
+                    if let Some(port) = parse_optional_u16(iter.next(), "QUIC port") {
+                        options.server_options.quic_port = Some(port);
+                    }
+                }
+                "--quic-cert" => parse_file(iter.next(), &mut options.server_options.quic_cert, "QUIC certificate"),
+                "--quic-key" => parse_file(iter.next(), &mut options.server_options.quic_key, "QUIC private key"),
                 "-t" | "--tunnels-addr" => parse_string(iter.next(), &mut options.server_options.tunnels_addr, "tunnels address"),
+                "--pid-file" => parse_file(iter.next(), &mut options.server_options.pid_file, "pid file"),
+                "--user" => parse_optional_string(iter.next(), &mut options.server_options.user, "user"),
+                "--group" => parse_optional_string(iter.next(), &mut options.server_options.group, "group"),
+                "--max-lifetime" => parse_optional_duration(iter.next(), &mut options.server_options.max_lifetime, "maximum lifetime"),
+                "--idle-timeout" => parse_optional_duration(iter.next(), &mut options.server_options.idle_timeout, "idle timeout"),
+                "--share" => parse_optional_string(iter.next(), &mut options.client_options.share, "tunnel name"),
+                "--weight" => match iter.next().map(|val| val.parse::<u32>()) {
+                    Some(Ok(weight)) => options.client_options.weight = Some(weight),
+                    Some(Err(_)) => {
+                        eprintln!("{RED}{BOLD} ! {RESET} Invalid weight{C_RESET}");
+                        std::process::exit(1);
+                    }
+                    None => eprintln!("{RED}{BOLD} ! {RESET} Missing weight{C_RESET}"),
+                },
+                "--ttl" => parse_optional_duration(iter.next(), &mut options.client_options.ttl, "tunnel lifetime"),
+                "--health-check" => {
+                    options.client_options.health_check.get_or_insert_default();
+                }
+                "--health-path" => parse_optional_string(
+                    iter.next(),
+                    &mut options.client_options.health_check.get_or_insert_default().path,
+                    "health check path",
+                ),
+                "--health-interval" => parse_optional_duration(
+                    iter.next(),
+                    &mut options.client_options.health_check.get_or_insert_default().interval,
+                    "health check interval",
+                ),
+                "--conn-idle-timeout" => {
+                    parse_optional_duration(iter.next(), &mut options.client_options.connection_limits.idle_timeout, "connection idle timeout");
+                    options.server_options.connection_limits.idle_timeout = options.client_options.connection_limits.idle_timeout;
+                }
+                "--conn-write-timeout" => {
+                    parse_optional_duration(iter.next(), &mut options.client_options.connection_limits.write_timeout, "connection write timeout");
+                    options.server_options.connection_limits.write_timeout = options.client_options.connection_limits.write_timeout;
+                }
+                "--max-conn-duration" => {
+                    parse_optional_duration(iter.next(), &mut options.client_options.connection_limits.max_duration, "maximum connection duration");
+                    options.server_options.connection_limits.max_duration = options.client_options.connection_limits.max_duration;
+                }
+                "--allow-forward" => match iter.next() {
+                    Some(rule) => if let Some(rule) = ForwardRule::parse(rule) {
+                        options.server_options.forward_rules.push(rule);
+                    } else {
+                        eprintln!("{RED}{BOLD} ! {RESET} Invalid forward rule: {rule} (expected host:port or tunnel:<name>){C_RESET}");
+                        std::process::exit(1);
+                    },
+                    None => eprintln!("{RED}{BOLD} ! {RESET} Missing forward rule{C_RESET}"),
+                },
+                "--allow-dest" => match iter.next() {
+                    Some(rule) => if let Some(rule) = AddressRule::parse(rule) {
+                        options.client_options.proxy_rules.push(rule);
+                    } else {
+                        eprintln!("{RED}{BOLD} ! {RESET} Invalid destination rule: {rule} (expected host:port){C_RESET}");
+                        std::process::exit(1);
+                    },
+                    None => eprintln!("{RED}{BOLD} ! {RESET} Missing destination rule{C_RESET}"),
+                },
+                "--allow" => parse_network(iter.next(), &mut options.client_options.sources.allow),
+                "--deny" => parse_network(iter.next(), &mut options.client_options.sources.deny),
+                "-o" | "--output" => match iter.next().map(String::as_str) {
+                    Some("text") => options.client_options.output = OutputFormat::Text,
+                    Some("json") => options.client_options.output = OutputFormat::Json,
+                    Some("tui") => options.client_options.output = OutputFormat::Tui,
+                    Some(other) => {
+                        eprintln!("{RED}{BOLD} ! {RESET} Invalid output format: {other} (expected text, json or tui){C_RESET}");
+                        std::process::exit(1);
+                    }
+                    None => eprintln!("{RED}{BOLD} ! {RESET} Missing output format{C_RESET}"),
+                },
+                "--tui" => {
+                    options.client_options.output = OutputFormat::Tui;
+                    options.server_options.tui = true;
+                },
+                "--watch" => options.client_options.watch = true,
+                "-d" | "--detach" => options.client_options.detach_flags.push(self.args.len() - iter.len() - 1),
+                "--follow" => options.client_options.follow = true,
+                "--profile" => match iter.next() {
+                    Some(profile) => options.client_options.profiles.push(profile.clone()),
+                    None => eprintln!("{RED}{BOLD} ! {RESET} Missing profile{C_RESET}"),
+                },
+                "--proxy" => parse_optional_string(iter.next(), &mut options.client_options.outbound_proxy, "proxy"),
+                "--assignments-file" => parse_file(
+                    iter.next(),
+                    &mut options.client_options.assignments_file,
+                    "assignments file",
+                ),
+                other if matches!(self.command, Command::Compose) && compose_action.is_none() => {
+                    let action = match other {
+                        "up" => ComposeAction::Up,
+                        "ps" => ComposeAction::Ps,
+                        "logs" => ComposeAction::Logs,
+                        "down" => ComposeAction::Down,
+                        "config" => ComposeAction::Config,
+                        _ => {
+                            eprintln!("{RED}{BOLD} ! {RESET} Invalid compose command: {other} (expected up, ps, logs, down or config){C_RESET}");
+                            std::process::exit(1);
+                        }
+                    };
+                    compose_action = Some(action);
+                    options.client_options.compose_action = action;
+                }
+                other if compose_action == Some(ComposeAction::Logs) && options.client_options.compose_service.is_none() => {
+                    options.client_options.compose_service = Some(other.to_string());
+                }
+                other if matches!(self.command, Command::Compose) => {
+                    eprintln!("{RED}{BOLD} ! {RESET} Unexpected argument: {other}{C_RESET}");
+                    std::process::exit(1);
+                }
+                other if matches!(self.command, Command::Forward) => {
+                    options.client_options.forward_target = Some(other.to_string());
+                }
                 other => {
                     if let Ok(port) = other.parse::<u16>() {
                         options.client_options.port = port;
@@ -198,6 +413,28 @@ fn parse_optional_u16(input: Option<&String>, field_name: &str) -> Option<u16> {
     })
 }
 
+fn parse_optional_duration(input: Option<&String>, field: &mut Option<Duration>, field_name: &str) {
+    if let Some(val) = input {
+        *field = Some(parse_duration(val).unwrap_or_else(|| {
+            eprintln!("{RED}{BOLD} ! {RESET} Invalid {field_name} (e.g. 30s, 15m, 2h){C_RESET}");
+            std::process::exit(1);
+        }));
+    } else {
+        eprintln!("{RED}{BOLD} ! {RESET} Missing {field_name}{C_RESET}");
+    }
+}
+
+fn parse_network(input: Option<&String>, networks: &mut Vec<Cidr>) {
+    if let Some(val) = input {
+        networks.push(Cidr::parse(val).unwrap_or_else(|| {
+            eprintln!("{RED}{BOLD} ! {RESET} Invalid network: {val} (e.g. 203.0.113.0/24){C_RESET}");
+            std::process::exit(1);
+        }));
+    } else {
+        eprintln!("{RED}{BOLD} ! {RESET} Missing network{C_RESET}");
+    }
+}
+
 fn parse_file(input: Option<&String>, field: &mut Option<String>, field_name: &str) {
     if let Some(val) = input {
         *field = Some(val.clone());
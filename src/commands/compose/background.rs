@@ -0,0 +1,304 @@
+//! Services running in the background, started by `compose up -d`.
+//!
+//! Compose starts again as a detached process, which serves the states of
+//! the services on a Unix socket and writes what they do to log files.
+//! `compose ps`, `logs` and `down` find both in the state directory of the
+//! services file.
+
+use std::env;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[cfg(unix)]
+use super::Statuses;
+use crate::core::output::{MAIN_LOG, Output, ServiceStatus, log_file};
+
+/// Set for the detached process, to the state directory it serves.
+const STATE_ENV: &str = "TUNNELED_COMPOSE_STATE";
+
+/// Control socket of the detached process, in the state directory.
+const SOCKET: &str = "control.sock";
+
+/// How long `up -d` waits for the detached process to serve its socket.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `down` waits for the detached process to exit.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time between checks while waiting, and for new lines with `logs --follow`.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Lines `logs` prints before following.
+const TAIL_LINES: usize = 100;
+
+/// A request on the control socket, answered with the states of all services.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Request {
+    Status,
+
+    /// Stop all services and exit, after answering.
+    Down,
+}
+
+/// Directory holding the socket and logs of the services started from a
+/// file, one per file no matter how it is referred to.
+pub fn state_dir(path: &Path) -> Result<PathBuf> {
+    let path = fs::canonicalize(path).with_context(|| format!("File '{}' not found", path.display()))?;
+    let home = dirs::home_dir().context("Could not find the home directory")?;
+    let hash = hex::encode(Sha256::digest(path.as_os_str().as_encoded_bytes()));
+    Ok(home
+        .join(".config")
+        .join("tunneled")
+        .join("compose")
+        .join(&hash[..16]))
+}
+
+/// State directory to serve, if this process was started by `up -d`.
+#[must_use]
+pub fn detached() -> Option<PathBuf> {
+    env::var_os(STATE_ENV).map(PathBuf::from)
+}
+
+/// Create the state directory, readable and writable only by its owner.
+#[cfg(unix)]
+fn create_private_dir(state: &Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(state)
+        .with_context(|| format!("Could not create {}", state.display()))?;
+    // Directories of older versions were created with the default permissions.
+    fs::set_permissions(state, fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+/// The control socket of a detached process, removed when it is dropped.
+#[cfg(unix)]
+pub struct ControlSocket {
+    path: PathBuf,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+#[cfg(unix)]
+impl ControlSocket {
+    /// Serve the states of the services, and notify `down` when asked to stop.
+    ///
+    /// Only the user running compose may stop it.
+    pub fn bind(state: &Path, statuses: Statuses, down: std::sync::Arc<tokio::sync::Notify>) -> Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        create_private_dir(state)?;
+        let owner = fs::metadata(state)?.uid();
+        let path = state.join(SOCKET);
+        let _ = fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)
+            .with_context(|| format!("Could not listen on {}", path.display()))?;
+
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let statuses = std::sync::Arc::clone(&statuses);
+                let down = std::sync::Arc::clone(&down);
+                let is_owner = stream.peer_cred().is_ok_and(|peer| peer.uid() == owner);
+                tokio::spawn(async move {
+                    if matches!(answer(stream, &statuses).await, Ok(Request::Down)) && is_owner {
+                        down.notify_one();
+                    }
+                });
+            }
+        });
+        Ok(Self { path, handle })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.handle.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Read a request and answer it with the states of all services.
+#[cfg(unix)]
+async fn answer(stream: tokio::net::UnixStream, statuses: &Statuses) -> Result<Request> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (read, mut write) = stream.into_split();
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .context("No request")?;
+    let request = serde_json::from_str(&line)?;
+
+    let mut services = statuses.lock().unwrap().values().cloned().collect::<Vec<_>>();
+    services.sort_by(|a, b| a.service.cmp(&b.service));
+    write
+        .write_all((serde_json::to_string(&services)? + "\n").as_bytes())
+        .await?;
+    Ok(request)
+}
+
+/// Send a request to the detached process of a state directory.
+#[cfg(unix)]
+async fn send(state: &Path, request: &Request) -> Result<Vec<ServiceStatus>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(state.join(SOCKET)).await?;
+    let (read, mut write) = stream.into_split();
+    write
+        .write_all((serde_json::to_string(request)? + "\n").as_bytes())
+        .await?;
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .context("Compose closed the control socket")?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(not(unix))]
+async fn send(_state: &Path, _request: &Request) -> Result<Vec<ServiceStatus>> {
+    bail!("Running services in the background is only supported on Unix")
+}
+
+/// Services started from a file, failing if none run in the background.
+async fn running(path: &Path, state: &Path, request: &Request) -> Result<Vec<ServiceStatus>> {
+    send(state, request)
+        .await
+        .with_context(|| format!("No services of '{}' are running in the background", path.display()))
+}
+
+/// Start compose again as a detached process with the given arguments,
+/// without the detach flag, and wait until it serves its control socket. Its
+/// output goes to the log of the state directory.
+#[cfg(unix)]
+pub async fn detach<'a>(path: &Path, args: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    let state = state_dir(path)?;
+    if send(&state, &Request::Status).await.is_ok() {
+        bail!(
+            "The services of '{}' already run in the background, see `tunneled compose ps`",
+            path.display()
+        );
+    }
+    create_private_dir(&state)?;
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state.join(MAIN_LOG))?;
+
+    let mut child = Command::new(env::current_exe()?)
+        .args(args)
+        .env(STATE_ENV, &state)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0)
+        .spawn()
+        .context("Could not start compose in the background")?;
+
+    let started = tokio::time::Instant::now();
+    while started.elapsed() < START_TIMEOUT {
+        if let Some(status) = child.try_wait()? {
+            bail!("Compose exited right away ({status}), see `tunneled compose logs`");
+        }
+        if send(&state, &Request::Status).await.is_ok() {
+            println!(
+                "Started the services of '{}' in the background (pid {}), see `tunneled compose ps`",
+                path.display(),
+                child.id()
+            );
+            return Ok(());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    bail!("Compose did not start in time, see `tunneled compose logs`")
+}
+
+#[cfg(not(unix))]
+pub async fn detach<'a>(_path: &Path, _args: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    bail!("Running services in the background is only supported on Unix")
+}
+
+/// Show the states of the services running in the background.
+pub async fn ps(path: &Path, output: &Output) -> Result<()> {
+    let state = state_dir(path)?;
+    output.summary(&running(path, &state, &Request::Status).await?);
+    Ok(())
+}
+
+/// Stop the services running in the background, and wait for compose to exit.
+pub async fn down(path: &Path, output: &Output) -> Result<()> {
+    let state = state_dir(path)?;
+    let services = running(path, &state, &Request::Down).await?;
+
+    let started = tokio::time::Instant::now();
+    while state.join(SOCKET).exists() {
+        if started.elapsed() >= STOP_TIMEOUT {
+            bail!("Compose did not stop in time, see `tunneled compose logs`");
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    output.info(&format!("Stopped the services of '{}'", path.display()));
+    output.summary(&services);
+    Ok(())
+}
+
+/// Print the last lines a service, or compose itself, logged in the
+/// background, then the new ones as they come if `follow` is set.
+pub async fn logs(path: &Path, service: Option<&str>, follow: bool) -> Result<()> {
+    let file = log_file(&state_dir(path)?, service);
+    let mut log = fs::File::open(&file).with_context(|| {
+        service.map_or_else(
+            || format!("The services of '{}' never ran in the background", path.display()),
+            |service| format!("Service '{service}' has not logged anything"),
+        )
+    })?;
+
+    let mut contents = Vec::new();
+    log.read_to_end(&mut contents)?;
+    // A line that is still being written is printed once it is complete.
+    let complete = if follow { complete_lines(&contents) } else { contents.len() };
+    let mut partial = contents.split_off(complete);
+    let contents = String::from_utf8_lossy(&contents);
+    let lines = contents.lines().collect::<Vec<_>>();
+    for line in &lines[lines.len().saturating_sub(TAIL_LINES)..] {
+        println!("{line}");
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    let mut position = log.stream_position()?;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        if fs::metadata(&file).map_or(0, |metadata| metadata.len()) < position {
+            position = log.seek(SeekFrom::Start(0))?;
+            partial.clear();
+        }
+        let read = log.read_to_end(&mut partial)?;
+        position += read as u64;
+        let rest = partial.split_off(complete_lines(&partial));
+        for line in String::from_utf8_lossy(&partial).lines() {
+            println!("{line}");
+        }
+        partial = rest;
+    }
+}
+
+/// Length of the complete lines at the start of a log, up to the last newline.
+fn complete_lines(log: &[u8]) -> usize {
+    log.iter().rposition(|byte| *byte == b'\n').map_or(0, |end| end + 1)
+}
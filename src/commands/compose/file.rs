@@ -0,0 +1,333 @@
+//! Reading services files: includes, defaults, environment variables and
+//! secret files are resolved into one plain entry per service.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_yaml::{Mapping, Value};
+
+/// A service entry, fully resolved.
+#[derive(Debug)]
+pub struct Entry {
+    /// File the service is defined in.
+    pub file: PathBuf,
+    pub spec: Value,
+}
+
+/// Entries of a services file and the files it includes.
+#[derive(Debug, Default)]
+pub struct ServicesFile {
+    /// One entry per service, in the order they are defined.
+    pub services: Vec<Entry>,
+
+    /// Every file that was read, to notice changes.
+    pub files: Vec<PathBuf>,
+
+    /// Files being read, each included by the one before.
+    reading: Vec<PathBuf>,
+}
+
+impl ServicesFile {
+    /// Read a services file, with every file it includes.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut file = Self::default();
+        file.read(path, &Mapping::new())?;
+        if file.services.is_empty() {
+            bail!("'{}' has no services", path.display());
+        }
+        Ok(file)
+    }
+
+    /// Read a file with the defaults of the files including it.
+    fn read(&mut self, path: &Path, inherited: &Mapping) -> Result<()> {
+        let canonical = fs::canonicalize(path).map_err(|_| anyhow!("File '{}' not found", path.display()))?;
+        if let Some(start) = self.reading.iter().position(|file| *file == canonical) {
+            let cycle = self.reading[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>();
+            bail!("Files include each other: {}", cycle.join(" -> "));
+        }
+        // Files included by several others, its services are already there.
+        if self.files.contains(&canonical) {
+            return Ok(());
+        }
+        let contents = fs::read_to_string(&canonical).map_err(|_| anyhow!("File '{}' not found", path.display()))?;
+        self.files.push(canonical.clone());
+        self.reading.push(canonical);
+
+        let mut document: Value =
+            serde_yaml::from_str(&contents).with_context(|| format!("'{}' is not valid YAML", path.display()))?;
+        interpolate(&mut document, None, &|name| std::env::var(name).ok())
+            .with_context(|| format!("In '{}'", path.display()))?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut defaults = inherited.clone();
+        match document.get("defaults") {
+            None => (),
+            Some(Value::Mapping(own)) => {
+                for (key, value) in own {
+                    defaults.retain(|inherited, _| !same_key(inherited, key));
+                    defaults.insert(key.clone(), value.clone());
+                }
+            }
+            Some(_) => bail!("'defaults' in '{}' must be a mapping", path.display()),
+        }
+        for include in sequence(&document, "include", path)? {
+            let Value::String(include) = include else {
+                bail!("'include' in '{}' must list file names", path.display());
+            };
+            self.read(&directory.join(include), &defaults)?;
+        }
+        self.reading.pop();
+
+        for (index, service) in sequence(&document, "services", path)?.iter().enumerate() {
+            let Value::Mapping(service) = service else {
+                bail!("Service #{} in '{}' must be a mapping", index + 1, path.display());
+            };
+            let mut service = service.clone();
+            for (key, value) in &defaults {
+                if !service.keys().any(|set| same_key(set, key)) {
+                    service.insert(key.clone(), value.clone());
+                }
+            }
+            self.read_secret(&mut service, directory)?;
+            self.services.push(Entry {
+                file: path.to_path_buf(),
+                spec: Value::Mapping(service),
+            });
+        }
+        Ok(())
+    }
+
+    /// Replace `secret-file` of a service with the secret it contains.
+    fn read_secret(&mut self, service: &mut Mapping, directory: &Path) -> Result<()> {
+        let Some(secret_file) = service.remove("secret-file") else {
+            return Ok(());
+        };
+        let name = service.get("name").and_then(Value::as_str).unwrap_or("unnamed");
+        let Value::String(secret_file) = secret_file else {
+            bail!("'secret-file' of service '{name}' must be a file name");
+        };
+        if service.contains_key("secret") {
+            bail!("Service '{name}' sets both secret and secret-file");
+        }
+
+        let path = directory.join(secret_file);
+        let secret = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read the secret file of service '{name}' ({})", path.display()))?;
+        service.insert("secret".into(), secret.trim_end_matches(['\r', '\n']).into());
+        self.files.push(path);
+        Ok(())
+    }
+}
+
+/// Whether two keys set the same field, counting `secret` and `secret-file`
+/// as one, so a default never adds the other.
+fn same_key(a: &Value, b: &Value) -> bool {
+    match (a.as_str(), b.as_str()) {
+        (Some("secret" | "secret-file"), Some("secret" | "secret-file")) => true,
+        _ => a == b,
+    }
+}
+
+/// Items of a top level list, none if it is missing.
+fn sequence<'a>(document: &'a Value, key: &str, path: &Path) -> Result<&'a [Value]> {
+    match document.get(key) {
+        None => Ok(&[]),
+        Some(Value::Sequence(items)) => Ok(items),
+        Some(_) => bail!("'{key}' in '{}' must be a list", path.display()),
+    }
+}
+
+/// Keys of a service whose values are numbers or booleans, read from their
+/// text when it comes from environment variables.
+const TYPED_KEYS: &[&str] = &["port", "static-port", "control-port", "use-auth", "weight", "max-retries"];
+
+/// Replace `${NAME}` and `${NAME:-default}` in every string of a document
+/// with environment variables, and `$$` with `$`. The default is also used if
+/// the variable is empty.
+///
+/// Variables are replaced after the YAML is parsed, so their values are never
+/// read as YAML. Under the keys in [`TYPED_KEYS`], a value like `8080` becomes
+/// a number again.
+fn interpolate(value: &mut Value, key: Option<&str>, vars: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+    match value {
+        Value::String(text) if text.contains('$') => {
+            let substituted = substitute(text, vars)?;
+            *value = match serde_yaml::from_str(&substituted) {
+                Ok(typed @ (Value::Number(_) | Value::Bool(_))) if key.is_some_and(|key| TYPED_KEYS.contains(&key)) => typed,
+                _ => Value::String(substituted),
+            };
+        }
+        Value::Sequence(items) => {
+            for item in items {
+                interpolate(item, key, vars)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (name, value) in mapping.iter_mut() {
+                interpolate(value, name.as_str(), vars)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate(&mut tagged.value, key, vars)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+fn substitute(text: &str, vars: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix('{') else {
+            result.push('$');
+            continue;
+        };
+        let end = after.find('}').context("Variable is missing its closing brace")?;
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        match (vars(name), default) {
+            (Some(value), _) if !value.is_empty() => result.push_str(&value),
+            (_, Some(default)) => result.push_str(default),
+            (Some(_), None) => (),
+            (None, None) => bail!("Environment variable '{name}' is not set"),
+        }
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(name: &str) -> Option<String> {
+        match name {
+            "PORT" => Some("8080".to_string()),
+            "HOST" => Some("db.internal".to_string()),
+            "EMPTY" => Some(String::new()),
+            "YAML" => Some("{ secret: stolen }".to_string()),
+            _ => None,
+        }
+    }
+
+    fn interpolated(yaml: &str) -> Result<Value> {
+        let mut document = serde_yaml::from_str(yaml).unwrap();
+        interpolate(&mut document, None, &vars)?;
+        Ok(document)
+    }
+
+    /// Write files into a fresh directory, named after the test.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("tunneled-test-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        for (name, contents) in files {
+            fs::write(directory.join(name), contents).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn substitute_variables() {
+        assert_eq!(substitute("${HOST}:${PORT}", &vars).unwrap(), "db.internal:8080");
+        assert_eq!(substitute("${MISSING:-fallback}", &vars).unwrap(), "fallback");
+        assert_eq!(substitute("${EMPTY:-fallback}", &vars).unwrap(), "fallback");
+        assert_eq!(substitute("${HOST:-fallback}", &vars).unwrap(), "db.internal");
+        assert_eq!(substitute("[${EMPTY}]", &vars).unwrap(), "[]");
+        assert_eq!(substitute("$$HOST costs $5", &vars).unwrap(), "$HOST costs $5");
+        assert_eq!(substitute("$${HOST}", &vars).unwrap(), "${HOST}");
+    }
+
+    #[test]
+    fn substitute_errors() {
+        let err = substitute("${MISSING}", &vars).unwrap_err();
+        assert_eq!(err.to_string(), "Environment variable 'MISSING' is not set");
+        assert!(substitute("${HOST", &vars).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn interpolate_typed_keys() {
+        let document = interpolated("port: ${PORT}\nname: ${PORT}\nuse-auth: ${MISSING:-true}").unwrap();
+        assert_eq!(document["port"], Value::from(8080));
+        assert_eq!(document["name"], Value::from("8080"));
+        assert_eq!(document["use-auth"], Value::from(true));
+    }
+
+    #[test]
+    fn interpolated_values_are_not_yaml() {
+        let document = interpolated("services:\n  - name: web\n    password: ${YAML}\n    port: ${YAML}").unwrap();
+        let service = &document["services"][0];
+        assert_eq!(service["password"], Value::from("{ secret: stolen }"));
+        assert_eq!(service["port"], Value::from("{ secret: stolen }"));
+        assert!(service.get("secret").is_none());
+    }
+
+    #[test]
+    fn include_cycle_is_reported() {
+        let directory = write_files(
+            "cycle",
+            &[
+                ("a.yml", "include: [b.yml]\nservices:\n  - name: a\n    port: 1"),
+                ("b.yml", "include: [a.yml]\nservices:\n  - name: b\n    port: 2"),
+            ],
+        );
+        let err = ServicesFile::load(&directory.join("a.yml")).unwrap_err().to_string();
+        assert!(err.starts_with("Files include each other: "), "{err}");
+        assert!(err.ends_with("a.yml") && err.matches("a.yml").count() == 2, "{err}");
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn shared_include_is_read_once() {
+        let directory = write_files(
+            "diamond",
+            &[
+                ("root.yml", "include: [a.yml, b.yml]"),
+                ("a.yml", "include: [shared.yml]\nservices:\n  - name: a\n    port: 1"),
+                ("b.yml", "include: [shared.yml]\nservices:\n  - name: b\n    port: 2"),
+                ("shared.yml", "services:\n  - name: shared\n    port: 3"),
+            ],
+        );
+        let file = ServicesFile::load(&directory.join("root.yml")).unwrap();
+        let names = file.services.iter().map(|entry| entry.spec["name"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, ["shared", "a", "b"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn defaults_are_inherited() {
+        let directory = write_files(
+            "defaults",
+            &[
+                ("root.yml", "defaults:\n  server: root.example\n  secret: root\ninclude: [team.yml]"),
+                (
+                    "team.yml",
+                    "defaults:\n  server: team.example\nservices:\n  - name: web\n    port: 1\n  - name: db\n    port: 2\n    secret-file: db.secret",
+                ),
+                ("db.secret", "from-file\n"),
+            ],
+        );
+        let file = ServicesFile::load(&directory.join("root.yml")).unwrap();
+        let web = &file.services[0].spec;
+        assert_eq!(web["server"], Value::from("team.example"));
+        assert_eq!(web["secret"], Value::from("root"));
+        let db = &file.services[1].spec;
+        assert_eq!(db["secret"], Value::from("from-file"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
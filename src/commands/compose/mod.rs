@@ -0,0 +1,744 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::commands::local::{Backend, Client, ClientError};
+use crate::core::acl::{DestinationAcl, SourceFilter};
+use crate::core::balance::{BalanceStrategy, Upstreams};
+use crate::core::compression::Compression;
+use crate::core::constants::{DEFAULT_CONTROL_PORT, DEFAULT_SERVER};
+use crate::core::daemon;
+use crate::core::forward::ConnectionLimits;
+use crate::core::health::HealthCheck;
+use crate::core::outbound::OutboundProxy;
+use crate::core::output::{Output, ServiceState, ServiceStatus, public_host};
+use crate::core::private::hash_password;
+use crate::core::shared::{TunnelOptions, TunnelProtocol, deserialize_duration, format_duration};
+use crate::core::target::LocalTarget;
+
+pub mod background;
+mod file;
+mod process;
+mod wait;
+
+use file::{Entry, ServicesFile};
+use process::Process;
+use wait::WaitFor;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Service {
+    pub name: String,
+    pub port: Option<u16>,
+    pub host: Option<String>,
+    #[serde(rename = "unix-socket")]
+    pub unix_socket: Option<PathBuf>,
+    pub server: Option<String>,
+    pub secret: Option<String>,
+    #[serde(rename = "static-port")]
+    pub static_port: Option<u16>,
+    #[serde(rename = "control-port")]
+    pub control_port: Option<u16>,
+    #[serde(rename = "use-auth")]
+    pub use_auth: Option<bool>,
+    pub password: Option<String>,
+    pub protocol: Option<TunnelProtocol>,
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub sources: SourceFilter,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub ttl: Option<Duration>,
+    #[serde(flatten)]
+    pub connection_limits: ConnectionLimits,
+    #[serde(rename = "health-check")]
+    pub health_check: Option<HealthCheck>,
+    pub share: Option<String>,
+    pub weight: Option<u32>,
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    pub balance: Option<BalanceStrategy>,
+    pub proxy: Option<ServiceProxy>,
+    #[serde(rename = "outbound-proxy")]
+    pub outbound_proxy: Option<String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(rename = "max-retries")]
+    pub max_retries: Option<u32>,
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    #[serde(default, rename = "depends-on")]
+    pub depends_on: Vec<String>,
+    #[serde(rename = "wait-for")]
+    pub wait_for: Option<WaitFor>,
+    pub command: Option<String>,
+}
+
+/// When a service is started again after its tunnel ended.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Whenever the tunnel ended, also if the server closed it or it expired.
+    Always,
+
+    /// If the tunnel could not be opened or failed.
+    OnFailure,
+
+    /// Never, the tunnel stays down.
+    #[default]
+    Never,
+}
+
+/// One of several local services sharing the connections of a service entry.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    #[serde(rename = "unix-socket")]
+    pub unix_socket: Option<PathBuf>,
+}
+
+/// Proxy mode of a service entry, dialing destinations chosen by the visitors.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceProxy {
+    pub allow: DestinationAcl,
+}
+
+impl Service {
+    /// Where this entry forwards connections to.
+    pub fn backend(&self) -> Result<Backend> {
+        let Some(proxy) = &self.proxy else {
+            return self.upstreams().map(Backend::Upstreams);
+        };
+        if self.port.is_some() || self.unix_socket.is_some() || !self.upstreams.is_empty() {
+            bail!("Service '{}' sets proxy together with a local service", self.name);
+        }
+        if proxy.allow.is_empty() {
+            bail!("Service '{}' needs at least one allowed proxy destination", self.name);
+        }
+        Ok(Backend::Proxy(proxy.allow.clone()))
+    }
+
+    /// Local services this entry forwards to.
+    fn upstreams(&self) -> Result<Upstreams> {
+        if self.upstreams.is_empty() {
+            let target = self.target(self.host.as_deref(), self.port, self.unix_socket.as_ref())?;
+            return Ok(Upstreams::single(target));
+        }
+        if self.port.is_some() || self.unix_socket.is_some() {
+            bail!("Service '{}' sets upstreams together with port or unix-socket", self.name);
+        }
+
+        let targets = self
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let host = upstream.host.as_deref().or(self.host.as_deref());
+                self.target(host, upstream.port, upstream.unix_socket.as_ref())
+            })
+            .collect::<Result<_>>()?;
+        Ok(Upstreams::new(targets, self.balance.unwrap_or_default()))
+    }
+
+    /// Local service at either a Unix socket or a TCP port.
+    fn target(&self, host: Option<&str>, port: Option<u16>, unix_socket: Option<&PathBuf>) -> Result<LocalTarget> {
+        match (unix_socket, port) {
+            (Some(path), None) => Ok(LocalTarget::Unix(path.clone())),
+            (None, Some(port)) => Ok(LocalTarget::Tcp {
+                host: host.unwrap_or("localhost").to_string(),
+                port,
+            }),
+            (Some(_), Some(_)) => bail!("Service '{}' sets both port and unix-socket", self.name),
+            (None, None) => bail!("Service '{}' needs either port or unix-socket", self.name),
+        }
+    }
+}
+
+/// Services file used if none is given.
+pub const DEFAULT_FILE: &str = "services.yml";
+
+/// Keys of a service entry, checked by hand since [`Service`] flattens the
+/// connection limits and serde can't deny unknown keys then.
+const SERVICE_KEYS: [&str; 30] = [
+    "name",
+    "port",
+    "host",
+    "unix-socket",
+    "server",
+    "secret",
+    "static-port",
+    "control-port",
+    "use-auth",
+    "password",
+    "protocol",
+    "compression",
+    "sources",
+    "ttl",
+    "connection-idle-timeout",
+    "connection-write-timeout",
+    "max-connection-duration",
+    "health-check",
+    "share",
+    "weight",
+    "upstreams",
+    "balance",
+    "proxy",
+    "outbound-proxy",
+    "restart",
+    "max-retries",
+    "profiles",
+    "depends-on",
+    "wait-for",
+    "command",
+];
+
+/// Keys of a service entry whose values `config` does not print.
+const HIDDEN_KEYS: [&str; 2] = ["secret", "password"];
+
+/// Time between checks of the services file for changes, with `--watch`.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before the first restart of a service, doubled for every further one.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between restarts.
+const MAX_RESTART_DELAY: Duration = Duration::from_mins(1);
+
+/// Time a tunnel has to stay open for the backoff to start over.
+const STABLE_AFTER: Duration = Duration::from_mins(1);
+
+/// Time between checks whether the dependencies of a service run.
+const DEPENDENCY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A service entry that was checked and can be started.
+struct Prepared {
+    service: Service,
+
+    /// The entry as written, to tell whether it changed on reload.
+    spec: Value,
+    proxy: Option<OutboundProxy>,
+}
+
+/// A started service, with the entry it was started from.
+struct Running {
+    spec: Value,
+    handle: JoinHandle<()>,
+
+    /// Asks the service to stop its tunnel and backend process.
+    stop: CancellationToken,
+}
+
+/// States of all services, by name.
+type Statuses = Arc<Mutex<HashMap<String, ServiceStatus>>>;
+
+/// Check the service entries of a file, without starting anything, and
+/// keep the ones in an active profile. Entries without profiles are always
+/// active.
+fn prepare(entries: &[Entry], proxy: Option<&str>, profiles: &[String]) -> Result<Vec<Prepared>> {
+    let mut names = HashSet::new();
+    let mut services = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let Entry { file, spec } = entry;
+        let name = spec.get("name").and_then(Value::as_str).map_or_else(
+            || format!("#{}", index + 1),
+            |name| format!("'{name}'"),
+        );
+        if let Some(key) = spec
+            .as_mapping()
+            .and_then(|spec| spec.keys().find(|key| !key.as_str().is_some_and(|key| SERVICE_KEYS.contains(&key))))
+        {
+            let key = serde_yaml::to_string(key).unwrap_or_default();
+            bail!("Service {name} in '{}' has an unknown field '{}'", file.display(), key.trim_end());
+        }
+        let service: Service = serde_yaml::from_value(spec.clone())
+            .with_context(|| format!("Service {name} in '{}' is invalid", file.display()))?;
+        if !names.insert(service.name.clone()) {
+            bail!("Service {name} in '{}' is defined more than once", file.display());
+        }
+        service.backend()?;
+        if let Some(wait_for) = &service.wait_for {
+            wait_for.validate(&service)?;
+        }
+        let proxy = OutboundProxy::resolve(service.outbound_proxy.as_deref().or(proxy))
+            .with_context(|| format!("Service {name} has an invalid outbound proxy"))?;
+
+        if service.profiles.is_empty() || service.profiles.iter().any(|profile| profiles.contains(profile)) {
+            services.push(Prepared {
+                service,
+                spec: spec.clone(),
+                proxy,
+            });
+        }
+    }
+    check_dependencies(&services)?;
+    Ok(services)
+}
+
+/// Check that services only depend on services that are started with them,
+/// and not on each other in a circle.
+fn check_dependencies(services: &[Prepared]) -> Result<()> {
+    fn visit<'a>(
+        name: &'a str,
+        services: &HashMap<&'a str, &'a Service>,
+        path: &mut Vec<&'a str>,
+        checked: &mut HashSet<&'a str>,
+    ) -> Result<()> {
+        if checked.contains(name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|visited| *visited == name) {
+            bail!("Services depend on each other: {} -> {name}", path[start..].join(" -> "));
+        }
+        path.push(name);
+        for dependency in &services[name].depends_on {
+            visit(dependency, services, path, checked)?;
+        }
+        path.pop();
+        checked.insert(name);
+        Ok(())
+    }
+
+    let by_name = services
+        .iter()
+        .map(|prepared| (prepared.service.name.as_str(), &prepared.service))
+        .collect::<HashMap<_, _>>();
+    for service in by_name.values() {
+        if let Some(missing) = service.depends_on.iter().find(|name| !by_name.contains_key(name.as_str())) {
+            bail!(
+                "Service '{}' depends on '{missing}', which is not defined or not in an active profile",
+                service.name
+            );
+        }
+    }
+
+    let mut checked = HashSet::new();
+    for name in by_name.keys() {
+        visit(name, &by_name, &mut Vec::new(), &mut checked)?;
+    }
+    Ok(())
+}
+
+/// Open the tunnel of a service and serve it until it ends.
+async fn run(
+    service: &Service,
+    proxy: Option<OutboundProxy>,
+    verbose: bool,
+    output: &Arc<Output>,
+    statuses: &Statuses,
+) -> Result<(), ClientError> {
+    let client = Client::builder(service.backend()?)
+        .server(service.server.as_deref().unwrap_or(DEFAULT_SERVER))
+        .control_port(service.control_port.unwrap_or(DEFAULT_CONTROL_PORT))
+        .secret(service.secret.as_deref())
+        .static_port(service.static_port)
+        .require_auth(service.use_auth.unwrap_or(false))
+        .options(TunnelOptions {
+            password: service.password.as_deref().map(hash_password),
+            protocol: service.protocol.unwrap_or_default(),
+            ttl: service.ttl.map(|ttl| ttl.as_secs()),
+            share: service.share.clone(),
+            weight: service.weight,
+            compression: service.compression,
+            sources: service.sources.clone(),
+            ..TunnelOptions::default()
+        })
+        .connection_limits(service.connection_limits)
+        .health_check(service.health_check.clone())
+        .name(service.name.clone())
+        .proxy(proxy)
+        .ping_interval(output.ping_interval())
+        .verbosity(output.verbosity(verbose))
+        .connect()
+        .await;
+    let client = match client {
+        Ok(client) => client,
+        Err(err) => {
+            output.error(Some(&service.name), &err);
+            return Err(err);
+        }
+    };
+
+    output.ready(Some(&service.name), &client);
+    update(statuses, &service.name, |status| {
+        status.state = ServiceState::Running;
+        status.address = Some(format!(
+            "{}:{}",
+            public_host(&client.remote_addr(), client.server()),
+            client.remote_port()
+        ));
+        status.error = None;
+    });
+    let result = client.listen().await;
+    output.stopped(Some(&service.name), &result);
+    result
+}
+
+/// Start the backend process of a service if it has a command, and open its
+/// tunnel once it is ready. The tunnel is closed when the process exits, and
+/// the process stopped when the tunnel ends or the service is stopped.
+async fn launch(
+    service: &Service,
+    proxy: Option<OutboundProxy>,
+    verbose: bool,
+    output: &Arc<Output>,
+    statuses: &Statuses,
+    stop: &CancellationToken,
+) -> Result<(), ClientError> {
+    let name = service.name.as_str();
+    let failed = |err: anyhow::Error| {
+        output.error(Some(name), &format!("{err:#}"));
+        ClientError::Other(err)
+    };
+    let process = service
+        .command
+        .as_deref()
+        .map(|command| Process::spawn(name, command, output))
+        .transpose()
+        .map_err(failed)?;
+    let tunnel = async {
+        ready(service, output, statuses).await.map_err(failed)?;
+        run(service, proxy, verbose, output, statuses).await
+    };
+    let Some(mut process) = process else {
+        return tokio::select! {
+            result = tunnel => result,
+            () = stop.cancelled() => Ok(()),
+        };
+    };
+
+    let result = tokio::select! {
+        result = tunnel => result,
+        () = stop.cancelled() => Ok(()),
+        exited = process.wait(name) => {
+            if exited.is_ok() {
+                output.info(&format!("The command of service '{name}' exited"));
+            }
+            let result = exited.map_err(ClientError::Other);
+            output.stopped(Some(name), &result);
+            result
+        }
+    };
+    process.stop().await;
+    result
+}
+
+/// Run a service, and restart it as its policy says, waiting longer after
+/// every restart that did not keep the tunnel open for a while.
+async fn supervise(prepared: Prepared, verbose: bool, output: Arc<Output>, statuses: Statuses, stop: CancellationToken) {
+    let Prepared { service, proxy, .. } = prepared;
+    let name = service.name.as_str();
+
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let result = launch(&service, proxy.clone(), verbose, &output, &statuses, &stop).await;
+        if stop.is_cancelled() {
+            return;
+        }
+        let failed = matches!(&result, Err(err) if !matches!(err, ClientError::Expired(_)));
+        if started.elapsed() >= STABLE_AFTER {
+            attempt = 0;
+        }
+
+        let restart = match service.restart {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Never => false,
+        };
+        let exhausted = service.max_retries.is_some_and(|max| attempt >= max);
+        update(&statuses, name, |status| {
+            status.error = result.as_ref().err().map(ToString::to_string);
+            status.state = match (restart && !exhausted, failed) {
+                (true, _) => ServiceState::Restarting,
+                (false, true) => ServiceState::Failed,
+                (false, false) => ServiceState::Stopped,
+            };
+        });
+        if !restart {
+            return;
+        }
+        if exhausted {
+            output.error(Some(name), &format!("Giving up on service '{name}' after {attempt} restarts"));
+            return;
+        }
+
+        let delay = RESTART_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RESTART_DELAY);
+        attempt += 1;
+        output.info(&format!("Restarting service '{name}' in {}", format_duration(delay)));
+        tokio::select! {
+            () = tokio::time::sleep(delay) => (),
+            () = stop.cancelled() => return,
+        }
+        update(&statuses, name, |status| status.restarts += 1);
+    }
+}
+
+/// Wait until the services a service depends on run and its local service
+/// is ready, so visitors never reach a tunnel without a service behind it.
+async fn ready(service: &Service, output: &Output, statuses: &Statuses) -> Result<()> {
+    // A started backend process is ready once it listens on its port.
+    let wait_for = service.wait_for.clone().or_else(|| {
+        service
+            .command
+            .as_ref()
+            .and(service.port)
+            .map(|port| WaitFor {
+                port: Some(port),
+                ..WaitFor::default()
+            })
+    });
+    let mut waiting = service
+        .depends_on
+        .iter()
+        .map(|name| format!("'{name}'"))
+        .collect::<Vec<_>>();
+    if wait_for.is_some() {
+        waiting.push("its local service".to_string());
+    }
+    if waiting.is_empty() {
+        return Ok(());
+    }
+    output.info(&format!("Service '{}' is waiting for {}", service.name, waiting.join(", ")));
+    update(statuses, &service.name, |status| status.state = ServiceState::Waiting);
+
+    for dependency in &service.depends_on {
+        loop {
+            let state = statuses.lock().unwrap().get(dependency).map(|status| status.state);
+            match state {
+                Some(ServiceState::Running) => break,
+                Some(ServiceState::Stopped | ServiceState::Failed) => {
+                    bail!("Service '{}' depends on '{dependency}', which is down", service.name);
+                }
+                None => bail!("Service '{}' depends on '{dependency}', which was removed", service.name),
+                Some(_) => tokio::time::sleep(DEPENDENCY_INTERVAL).await,
+            }
+        }
+    }
+    if let Some(wait_for) = &wait_for {
+        wait_for.wait(service).await?;
+    }
+    update(statuses, &service.name, |status| status.state = ServiceState::Starting);
+    Ok(())
+}
+
+fn update(statuses: &Statuses, name: &str, change: impl FnOnce(&mut ServiceStatus)) {
+    if let Some(status) = statuses.lock().unwrap().get_mut(name) {
+        change(status);
+    }
+}
+
+/// Times the files were last changed, if they can be read.
+fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+/// Read a services file and check the services in the active profiles.
+fn load(path: &Path, proxy: Option<&str>, profiles: &[String]) -> Result<(Vec<Prepared>, Vec<PathBuf>)> {
+    let file = ServicesFile::load(path)?;
+    Ok((prepare(&file.services, proxy, profiles)?, file.files))
+}
+
+/// Run every service of a services file until all of them stopped, or the
+/// process is asked to shut down, then report their states. Fails if any
+/// service failed.
+pub async fn compose(
+    path: Option<&str>,
+    proxy: Option<&str>,
+    profiles: &[String],
+    verbose: bool,
+    watch: bool,
+    output: Arc<Output>,
+) -> Result<()> {
+    let path = Path::new(path.unwrap_or(DEFAULT_FILE));
+    let (services, mut files) =
+        load(path, proxy, profiles).map_err(|err| anyhow::anyhow!("Failed to read service file: {err:#}"))?;
+    let mut seen = modified(&files);
+
+    let statuses = Statuses::default();
+    let down = Arc::new(Notify::new());
+    #[cfg(unix)]
+    let _control = background::detached()
+        .map(|state| background::ControlSocket::bind(&state, Arc::clone(&statuses), Arc::clone(&down)))
+        .transpose()?;
+    let start = |prepared: Prepared| {
+        let spec = prepared.spec.clone();
+        let stop = CancellationToken::new();
+        let handle = tokio::spawn(supervise(
+            prepared,
+            verbose,
+            Arc::clone(&output),
+            Arc::clone(&statuses),
+            stop.clone(),
+        ));
+        Running { spec, handle, stop }
+    };
+    register(&statuses, &services);
+    let mut running = services
+        .into_iter()
+        .map(|prepared| (prepared.service.name.clone(), start(prepared)))
+        .collect::<HashMap<_, _>>();
+
+    let shutdown = daemon::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            () = &mut shutdown => break,
+            () = down.notified() => break,
+            () = tokio::time::sleep(WATCH_INTERVAL) => {}
+        }
+        if !watch {
+            if running.values().all(|service| service.handle.is_finished()) {
+                break;
+            }
+            continue;
+        }
+
+        let changed = modified(&files);
+        if changed == seen {
+            continue;
+        }
+        match load(path, proxy, profiles) {
+            Ok((services, read)) => {
+                files = read;
+                reload(&mut running, services, &start, &output, &statuses).await;
+            }
+            Err(err) => output.error(
+                None,
+                &format!("Keeping the running services, {} is invalid: {err:#}", path.display()),
+            ),
+        }
+        seen = modified(&files);
+    }
+
+    for service in running.into_values() {
+        stop(service).await;
+    }
+    let mut statuses = statuses.lock().unwrap().values().cloned().collect::<Vec<_>>();
+    statuses.sort_by(|a, b| a.service.cmp(&b.service));
+    output.summary(&statuses);
+
+    let failed = statuses
+        .iter()
+        .filter(|status| status.state == ServiceState::Failed)
+        .count();
+    if failed > 0 {
+        bail!("{failed} of {} services failed", statuses.len());
+    }
+    Ok(())
+}
+
+/// The service entries of a file as they would be started, with includes,
+/// defaults and variables resolved, after checking them like `compose` does.
+pub fn resolve(path: Option<&str>, proxy: Option<&str>, profiles: &[String]) -> Result<Vec<Value>> {
+    let path = Path::new(path.unwrap_or(DEFAULT_FILE));
+    let (services, _) = load(path, proxy, profiles)?;
+    Ok(services.into_iter().map(|prepared| prepared.spec).collect())
+}
+
+/// Print the resolved services file, without the secrets and passwords.
+pub fn config(path: Option<&str>, proxy: Option<&str>, profiles: &[String]) -> Result<()> {
+    let mut services = resolve(path, proxy, profiles)?;
+    for spec in &mut services {
+        if let Some(spec) = spec.as_mapping_mut() {
+            for key in HIDDEN_KEYS {
+                if let Some(value) = spec.get_mut(key) {
+                    *value = Value::from("********");
+                }
+            }
+        }
+    }
+
+    let mut file = Mapping::new();
+    file.insert(Value::from("services"), Value::Sequence(services));
+    print!("{}", serde_yaml::to_string(&file)?);
+    Ok(())
+}
+
+/// Apply a changed services file: start added services, stop removed ones
+/// and restart the ones whose entry changed.
+async fn reload(
+    running: &mut HashMap<String, Running>,
+    services: Vec<Prepared>,
+    start: &(impl Fn(Prepared) -> Running + Sync),
+    output: &Arc<Output>,
+    statuses: &Statuses,
+) {
+    let names = services
+        .iter()
+        .map(|prepared| prepared.service.name.clone())
+        .collect::<HashSet<_>>();
+    let removed = running
+        .keys()
+        .filter(|name| !names.contains(*name))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in removed {
+        if let Some(service) = running.remove(&name) {
+            stop(service).await;
+        }
+        statuses.lock().unwrap().remove(&name);
+        output.removed(&name);
+        output.info(&format!("Stopped service '{name}', it was removed from the file"));
+    }
+
+    let mut starting = Vec::new();
+    for prepared in services {
+        let name = prepared.service.name.clone();
+        let message = match running.remove(&name) {
+            Some(service) if service.spec == prepared.spec => {
+                running.insert(name, service);
+                continue;
+            }
+            Some(service) => {
+                stop(service).await;
+                format!("Restarting service '{name}', its entry changed")
+            }
+            None => format!("Starting service '{name}', it was added to the file"),
+        };
+        output.info(&message);
+        starting.push(prepared);
+    }
+    register(statuses, &starting);
+    for prepared in starting {
+        running.insert(prepared.service.name.clone(), start(prepared));
+    }
+}
+
+/// Mark services as starting before any of them runs, so a service never
+/// mistakes a dependency that was not started yet for a removed one.
+fn register(statuses: &Statuses, services: &[Prepared]) {
+    let mut statuses = statuses.lock().unwrap();
+    for prepared in services {
+        let name = prepared.service.name.clone();
+        statuses.insert(
+            name.clone(),
+            ServiceStatus {
+                service: name,
+                state: ServiceState::Starting,
+                address: None,
+                restarts: 0,
+                error: None,
+            },
+        );
+    }
+}
+
+/// Stop a service, closing its control connection and waiting for its
+/// backend process to exit before it returns.
+async fn stop(service: Running) {
+    service.stop.cancel();
+    let _ = service.handle.await;
+}
@@ -0,0 +1,136 @@
+//! Backend processes that compose starts along with the tunnels to them.
+
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Child;
+
+use super::wait::shell;
+use crate::core::output::Output;
+
+/// Time a process gets to exit on its own before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time between checks whether a stopped process group is gone.
+#[cfg(unix)]
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The backend process of a service. It runs in its own process group, which
+/// is asked to terminate when the process is dropped.
+pub struct Process {
+    child: Child,
+    /// Process group to signal, until it has been stopped.
+    #[cfg(unix)]
+    group: Option<i32>,
+}
+
+impl Process {
+    /// Start the command of a service, forwarding everything it prints to the output.
+    pub fn spawn(service: &str, command: &str, output: &Arc<Output>) -> Result<Self> {
+        let mut shell = shell(command);
+        shell
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        shell.process_group(0);
+        let mut child = shell
+            .spawn()
+            .with_context(|| format!("Could not start the command of service '{service}'"))?;
+
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(forward(stdout, service.to_string(), false, Arc::clone(output)));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(forward(stderr, service.to_string(), true, Arc::clone(output)));
+        }
+        Ok(Self {
+            #[cfg(unix)]
+            group: child.id().and_then(|pid| i32::try_from(pid).ok()),
+            child,
+        })
+    }
+
+    /// Wait for the process to exit, failing unless it exits with status 0.
+    pub async fn wait(&mut self, service: &str) -> Result<()> {
+        let status = self.child.wait().await?;
+        if !status.success() {
+            bail!("The command of service '{service}' exited with {status}");
+        }
+        Ok(())
+    }
+
+    /// Ask the process to terminate and wait for it and everything it started,
+    /// killing them if they take too long.
+    pub async fn stop(mut self) {
+        self.terminate();
+        let exited = async {
+            let _ = self.child.wait().await;
+            // The shell exits right away, the command it runs may take longer.
+            #[cfg(unix)]
+            while self.signal(0) {
+                tokio::time::sleep(EXIT_POLL_INTERVAL).await;
+            }
+        };
+        if tokio::time::timeout(STOP_TIMEOUT, exited).await.is_err() {
+            #[cfg(unix)]
+            let _ = self.signal(libc::SIGKILL);
+            let _ = self.child.kill().await;
+        }
+        // The group is gone, its id may be reused by the time this is dropped.
+        #[cfg(unix)]
+        self.group.take();
+    }
+
+    /// Ask the process and everything it started to terminate.
+    #[cfg_attr(unix, allow(clippy::needless_pass_by_ref_mut))]
+    fn terminate(&mut self) {
+        #[cfg(unix)]
+        let _ = self.signal(libc::SIGTERM);
+        #[cfg(not(unix))]
+        let _ = self.child.start_kill();
+    }
+
+    /// Send a signal to the process group, returning whether any process
+    /// of it is left.
+    #[cfg(unix)]
+    fn signal(&self, signal: i32) -> bool {
+        self.group.is_some_and(|group| unsafe { libc::kill(-group, signal) } == 0)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.terminate();
+    }
+}
+
+/// Report every line a process prints, until it closes the stream.
+async fn forward(stream: impl AsyncRead + Unpin, service: String, stderr: bool, output: Arc<Output>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line).await.is_ok_and(|read| read > 0) {
+        let text = String::from_utf8_lossy(&line);
+        output.process(&service, text.trim_end_matches(['\r', '\n']), stderr);
+        line.clear();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::core::output::OutputFormat;
+
+    #[tokio::test]
+    async fn stop_waits_for_the_group() {
+        let output = Arc::new(Output::new(OutputFormat::Json, None));
+        let process = Process::spawn("test", "sleep 30", &output).unwrap();
+        let group = process.group.unwrap();
+        process.stop().await;
+        assert_ne!(unsafe { libc::kill(-group, 0) }, 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Conditions a service has to meet before its tunnel is opened.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use super::Service;
+use crate::core::health::http_status;
+use crate::core::shared::{NETWORK_TIMEOUT, deserialize_duration, format_duration};
+use crate::core::target::connect_with_timeout;
+
+/// Time between two checks, if not configured otherwise.
+const DEFAULT_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When the local service of an entry is ready. Every condition that is set
+/// has to hold.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WaitFor {
+    /// TCP port that has to accept connections, the service's port for `http`
+    /// if unset.
+    pub port: Option<u16>,
+
+    /// Host of `port` and `http`, the service's host if unset.
+    pub host: Option<String>,
+
+    /// HTTP path that has to answer with status 200.
+    pub http: Option<String>,
+
+    /// Shell command that has to exit with status 0.
+    pub command: Option<String>,
+
+    /// Time between two checks.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub interval: Option<Duration>,
+
+    /// Time after which the service fails, waits as long as it takes if unset.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub timeout: Option<Duration>,
+}
+
+impl WaitFor {
+    /// Check that the conditions can be tested for a service.
+    pub fn validate(&self, service: &Service) -> Result<()> {
+        if self.port.is_none() && self.http.is_none() && self.command.is_none() {
+            bail!("Service '{}' needs port, http or command in wait-for", service.name);
+        }
+        if self.http.is_some() && self.port.or(service.port).is_none() {
+            bail!("Service '{}' needs a port to wait for http", service.name);
+        }
+        Ok(())
+    }
+
+    /// Wait until all conditions hold, failing after the timeout.
+    pub async fn wait(&self, service: &Service) -> Result<()> {
+        let checks = async {
+            while !self.check(service).await {
+                tokio::time::sleep(self.interval.unwrap_or(DEFAULT_WAIT_INTERVAL)).await;
+            }
+        };
+        let Some(limit) = self.timeout else {
+            checks.await;
+            return Ok(());
+        };
+        timeout(limit, checks)
+            .await
+            .map_err(|_| anyhow::anyhow!("Service '{}' was not ready after {}", service.name, format_duration(limit)))
+    }
+
+    /// Test the conditions once.
+    async fn check(&self, service: &Service) -> bool {
+        let host = self
+            .host
+            .as_deref()
+            .or(service.host.as_deref())
+            .unwrap_or("localhost");
+        let port = self.port.or(service.port);
+
+        if let Some(port) = port.filter(|_| self.port.is_some() || self.http.is_some()) {
+            let Ok(stream) = connect_with_timeout(host, port).await else {
+                return false;
+            };
+            if let Some(path) = &self.http {
+                let status = timeout(NETWORK_TIMEOUT, http_status(stream, host, path)).await;
+                if !matches!(status, Ok(Some(200))) {
+                    return false;
+                }
+            }
+        }
+        match &self.command {
+            Some(command) => shell(command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .status()
+                .await
+                .is_ok_and(|status| status.success()),
+            None => true,
+        }
+    }
+}
+
+/// A command run by the system shell.
+pub fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
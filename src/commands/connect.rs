@@ -0,0 +1,97 @@
+//! Visitor side of private tunnels.
+//!
+//! Opens a local listener and forwards every accepted connection to a private
+//! tunnel, answering the server's password challenge first.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use libstrawberry::colors::{BLUE, C_RESET, GRAY, ITALIC, MAGENTA, RESET};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{Instrument, info_span};
+
+use crate::core::auth::secret::Authenticator;
+use crate::core::constants::CLIENT_LOG;
+use crate::core::shared::{ClientMessage, Delimited, ServerMessage};
+use crate::core::target::connect_with_timeout;
+
+/// Forward a local port to a private tunnel on the server.
+pub async fn connect(
+    server: &str,
+    port: u16,
+    listen_port: Option<u16>,
+    password: Option<&str>,
+    verbose: bool,
+) -> Result<()> {
+    let Some(password) = password else {
+        bail!("A password is required to connect to a private tunnel (--password)");
+    };
+
+    let listen_addr = SocketAddr::from(([127, 0, 0, 1], listen_port.unwrap_or(port)));
+    let listener = TcpListener::bind(listen_addr).await?;
+    let auth = Arc::new(Authenticator::new(password));
+    let server = Arc::new(server.to_string());
+
+    CLIENT_LOG.ok(format!(
+        "Connecting {BLUE}{}{RESET}->{ITALIC}{MAGENTA}{server}:{port}{RESET}",
+        listener.local_addr()?
+    ));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let auth = Arc::clone(&auth);
+        let server = Arc::clone(&server);
+
+        tokio::spawn(
+            async move {
+                if verbose {
+                    CLIENT_LOG.info(format!("New connection ({GRAY}{addr}{C_RESET})"));
+                }
+                match forward(stream, &server, port, &auth).await {
+                    Ok(()) => {
+                        if verbose {
+                            CLIENT_LOG.info(format!("Connection exited ({GRAY}{addr}{C_RESET})"));
+                        }
+                    }
+                    Err(err) => CLIENT_LOG.error(format!(
+                        "Connection ({GRAY}{addr}{C_RESET}) exited with error: {err}"
+                    )),
+                }
+            }
+            .instrument(info_span!("visitor", %addr)),
+        );
+    }
+}
+
+/// Authenticate against the private tunnel and forward a local connection.
+async fn forward(mut local_conn: TcpStream, server: &str, port: u16, auth: &Authenticator) -> Result<()> {
+    let mut remote_conn = Delimited::new(connect_with_timeout(server, port).await?);
+    authenticate(&mut remote_conn, auth).await?;
+
+    let mut parts = remote_conn.into_parts();
+    debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+    local_conn.write_all(&parts.read_buf).await?;
+    tokio::io::copy_bidirectional(&mut local_conn, &mut parts.io).await?;
+    Ok(())
+}
+
+/// Answer the password challenge of a private tunnel.
+pub async fn authenticate<U: AsyncRead + AsyncWrite + Unpin>(
+    remote_conn: &mut Delimited<U>,
+    auth: &Authenticator,
+) -> Result<()> {
+    let Some(ServerMessage::Challenge(challenge)) = remote_conn.recv_timeout().await? else {
+        bail!("Tunnel did not ask for a password, is it private?");
+    };
+    remote_conn
+        .send(ClientMessage::Authenticate(auth.answer(&challenge)))
+        .await?;
+
+    match remote_conn.recv_timeout().await? {
+        Some(ServerMessage::Authenticated) => Ok(()),
+        Some(ServerMessage::Error(message)) => bail!("Server Error: {message}"),
+        _ => bail!("Server Error: unexpected response to authentication"),
+    }
+}
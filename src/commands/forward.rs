@@ -0,0 +1,136 @@
+//! Local forwarding through the server.
+//!
+//! Opens a local listener and asks the server to connect every accepted
+//! connection to a target reachable from the server, or to the shared tunnel
+//! of another client. The server decides with its forwarding rules which
+//! targets are allowed.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use libstrawberry::colors::{BLUE, C_RESET, GRAY, ITALIC, MAGENTA, RESET};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{Instrument, info_span};
+
+use crate::commands::connect::authenticate;
+use crate::core::auth::authenticator::StrawberryIdAuthenticator;
+use crate::core::auth::secret::Authenticator;
+use crate::core::constants::CLIENT_LOG;
+use crate::core::shared::{ClientMessage, Delimited, ForwardTarget, ServerMessage};
+use crate::core::target::connect_with_timeout;
+
+/// Settings shared by all forwarded connections.
+struct Forwarder {
+    server: String,
+    control_port: u16,
+    target: ForwardTarget,
+
+    /// Authenticates against the server, if it requires a secret.
+    auth: Option<Authenticator>,
+
+    /// Answers the challenge of a private tunnel.
+    password: Option<Authenticator>,
+
+    /// Strawberry ID sent with every forward request, if requested.
+    id: Option<StrawberryIdAuthenticator>,
+}
+
+/// Forward a local port to a target behind the server.
+#[allow(clippy::too_many_arguments)]
+pub async fn forward(
+    server: &str,
+    control_port: u16,
+    target: Option<&str>,
+    listen_port: Option<u16>,
+    secret: Option<&str>,
+    password: Option<&str>,
+    require_auth: bool,
+    verbose: bool,
+) -> Result<()> {
+    let Some(target) = target else {
+        bail!("A target is required (host:port or tunnel:<name>)");
+    };
+    let Some(target) = ForwardTarget::parse(target) else {
+        bail!("Invalid target '{target}' (expected host:port or tunnel:<name>)");
+    };
+
+    let listen_port = match (&target, listen_port) {
+        (_, Some(listen_port)) => listen_port,
+        (ForwardTarget::Address(_, port), None) => *port,
+        (ForwardTarget::Tunnel(_), None) => bail!("A local port is required to forward a tunnel (--listen)"),
+    };
+
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], listen_port))).await?;
+    CLIENT_LOG.ok(format!(
+        "Forwarding {BLUE}{}{RESET}->{ITALIC}{MAGENTA}{target}{RESET} via {ITALIC}{MAGENTA}{server}{RESET}",
+        listener.local_addr()?
+    ));
+
+    let forwarder = Arc::new(Forwarder {
+        server: server.to_string(),
+        control_port,
+        target,
+        auth: secret.map(Authenticator::new),
+        password: password.map(Authenticator::new),
+        id: if require_auth { StrawberryIdAuthenticator::fetch().ok() } else { None },
+    });
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let forwarder = Arc::clone(&forwarder);
+
+        tokio::spawn(
+            async move {
+                if verbose {
+                    CLIENT_LOG.info(format!("New connection ({GRAY}{addr}{C_RESET})"));
+                }
+                match forwarder.forward(stream).await {
+                    Ok(()) => {
+                        if verbose {
+                            CLIENT_LOG.info(format!("Connection exited ({GRAY}{addr}{C_RESET})"));
+                        }
+                    }
+                    Err(err) => CLIENT_LOG.error(format!(
+                        "Connection ({GRAY}{addr}{C_RESET}) exited with error: {err}"
+                    )),
+                }
+            }
+            .instrument(info_span!("forward", %addr)),
+        );
+    }
+}
+
+impl Forwarder {
+    /// Ask the server for a connection to the target and forward a local connection.
+    async fn forward(&self, mut local_conn: TcpStream) -> Result<()> {
+        let mut remote_conn =
+            Delimited::new(connect_with_timeout(&self.server, self.control_port).await?);
+
+        if let Some(auth) = &self.auth {
+            auth.client_handshake(&mut remote_conn).await?;
+        }
+
+        remote_conn
+            .send(ClientMessage::Forward(self.target.clone(), self.id.clone()))
+            .await?;
+
+        match remote_conn.recv_timeout().await? {
+            Some(ServerMessage::Forwarding) => (),
+            Some(ServerMessage::Error(message)) => bail!("Server Error: {message}"),
+            _ => bail!("Server Error: unexpected response to forward request"),
+        }
+
+        // From here on, the server treats us like any visitor of the tunnel.
+        if let Some(password) = &self.password {
+            authenticate(&mut remote_conn, password).await?;
+        }
+
+        let mut parts = remote_conn.into_parts();
+        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+        local_conn.write_all(&parts.read_buf).await?;
+        tokio::io::copy_bidirectional(&mut local_conn, &mut parts.io).await?;
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use libstrawberry::colors::{C_RESET, GREEN, BOLD, UNDERLINE, CYAN, RESET, WHITE, RED, MAGENTA};
 use crate::core::constants::VERSION;
 
+#[allow(clippy::too_many_lines)]
 pub fn help() {
     println!("\
 {BOLD}{CYAN}{UNDERLINE}Strawberry Tunneled v{}{C_RESET}\n\
@@ -13,13 +14,67 @@ pub fn help() {
 
     {CYAN}{BOLD}local <port>:{C_RESET} Starts a local proxy to the remote server
      {BOLD}↳ {MAGENTA}Options:{C_RESET}
-            {CYAN}{BOLD}-u, --use <server>{C_RESET}      Select your target server for tunneling your traffic
+            {CYAN}{BOLD}-u, --use <server>{C_RESET}      Select your target server, or a ws://, wss:// or quic:// URL
             {CYAN}{BOLD}-h, --address <host>{C_RESET}    The address to expose                 {GREEN}{BOLD}[default: localhost]{C_RESET}
             {CYAN}{BOLD}-p, --port <port>{C_RESET}       The port to expose                    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--unix <path>{C_RESET}           Expose a Unix domain socket           {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-s, --secret <secret>{C_RESET}   Secret for authentication             {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-a, --auth{C_RESET}              Use Strawberry ID for Authentication  {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-cp, --control-port{C_RESET}     Control port for remote proxy server  {GREEN}{BOLD}[default: 7835]{C_RESET}
+            {CYAN}{BOLD}-sp, --static-port{C_RESET}      Static port forwarding (whitelist)    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--proxy <url>{C_RESET}           Reach the server through a proxy      {GREEN}{BOLD}[default: $HTTPS_PROXY]{C_RESET}
+            {CYAN}{BOLD}-pw, --password{C_RESET}         Make the tunnel private               {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--protocol <tcp|http>{C_RESET}   Protocol of the tunnel                {GREEN}{BOLD}[default: tcp]{C_RESET}
+            {CYAN}{BOLD}--ttl <dur>{C_RESET}             Maximum lifetime of the tunnel        {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--compression <alg>{C_RESET}     Compress connections (zstd|deflate)   {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--allow <cidr>{C_RESET}          Only accept visitors from a network   {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--deny <cidr>{C_RESET}           Drop visitors from a network          {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--share <name>{C_RESET}          Serve a tunnel with other clients     {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--weight <n>{C_RESET}            Share of connections, 0 for standby   {GREEN}{BOLD}[default: 1]{C_RESET}
+            {CYAN}{BOLD}--conn-idle-timeout{C_RESET}     Close idle connections                {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--conn-write-timeout{C_RESET}    Close connections stuck writing       {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--max-conn-duration{C_RESET}     Maximum duration of connections       {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--health-check{C_RESET}          Check health of the local service     {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--health-path <path>{C_RESET}    HTTP path for health checks           {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--health-interval <dur>{C_RESET} Time between health checks            {GREEN}{BOLD}[default: 10s]{C_RESET}
+            {CYAN}{BOLD}-o, --output <format>{C_RESET}   Output as text or JSON events         {GREEN}{BOLD}[default: text]{C_RESET}
+            {CYAN}{BOLD}--assignments-file{C_RESET}      Write the public address to a file    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--tui{C_RESET}                   Show a live dashboard of the tunnel   {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-v, --verbose    {C_RESET}       Enable verbose logging                {GREEN}{BOLD}[optional]{C_RESET}
+
+    {CYAN}{BOLD}connect <port>:{C_RESET} Connects to a private tunnel through a local port
+     {BOLD}↳ {MAGENTA}Options:{C_RESET}
+            {CYAN}{BOLD}-u, --use <server>{C_RESET}      Server hosting the private tunnel
+            {CYAN}{BOLD}-pw, --password{C_RESET}         Password of the private tunnel
+            {CYAN}{BOLD}-l, --listen <port>{C_RESET}     Local port to listen on               {GREEN}{BOLD}[default: <port>]{C_RESET}
+            {CYAN}{BOLD}-v, --verbose    {C_RESET}       Enable verbose logging                {GREEN}{BOLD}[optional]{C_RESET}
+
+    {CYAN}{BOLD}forward <target>:{C_RESET} Forwards a local port to host:port or tunnel:<name> behind the server
+     {BOLD}↳ {MAGENTA}Options:{C_RESET}
+            {CYAN}{BOLD}-u, --use <server>{C_RESET}      Server to forward through
+            {CYAN}{BOLD}-cp, --control-port{C_RESET}     Control port for remote proxy server  {GREEN}{BOLD}[default: 7835]{C_RESET}
+            {CYAN}{BOLD}-s, --secret <secret>{C_RESET}   Secret for authentication             {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-a, --auth{C_RESET}              Use Strawberry ID for Authentication  {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-pw, --password{C_RESET}         Password of a private tunnel          {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-l, --listen <port>{C_RESET}     Local port to listen on               {GREEN}{BOLD}[default: target port]{C_RESET}
+            {CYAN}{BOLD}-v, --verbose    {C_RESET}       Enable verbose logging                {GREEN}{BOLD}[optional]{C_RESET}
+
+    {CYAN}{BOLD}proxy:{C_RESET} Lets visitors reach the local network through SOCKS5 or HTTP CONNECT
+     {BOLD}↳ {MAGENTA}Options:{C_RESET}
+            {CYAN}{BOLD}--allow-dest <rule>{C_RESET}     Allow a destination, e.g. 10.0.0.0/8:*
+            {CYAN}{BOLD}-u, --use <server>{C_RESET}      Select your target server for tunneling your traffic
             {CYAN}{BOLD}-s, --secret <secret>{C_RESET}   Secret for authentication             {GREEN}{BOLD}[optional]{C_RESET}
             {CYAN}{BOLD}-a, --auth{C_RESET}              Use Strawberry ID for Authentication  {GREEN}{BOLD}[optional]{C_RESET}
             {CYAN}{BOLD}-cp, --control-port{C_RESET}     Control port for remote proxy server  {GREEN}{BOLD}[default: 7835]{C_RESET}
             {CYAN}{BOLD}-sp, --static-port{C_RESET}      Static port forwarding (whitelist)    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--proxy <url>{C_RESET}           Reach the server through a proxy      {GREEN}{BOLD}[default: $HTTPS_PROXY]{C_RESET}
+            {CYAN}{BOLD}--ttl <dur>{C_RESET}             Maximum lifetime of the tunnel        {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--compression <alg>{C_RESET}     Compress connections (zstd|deflate)   {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--allow <cidr>{C_RESET}          Only accept visitors from a network   {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--deny <cidr>{C_RESET}           Drop visitors from a network          {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-o, --output <format>{C_RESET}   Output as text or JSON events         {GREEN}{BOLD}[default: text]{C_RESET}
+            {CYAN}{BOLD}--assignments-file{C_RESET}      Write the public address to a file    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--tui{C_RESET}                   Show a live dashboard of the tunnel   {GREEN}{BOLD}[optional]{C_RESET}
             {CYAN}{BOLD}-v, --verbose    {C_RESET}       Enable verbose logging                {GREEN}{BOLD}[optional]{C_RESET}
 
     {CYAN}{BOLD}server:{C_RESET} Runs the remote proxy server
@@ -27,14 +82,45 @@ pub fn help() {
             {CYAN}{BOLD}-s, --secret <secret>{C_RESET}   Secret for authentication                 {GREEN}{BOLD}[optional]{C_RESET}
             {CYAN}{BOLD}-id, --require-id{C_RESET}       Enable Strawberry ID for Authentication   {GREEN}{BOLD}[optional]{C_RESET}
             {CYAN}{BOLD}-cp, --control-port{C_RESET}     Control port for proxy server             {GREEN}{BOLD}[default: 7835]{C_RESET}
+            {CYAN}{BOLD}--ws-port <port>{C_RESET}        Also accept clients over WebSocket        {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--trusted-proxy <cidr>{C_RESET}  Reverse proxy in front of WebSocket port  {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--quic-port <port>{C_RESET}      Also accept clients over QUIC (UDP)       {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--quic-cert <file>{C_RESET}      PEM certificate chain for QUIC            {GREEN}{BOLD}[default: self-signed]{C_RESET}
+            {CYAN}{BOLD}--quic-key <file>{C_RESET}       PEM private key for QUIC                  {GREEN}{BOLD}[optional]{C_RESET}
             {CYAN}{BOLD}--min-port <port>{C_RESET}       Minimum Port for the remote proxy server  {GREEN}{BOLD}[default: 1024]{C_RESET}
             {CYAN}{BOLD}--max-port <port>{C_RESET}       Maximum Port for the remote proxy server  {GREEN}{BOLD}[default: 65535]{C_RESET}
             {CYAN}{BOLD}-t, --tunnels-addr{C_RESET}      IP address where tunnels will listen on   {GREEN}{BOLD}[default: 0.0.0.0]{C_RESET}
             {CYAN}{BOLD}-f, --file <file>{C_RESET}       Configuration file for server config      {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--max-lifetime <dur>{C_RESET}    Maximum lifetime of tunnels               {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--idle-timeout <dur>{C_RESET}    Close tunnels without connections         {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--conn-idle-timeout{C_RESET}     Close idle connections                    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--conn-write-timeout{C_RESET}    Close connections stuck writing           {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--max-conn-duration{C_RESET}     Maximum duration of connections           {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--allow-forward <rule>{C_RESET}  Allow local forwarding to a target        {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--no-compression{C_RESET}        Don't let clients compress connections    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--pid-file <file>{C_RESET}       Write the process id to a file            {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--user <user>{C_RESET}           Drop privileges after binding ports       {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--group <group>{C_RESET}         Group to use when dropping privileges     {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--tui{C_RESET}                   Show a live dashboard of the clients      {GREEN}{BOLD}[optional]{C_RESET}
             {CYAN}{BOLD}-v, --verbose    {C_RESET}       Enable verbose logging                    {GREEN}{BOLD}[optional]{C_RESET}
 
     {CYAN}{BOLD}compose:{C_RESET} Starts one or more local proxies for the remote server using a configuration file
+     {BOLD}↳ {MAGENTA}Commands:{C_RESET}
+            {CYAN}{BOLD}up{C_RESET}                    Start the services (default)
+            {CYAN}{BOLD}ps{C_RESET}                    Show the services running in the background
+            {CYAN}{BOLD}logs [service]{C_RESET}        Print what a service logged in the background
+            {CYAN}{BOLD}down{C_RESET}                  Stop the services running in the background
+            {CYAN}{BOLD}config{C_RESET}                Print the resolved configuration
      {BOLD}↳ {MAGENTA}Options:{C_RESET}
-            {CYAN}{BOLD}-f, --file <file>{C_RESET}     Configuration file for proxy services   {GREEN}{BOLD}[default: service.yml]{C_RESET}", *VERSION);
+            {CYAN}{BOLD}-f, --file <file>{C_RESET}     Configuration file for proxy services   {GREEN}{BOLD}[default: service.yml]{C_RESET}
+            {CYAN}{BOLD}--profile <name>{C_RESET}      Also start services of a profile        {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--watch{C_RESET}               Apply changes of the file while running {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-d, --detach{C_RESET}          Run the services in the background      {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--follow{C_RESET}              Keep printing new lines of logs         {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--proxy <url>{C_RESET}         Reach the server through a proxy        {GREEN}{BOLD}[default: $HTTPS_PROXY]{C_RESET}
+            {CYAN}{BOLD}-o, --output <format>{C_RESET} Output as text or JSON events           {GREEN}{BOLD}[default: text]{C_RESET}
+            {CYAN}{BOLD}--assignments-file{C_RESET}    Write the public addresses to a file    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}--tui{C_RESET}                 Show a live dashboard of the tunnels    {GREEN}{BOLD}[optional]{C_RESET}
+            {CYAN}{BOLD}-v, --verbose{C_RESET}         Enable verbose logging                  {GREEN}{BOLD}[optional]{C_RESET}", *VERSION);
     std::process::exit(0);
 }
\ No newline at end of file
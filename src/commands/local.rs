@@ -1,186 +1,786 @@
-#![allow(clippy::too_many_arguments)]
 //! Client implementation for the `tunneled` service.
 
-use std::sync::Arc;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result, bail};
-use libstrawberry::colors::{BLUE, BOLD, C_RESET, CYAN, GRAY, ITALIC, MAGENTA, RED, RESET};
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use anyhow::Result;
+use thiserror::Error;
+use libstrawberry::colors::{BLUE, C_RESET, CYAN, GRAY, GREEN, ITALIC, MAGENTA, RED, RESET};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tracing::{Instrument, info_span};
 use uuid::Uuid;
 
-use crate::cli::OPTIONS;
-use crate::commands::compose::Service;
+use crate::core::acl::DestinationAcl;
 use crate::core::auth::authenticator::StrawberryIdAuthenticator;
 use crate::core::auth::secret::Authenticator;
-use crate::core::constants::{SERVER_LOG, CLIENT_LOG};
-use crate::core::shared::{ClientMessage, Delimited, NETWORK_TIMEOUT, ServerMessage};
+use crate::core::balance::Upstreams;
+use crate::core::compression::{Compression, CompressedStream};
+use crate::core::constants::{CLIENT_LOG, DEFAULT_CONTROL_PORT, DEFAULT_SERVER, SERVER_LOG};
+use crate::core::events::{ClientEvent, EVENT_CAPACITY};
+use crate::core::control::{ClientCommand, ClientControl, ClientState, LiveConnection};
+use crate::core::forward::{CompressedBytes, ConnectionLimits, Forwarded, forward_tracked};
+use crate::core::health::HealthCheck;
+use crate::core::metrics::Metrics;
+use crate::core::outbound::OutboundProxy;
+use crate::core::output::Verbosity;
+use crate::core::proxy;
+use crate::core::shared::{
+    ClientMessage, Delimited, MAX_FRAME_LENGTH, ServerMessage, TunnelOptions, TunnelProtocol, format_duration, tick,
+};
+use crate::core::target::LocalTarget;
+use crate::core::transport::{Endpoint, Transport, TransportStream};
 
-/// State structure for the client.
-pub struct Client {
-    /// Control connection to the server.
-    connection: Option<Delimited<TcpStream>>,
+/// Exit code of the client when the server closed its tunnel after it expired.
+pub const EXIT_EXPIRED: i32 = 3;
 
-    /// Destination address of the server.
-    to: String,
+/// Delay before the first retry of a reconnect, doubled for every further one.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
 
-    /// Local host that is forwarded.
-    local_host: String,
+/// Longest delay between retries of a reconnect.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_mins(1);
 
-    /// Local port that is forwarded.
-    local_port: u16,
+/// Errors of a client, while connecting or once it is running.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The server could not be reached.
+    #[error("Server Error: {0:#}")]
+    Connect(anyhow::Error),
 
-    /// Tcp connection port for remote server
-    control_port: u16,
+    /// The server requires a secret, but none was configured.
+    #[error("Server Error: Server requires authentication, but no client secret was provided")]
+    SecretRequired,
 
-    /// Optional secret used to authenticate clients.
-    auth: Option<Authenticator>,
+    /// The server refused to open the tunnel.
+    #[error("Server Error: {0}")]
+    Rejected(String),
+
+    /// The server sent something unexpected.
+    #[error("Server Error: {0}")]
+    Protocol(&'static str),
+
+    /// The tunnel options do not fit in a hello, usually because of too
+    /// many source networks.
+    #[error("Too many source networks ({0}), the tunnel options have to fit in {limit} bytes", limit = MAX_FRAME_LENGTH)]
+    HelloTooLarge(usize),
+
+    /// The tunnel reached a lifetime or idle limit on the server.
+    #[error("{0}")]
+    Expired(String),
+
+    /// The control connection failed.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
 }
 
-impl Client {
-    /// Create a new client.
-    pub async fn new(
-        host: &str,
-        port: u16,
-        server: &str,
-        secret: Option<&str>,
-        static_port: Option<u16>,
-        control_port: u16,
-        require_auth: bool,
-        service: Option<&Service>,
-    ) -> Result<Self> {
-        let mut stream = Delimited::new(
-            connect_with_timeout(server, control_port)
-                .await
-                .unwrap_or_else(|err| {
-                    eprintln!(" {RED}{BOLD}!{C_RESET}  Server Error: {err}");
-                    std::process::exit(1)
-                }),
-        );
+impl ClientError {
+    /// Whether opening the tunnel again can't help.
+    #[must_use]
+    pub const fn is_permanent(&self) -> bool {
+        matches!(self, Self::SecretRequired | Self::HelloTooLarge(_) | Self::Expired(_))
+    }
 
-        let auth = secret.map(Authenticator::new);
+    /// Exit code for the binary, distinct from generic errors.
+    #[must_use]
+    pub const fn exit_code(&self) -> i32 {
+        match self {
+            Self::Expired(_) => EXIT_EXPIRED,
+            _ => 1,
+        }
+    }
+}
 
-        if let Some(auth) = &auth {
-            auth.client_handshake(&mut stream).await?;
+/// Where the client sends forwarded connections.
+pub enum Backend {
+    /// Local services, balanced if there are several.
+    Upstreams(Upstreams),
+
+    /// Destinations requested per connection with SOCKS5 or HTTP CONNECT.
+    Proxy(DestinationAcl),
+}
+
+impl Backend {
+    /// Forward all connections to a single local service.
+    #[must_use]
+    pub fn local(target: LocalTarget) -> Self {
+        Self::Upstreams(Upstreams::single(target))
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Upstreams(upstreams) => write!(f, "{upstreams}"),
+            Self::Proxy(acl) => write!(f, "proxy ({acl})"),
         }
+    }
+}
 
-        let id = if require_auth {
-            StrawberryIdAuthenticator::fetch().ok()
-        } else {
-            None
-        };
+/// Settings for a new [`Client`], created with [`Client::builder`].
+pub struct ClientBuilder {
+    backend: Backend,
+    server: String,
+    control_port: u16,
+    secret: Option<String>,
+    static_port: Option<u16>,
+    require_auth: bool,
+    options: TunnelOptions,
+    connection_limits: ConnectionLimits,
+    health_check: Option<HealthCheck>,
+    name: Option<String>,
+    proxy: Option<OutboundProxy>,
+    ping_interval: Option<Duration>,
+    verbosity: Verbosity,
+}
+
+impl ClientBuilder {
+    /// Server to open the tunnel on, a host name or a `ws://` or `wss://` URL.
+    #[must_use]
+    pub fn server(mut self, server: impl Into<String>) -> Self {
+        self.server = server.into();
+        self
+    }
+
+    /// Control port of the server.
+    #[must_use]
+    pub const fn control_port(mut self, control_port: u16) -> Self {
+        self.control_port = control_port;
+        self
+    }
+
+    /// Secret the server requires from clients.
+    #[must_use]
+    pub fn secret(mut self, secret: Option<&str>) -> Self {
+        self.secret = secret.map(ToString::to_string);
+        self
+    }
+
+    /// Static port to request, for whitelisted Strawberry ID users.
+    #[must_use]
+    pub const fn static_port(mut self, static_port: Option<u16>) -> Self {
+        self.static_port = static_port;
+        self
+    }
+
+    /// Authenticate with the Strawberry ID of the logged in user.
+    #[must_use]
+    pub const fn require_auth(mut self, require_auth: bool) -> Self {
+        self.require_auth = require_auth;
+        self
+    }
+
+    /// Options of the tunnel, sent to the server.
+    #[must_use]
+    pub fn options(mut self, options: TunnelOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Time limits of forwarded connections.
+    #[must_use]
+    pub const fn connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.connection_limits = connection_limits;
+        self
+    }
+
+    /// Health check of the local service.
+    #[must_use]
+    pub fn health_check(mut self, health_check: Option<HealthCheck>) -> Self {
+        self.health_check = health_check;
+        self
+    }
+
+    /// Name of the service, used in logs.
+    #[must_use]
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
 
-        stream
-            .send(ClientMessage::Hello(0, id, static_port))
-            .await?;
-
-        let (addr, remote_port) = match stream.recv_timeout().await? {
-            Some(ServerMessage::Hello(addr, remote_port)) => (addr, remote_port),
-            Some(ServerMessage::Error(message)) => bail!("Server Error: {message}"),
-            Some(ServerMessage::Challenge(_)) => bail!(
-                "Server Error: Server requires authentication, but no client secret was provided"
-            ),
-            Some(_) => bail!("Server Error: unexpected initial non-hello message"),
-            None => bail!("Server Error: unexpected EOF"),
+    /// Proxy to reach the server through.
+    #[must_use]
+    pub fn proxy(mut self, proxy: Option<OutboundProxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Measure the round trip time of the control connection this often.
+    ///
+    /// Servers before pings were introduced close the tunnel on a ping.
+    #[must_use]
+    pub const fn ping_interval(mut self, ping_interval: Option<Duration>) -> Self {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    /// How much the client logs, see [`Verbosity`].
+    #[must_use]
+    pub const fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Connect to the server and open the tunnel.
+    pub async fn connect(self) -> Result<Client, ClientError> {
+        let endpoint = Endpoint::parse(&self.server, self.control_port).map_err(ClientError::Connect)?;
+        let auth = self.secret.as_deref().map(Authenticator::new);
+        let request = TunnelRequest {
+            static_port: self.static_port,
+            require_auth: self.require_auth,
+            options: TunnelOptions {
+                peers: true,
+                ..self.options.clone()
+            },
         };
+        let tunnel = request.open(&endpoint, self.proxy.as_ref(), auth.as_ref()).await?;
+
+        if self.verbosity.logs() {
+            self.log_startup(&endpoint, &tunnel.addr, tunnel.port, tunnel.compression);
+        }
+
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        Ok(Client {
+            connection: Some(tunnel.stream),
+            endpoint,
+            backend: self.backend,
+            auth,
+            request,
+            compression: Mutex::new(tunnel.compression),
+            connection_limits: self.connection_limits,
+            metrics: Metrics::default(),
+            health_check: self.health_check,
+            proxy: self.proxy,
+            ping_interval: self.ping_interval,
+            state: Arc::new(ClientState {
+                tunnel: Mutex::new((tunnel.addr, tunnel.port)),
+                ..ClientState::default()
+            }),
+            commands: (commands_tx, Some(commands_rx)),
+            verbosity: self.verbosity,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        })
+    }
+
+    /// Describe the opened tunnel.
+    fn log_startup(&self, endpoint: &Endpoint, addr: &str, remote_port: u16, compression: Option<Compression>) {
+        let Self { backend, server, .. } = self;
 
-        if let Some(service) = service {
+        if let Some(name) = &self.name {
             CLIENT_LOG.ok(format!(
-                "Starting tunneling service '{CYAN}{}{RESET}'",
-                service.name
+                "Starting tunneling service '{CYAN}{name}{RESET}'"
             ));
             CLIENT_LOG.info(format!(
-                "Forwarding rule: {BLUE}{host}:{port}{RESET}->{ITALIC}{MAGENTA}{server}{RESET}"
+                "Forwarding rule: {BLUE}{backend}{RESET}->{ITALIC}{MAGENTA}{server}{RESET}"
             ));
+        } else {
+            CLIENT_LOG.ok(format!("Starting tunneling for {BLUE}{backend}{RESET}->{ITALIC}{MAGENTA}{server}{RESET}"));
+        }
+
+        if let Some(proxy) = self.proxy.as_ref().filter(|proxy| !proxy.bypasses(endpoint.host())) {
+            CLIENT_LOG.info(format!("Connecting through proxy {BLUE}{proxy}{RESET}"));
         }
 
-        if service.is_none() {
-            CLIENT_LOG.ok(format!("Starting tunneling for {BLUE}{host}:{port}{RESET}->{ITALIC}{MAGENTA}{server}{RESET}"));
+        if endpoint.transport() != Transport::Tcp {
+            CLIENT_LOG.info(format!("Connecting over {}", endpoint.transport()));
         }
 
-        if require_auth {
+        if let Some(compression) = compression {
+            CLIENT_LOG.info(format!("Compressing connections with {compression}"));
+        } else if let Some(requested) = self.options.compression {
+            CLIENT_LOG.warning(format!("Server does not allow {requested} compression, connections are not compressed"));
+        }
+
+        if self.require_auth {
             CLIENT_LOG.info("Using Strawberry ID Authentication");
         }
 
+        if matches!(backend, Backend::Proxy(_)) {
+            CLIENT_LOG.info("Proxy mode, visitors connect with SOCKS5 or HTTP CONNECT");
+        }
+
+        if let Some(health_check) = &self.health_check {
+            CLIENT_LOG.info(format!(
+                "Checking health of local service every {}{}",
+                format_duration(health_check.interval()),
+                health_check.path.as_ref().map_or_else(String::new, |path| format!(" ({path})"))
+            ));
+        }
+
+        if let Some(ttl) = self.options.ttl {
+            CLIENT_LOG.info(format!(
+                "Requested tunnel lifetime: {}",
+                format_duration(Duration::from_secs(ttl))
+            ));
+        }
+
+        let sources = &self.options.sources;
+        if !sources.allow.is_empty() {
+            CLIENT_LOG.info(format!("Only accepting visitors from {}", sources.allow.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")));
+        }
+        if !sources.deny.is_empty() {
+            CLIENT_LOG.info(format!("Dropping visitors from {}", sources.deny.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")));
+        }
+
+        if self.options.password.is_some() {
+            CLIENT_LOG.info(match self.options.protocol {
+                TunnelProtocol::Tcp => "Private tunnel, visitors connect with tunneled connect",
+                TunnelProtocol::Http => "Private tunnel, visitors authenticate with HTTP Basic auth",
+            });
+        }
+
         SERVER_LOG.info(format!(
             "Connected to server {MAGENTA}{ITALIC}{server}{C_RESET}"
         ));
         SERVER_LOG.info(format!("Listening at {BLUE}{addr}:{remote_port}{RESET}"));
 
-        if service.is_some() {
+        if self.name.is_some() {
             println!();
         }
+    }
+}
 
-        Ok(Self {
-            connection: Some(stream),
-            to: server.to_string(),
-            local_host: host.to_string(),
-            local_port: port,
-            control_port,
-            auth,
-        })
+/// What a client asks the server for in its hello message.
+struct TunnelRequest {
+    static_port: Option<u16>,
+    require_auth: bool,
+    options: TunnelOptions,
+}
+
+/// A tunnel the server opened for a client.
+struct OpenTunnel {
+    stream: Delimited<TransportStream>,
+    addr: String,
+    port: u16,
+    compression: Option<Compression>,
+}
+
+impl TunnelRequest {
+    /// Open a control connection and ask the server for the tunnel.
+    async fn open(
+        &self,
+        endpoint: &Endpoint,
+        proxy: Option<&OutboundProxy>,
+        auth: Option<&Authenticator>,
+    ) -> Result<OpenTunnel, ClientError> {
+        let id = if self.require_auth {
+            StrawberryIdAuthenticator::fetch().ok()
+        } else {
+            None
+        };
+
+        let hello = if self.options.is_default() {
+            ClientMessage::Hello(0, id, self.static_port)
+        } else {
+            ClientMessage::HelloWithOptions(0, id, self.static_port, self.options.clone())
+        };
+        // The server would only see a frame error, so fail before connecting.
+        if serde_json::to_vec(&hello).map_err(anyhow::Error::from)?.len() >= MAX_FRAME_LENGTH {
+            return Err(ClientError::HelloTooLarge(self.options.sources.allow.len() + self.options.sources.deny.len()));
+        }
+
+        let mut stream = Delimited::new(endpoint.connect(proxy).await.map_err(ClientError::Connect)?);
+
+        if let Some(auth) = auth {
+            auth.client_handshake(&mut stream).await?;
+        }
+
+        stream.send(hello).await?;
+
+        let mut message = stream.recv_timeout().await?;
+        let compression = if let Some(ServerMessage::Compression(compression)) = message {
+            message = stream.recv_timeout().await?;
+            Some(compression)
+        } else {
+            None
+        };
+
+        match message {
+            Some(ServerMessage::Hello(addr, port)) => Ok(OpenTunnel {
+                stream,
+                addr,
+                port,
+                compression,
+            }),
+            Some(ServerMessage::Error(message)) => Err(ClientError::Rejected(message)),
+            Some(ServerMessage::Challenge(_)) => Err(ClientError::SecretRequired),
+            Some(_) => Err(ClientError::Protocol("unexpected initial non-hello message")),
+            None => Err(ClientError::Protocol("unexpected EOF")),
+        }
+    }
+}
+
+/// State structure for the client.
+pub struct Client {
+    /// Control connection to the server.
+    connection: Option<Delimited<TransportStream>>,
+
+    /// Address of the server and how to reach it.
+    endpoint: Endpoint,
+
+    /// Local services or proxy destinations that are forwarded.
+    backend: Backend,
+
+    /// Optional secret used to authenticate clients.
+    auth: Option<Authenticator>,
+
+    /// Hello message to open the tunnel again after reconnecting.
+    request: TunnelRequest,
+
+    /// Compression of forwarded connections, if the server confirmed it.
+    compression: Mutex<Option<Compression>>,
+
+    /// Time limits of forwarded connections.
+    connection_limits: ConnectionLimits,
+
+    /// Counters of forwarded connections.
+    metrics: Metrics,
+
+    /// Optional health check of the local service.
+    health_check: Option<HealthCheck>,
+
+    /// Optional proxy to reach the server through.
+    proxy: Option<OutboundProxy>,
+
+    /// Time between pings on the control connection, if measured.
+    ping_interval: Option<Duration>,
+
+    /// Live connections and settings shared with controls.
+    state: Arc<ClientState>,
+
+    /// Requests from controls, the receiver is taken once listening.
+    commands: (UnboundedSender<ClientCommand>, Option<UnboundedReceiver<ClientCommand>>),
+
+    /// How much the client logs.
+    verbosity: Verbosity,
+
+    /// Lifecycle events for subscribers.
+    events: broadcast::Sender<ClientEvent>,
+}
+
+impl Client {
+    /// Start building a client that forwards connections to the backend.
+    #[must_use]
+    pub fn builder(backend: Backend) -> ClientBuilder {
+        ClientBuilder {
+            backend,
+            server: DEFAULT_SERVER.to_string(),
+            control_port: DEFAULT_CONTROL_PORT,
+            secret: None,
+            static_port: None,
+            require_auth: false,
+            options: TunnelOptions::default(),
+            connection_limits: ConnectionLimits::default(),
+            health_check: None,
+            name: None,
+            proxy: None,
+            ping_interval: None,
+            verbosity: Verbosity::default(),
+        }
+    }
+
+    /// Server the tunnel is open on.
+    #[must_use]
+    pub fn server(&self) -> &str {
+        self.endpoint.host()
+    }
+
+    /// Address the server exposes the tunnel on.
+    #[must_use]
+    pub fn remote_addr(&self) -> String {
+        self.control().remote_addr()
+    }
+
+    /// Port the server exposes the tunnel on.
+    #[must_use]
+    pub fn remote_port(&self) -> u16 {
+        self.control().remote_port()
+    }
+
+    /// Receive lifecycle events of the client from now on.
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// Handle to inspect and control the client while it listens.
+    #[must_use]
+    pub fn control(&self) -> ClientControl {
+        ClientControl {
+            state: Arc::clone(&self.state),
+            commands: self.commands.0.clone(),
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        if self.verbosity.logs() {
+            SERVER_LOG.warning(message);
+        }
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        // Sending only fails if nobody is subscribed.
+        let _ = self.events.send(event);
     }
 
     /// Start the client, listening for new connections.
-    pub async fn listen(mut self) -> Result<()> {
-        let control_port = self.control_port;
+    pub async fn listen(mut self) -> Result<(), ClientError> {
         let mut conn = self.connection.take().unwrap();
+        let mut commands = self.commands.1.take().unwrap();
         let this = Arc::new(self);
+
+        let (health_tx, mut health_rx) = mpsc::unbounded_channel();
+        if let (Some(health_check), Backend::Upstreams(_)) = (this.health_check.clone(), &this.backend) {
+            tokio::spawn(Arc::clone(&this).watch_health(health_check, health_tx));
+        }
+        let mut healthy = true;
+
+        let started = Instant::now();
+        let mut pings = this.ping_interval.map(tokio::time::interval);
+
+        loop {
+            tokio::select! {
+                message = conn.recv() => match message? {
+                    Some(ServerMessage::Hello(_, _)) => this.warn("Unexpected hello"),
+                    Some(ServerMessage::Challenge(_)) => this.warn("Unexpected challenge"),
+                    Some(ServerMessage::Authenticated) => this.warn("Unexpected authentication"),
+                    Some(ServerMessage::Forwarding) => this.warn("Unexpected forwarding confirmation"),
+                    Some(ServerMessage::Compression(_)) => this.warn("Unexpected compression confirmation"),
+                    Some(ServerMessage::Heartbeat) => (),
+                    Some(ServerMessage::Connection(id)) => this.spawn_connection(id, None),
+                    Some(ServerMessage::ConnectionFrom(id, peer)) => this.spawn_connection(id, Some(peer)),
+                    Some(ServerMessage::Pong(sent)) => {
+                        let rtt = started.elapsed().saturating_sub(Duration::from_millis(sent));
+                        *this.state.rtt.lock().unwrap() = Some(rtt);
+                    }
+                    Some(ServerMessage::Error(message)) => {
+                        if this.verbosity.logs() {
+                            SERVER_LOG.error(format!("Server error: {message}"));
+                        }
+                        this.emit(ClientEvent::ServerError { message });
+                    }
+                    Some(ServerMessage::Expired(message)) => return Err(ClientError::Expired(message)),
+                    None => {
+                        if this.verbosity.logs() {
+                            CLIENT_LOG.error("Lost connection to tunneled instance");
+                        }
+                        return Ok(());
+                    }
+                },
+                Some(health) = health_rx.recv() => {
+                    healthy = health;
+                    conn.send(ClientMessage::Health(healthy && !this.control().is_paused())).await?;
+                }
+                Some(command) = commands.recv() => match command {
+                    ClientCommand::Pause(paused) => {
+                        this.state.paused.store(paused, Ordering::Relaxed);
+                        if this.verbosity.logs() {
+                            CLIENT_LOG.info(if paused { "Paused tunnel, visitors are turned away" } else { "Resumed tunnel" });
+                        }
+                        conn.send(ClientMessage::Health(healthy && !paused)).await?;
+                    }
+                    ClientCommand::Reconnect => {
+                        // Close the old tunnel first, a static port can't be opened twice.
+                        drop(conn);
+                        conn = this.reopen_with_retries().await?;
+                        if !healthy || this.control().is_paused() {
+                            conn.send(ClientMessage::Health(false)).await?;
+                        }
+                    }
+                },
+                _ = tick(pings.as_mut()) => {
+                    let sent = u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX);
+                    conn.send(ClientMessage::Ping(sent)).await?;
+                }
+            }
+        }
+    }
+
+    /// Open the tunnel again, retrying with a growing delay until it is open
+    /// or the server refuses it for good.
+    async fn reopen_with_retries(&self) -> Result<Delimited<TransportStream>, ClientError> {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            match self.reopen().await {
+                Ok(stream) => return Ok(stream),
+                Err(err) if err.is_permanent() => return Err(err),
+                Err(err) => {
+                    self.warn(&format!("Could not reconnect: {err}, retrying in {}", format_duration(delay)));
+                    self.emit(ClientEvent::ReconnectFailed {
+                        error: err.to_string(),
+                        retry_in: delay,
+                    });
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Open the tunnel again on a new control connection.
+    async fn reopen(&self) -> Result<Delimited<TransportStream>, ClientError> {
+        let tunnel = self.request.open(&self.endpoint, self.proxy.as_ref(), self.auth.as_ref()).await?;
+        *self.compression.lock().unwrap() = tunnel.compression;
+        *self.state.rtt.lock().unwrap() = None;
+        *self.state.tunnel.lock().unwrap() = (tunnel.addr.clone(), tunnel.port);
+
+        if self.verbosity.logs() {
+            SERVER_LOG.info(format!("Reconnected, listening at {BLUE}{}:{}{RESET}", tunnel.addr, tunnel.port));
+        }
+        self.emit(ClientEvent::Reconnected {
+            addr: tunnel.addr,
+            port: tunnel.port,
+        });
+        Ok(tunnel.stream)
+    }
+
+    /// Forward a connection the server announced in the background.
+    fn spawn_connection(self: &Arc<Self>, id: Uuid, peer: Option<SocketAddr>) {
+        let this = Arc::clone(self);
+        tokio::spawn(
+            async move {
+                if this.verbosity.is_verbose() {
+                    match peer {
+                        Some(peer) => SERVER_LOG.info(format!("New connection from {peer} ({GRAY}{id}{C_RESET})")),
+                        None => SERVER_LOG.info(format!("New connection ({GRAY}{id}{C_RESET})")),
+                    }
+                }
+                this.emit(ClientEvent::ConnectionOpened { id, peer });
+                let live = this.state.open(id, peer);
+                let result = this.handle_connection(id, &live).await;
+                this.state.connections.remove(&id);
+                match &result {
+                    Ok(forwarded) if forwarded.outcome.is_expired() && this.verbosity.logs() => SERVER_LOG.info(format!(
+                        "Closed connection ({GRAY}{id}{C_RESET}) after {}, {} connections expired so far",
+                        forwarded.outcome.reason(),
+                        this.metrics.snapshot().expired()
+                    )),
+                    Ok(forwarded) => if this.verbosity.is_verbose() {
+                        SERVER_LOG.info(format!("Connection exited ({GRAY}{id}{C_RESET})"));
+                        if let Some(ratio) = forwarded.compression_ratio() {
+                            SERVER_LOG.info(format!(
+                                "Connection ({GRAY}{id}{C_RESET}) compressed {ratio:.1}x, {:.1}x overall",
+                                this.metrics.snapshot().compression_ratio().unwrap_or(1.0)
+                            ));
+                        }
+                    },
+                    Err(err) => if this.verbosity.is_verbose() {
+                        SERVER_LOG.error(format!("Connection ({GRAY}{id}{C_RESET}) exited with error: {err}"));
+                    },
+                }
+                this.emit(ClientEvent::ConnectionClosed {
+                    id,
+                    result: result.map_err(|err| err.to_string()),
+                });
+            }
+            .instrument(info_span!("proxy", %id)),
+        );
+    }
+
+    /// Probe the local service periodically and report changes of its health.
+    async fn watch_health(self: Arc<Self>, health_check: HealthCheck, health_tx: UnboundedSender<bool>) {
+        let Backend::Upstreams(upstreams) = &self.backend else {
+            return;
+        };
+        let mut interval = tokio::time::interval(health_check.interval());
+        let mut last = None;
+
         loop {
-            match conn.recv().await? {
-                Some(ServerMessage::Hello(_, _)) => SERVER_LOG.warning("Unexpected hello"),
-                Some(ServerMessage::Challenge(_)) => SERVER_LOG.warning("Unexpected challenge"),
-                Some(ServerMessage::Heartbeat) => (),
-                Some(ServerMessage::Connection(id)) => {
-                    let this = Arc::clone(&this);
-                    tokio::spawn(
-                        async move {
-                            if OPTIONS.client_options.verbose_logging {
-                                SERVER_LOG.info(format!("New connection ({GRAY}{id}{C_RESET})"));    
-                            }
-                            match this.handle_connection(id, control_port).await {
-                                Ok(()) => if OPTIONS.client_options.verbose_logging {
-                                    SERVER_LOG.info(format!("Connection exited ({GRAY}{id}{C_RESET})"));
-                                },
-                                Err(err) => if OPTIONS.client_options.verbose_logging {
-                                    SERVER_LOG.error(format!("Connection ({GRAY}{id}{C_RESET}) exited with error: {err}"));
-                                },
-                            }
-                        }.instrument(info_span!("proxy", %id)),
-                    );
+            interval.tick().await;
+            let mut healthy = false;
+            for target in upstreams.targets() {
+                if let Ok(stream) = target.connect().await
+                    && health_check.probe(stream, target.host()).await
+                {
+                    healthy = true;
+                    break;
                 }
-                Some(ServerMessage::Error(err)) => SERVER_LOG.error(format!("Server error: {err}")),
-                None => {
-                    CLIENT_LOG.error("Lost connection to tunneled instance");
-                    return Ok(());
+            }
+
+            if last == Some(healthy) {
+                continue;
+            }
+            if self.verbosity.logs() {
+                if healthy {
+                    CLIENT_LOG.info(format!(
+                        "Local service {BLUE}{upstreams}{RESET} is {GREEN}healthy{RESET}"
+                    ));
+                } else {
+                    CLIENT_LOG.warning(format!(
+                        "Local service {BLUE}{upstreams}{RESET} is {RED}unhealthy{RESET}"
+                    ));
                 }
             }
+
+            self.emit(ClientEvent::HealthChanged { healthy });
+
+            // The receiver is gone once the client stopped listening.
+            if health_tx.send(healthy).is_err() {
+                return;
+            }
+            last = Some(healthy);
         }
     }
 
-    async fn handle_connection(&self, id: Uuid, control_port: u16) -> Result<()> {
-        let mut remote_conn =
-            Delimited::new(connect_with_timeout(&self.to[..], control_port).await?);
+    async fn handle_connection(&self, id: Uuid, live: &LiveConnection) -> Result<Forwarded> {
+        let mut remote_conn = Delimited::new(self.endpoint.connect(self.proxy.as_ref()).await?);
 
         if let Some(auth) = &self.auth {
             auth.client_handshake(&mut remote_conn).await?;
         }
 
         remote_conn.send(ClientMessage::Accept(id)).await?;
-        let mut local_conn = connect_with_timeout(&self.local_host, self.local_port).await?;
         let mut parts = remote_conn.into_parts();
-
         debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-        local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
-        tokio::io::copy_bidirectional(&mut local_conn, &mut parts.io).await?;
-        Ok(())
+
+        let compression = *self.compression.lock().unwrap();
+        let Some(compression) = compression else {
+            return self.forward_connection(&mut parts.io, &parts.read_buf, live, |_| None).await;
+        };
+        let mut remote_conn = CompressedStream::new(parts.io, &parts.read_buf, compression);
+        self.forward_connection(&mut remote_conn, &[], live, |remote_conn| {
+            Some(CompressedBytes {
+                sent: remote_conn.wire_written(),
+                received: remote_conn.wire_read(),
+            })
+        })
+        .await
     }
-}
 
-pub async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
-    match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
-        Ok(res) => res,
-        Err(err) => Err(err.into()),
+    /// Forward a connection from the server to the backend.
+    async fn forward_connection<S>(
+        &self,
+        remote_conn: &mut S,
+        received: &[u8],
+        live: &LiveConnection,
+        compressed: impl FnOnce(&S) -> Option<CompressedBytes>,
+    ) -> Result<Forwarded>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut local_conn, _upstream) = match &self.backend {
+            Backend::Upstreams(upstreams) => {
+                let (mut local_conn, upstream) = upstreams.connect(self.verbosity).await?;
+                local_conn.write_all(received).await?; // mostly of the cases, this will be empty
+                (local_conn, Some(upstream))
+            }
+            Backend::Proxy(acl) => (proxy::serve(acl, remote_conn, received, self.verbosity).await?, None),
+        };
+
+        self.metrics.opened();
+        let result = tokio::select! {
+            result = forward_tracked(&mut local_conn, remote_conn, &self.connection_limits, &live.transfer) => result,
+            () = live.kill.notified() => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "closed by user")),
+        }
+        .map(|forwarded| Forwarded {
+            compressed: compressed(remote_conn),
+            ..forwarded
+        });
+        self.metrics.closed(result.as_ref().ok());
+        Ok(result?)
     }
-    .with_context(|| format!("Could not connect to {to}:{port}"))
 }
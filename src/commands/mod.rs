@@ -4,4 +4,6 @@ pub mod local;
 pub mod server;
 pub mod about;
 pub mod compose;
+pub mod connect;
+pub mod forward;
 pub mod plugin;
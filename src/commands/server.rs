@@ -1,27 +1,73 @@
-#![allow(unused_assignments)]
 //! Server implementation for the `tunneled` service.
 
 use std::fs::File;
+use std::future::Future;
 use std::io::Read;
+use std::net::IpAddr;
+use std::sync::Weak;
+use std::time::Instant;
 use std::{io, net::SocketAddr, ops::RangeInclusive, sync::Arc, time::Duration};
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
+use tokio::time::sleep;
 
 use anyhow::Result;
 use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use libstrawberry::colors::{
     BLUE, BOLD, C_RESET, CYAN, GREEN, ITALIC, MAGENTA, RED, RESET, YELLOW,
 };
 use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::{Notify, broadcast};
 use tracing::{Instrument, info_span};
 use uuid::Uuid;
 
-use crate::cli::OPTIONS;
-use crate::core::auth::authenticator::ClientAuthentication;
+use crate::core::acl::{Cidr, ForwardAcl};
+use crate::core::auth::authenticator::{ClientAuthentication, StrawberryIdAuthenticator};
 use crate::core::auth::secret::Authenticator;
-use crate::core::constants::{CLIENT_LOG, SERVER_LOG, STRAWBERRY_ID_API, VERSION};
-use crate::core::shared::{ClientMessage, Delimited, ServerMessage};
+use crate::core::compression::CompressedStream;
+use crate::core::constants::{
+    CLIENT_LOG, DEFAULT_CONTROL_PORT, SERVER_LOG, STRAWBERRY_ID_API, VERSION,
+};
+use crate::core::control::{ServerControl, ServerState, Session};
+use crate::core::daemon::{self, DaemonOptions, PidFile};
+use crate::core::events::{EVENT_CAPACITY, ServerEvent};
+use crate::core::forward::{CompressedBytes, ConnectionLimits, Forwarded, forward, forward_tracked};
+use crate::core::metrics::Metrics;
+use crate::core::private::{FailedAttempts, TunnelGate, Verdict};
+use crate::core::shared::{
+    ClientMessage, Delimited, ForwardTarget, ServerMessage, TunnelOptions, TunnelProtocol,
+    deserialize_duration, format_duration, http_response, tick,
+};
+use crate::core::target::connect_with_timeout;
+use crate::core::quic::{QuicCertificate, QuicListener, QuicStream};
+use crate::core::transport::{self, Transport, TransportStream};
+use crate::core::tunnel::{ActiveConnection, PendingConnection, Tunnel, TunnelIdentity};
+
+/// Errors that keep a server from starting or stop it.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    /// No port is left to assign to tunnels.
+    #[error("Port range is empty")]
+    EmptyPortRange,
+
+    /// The control port or WebSocket port could not be bound.
+    #[error("Could not listen on port {port}: {source}")]
+    Bind { port: u16, source: io::Error },
+
+    /// The QUIC endpoint could not be set up, e.g. its certificate is invalid.
+    #[error("Could not set up QUIC: {0:#}")]
+    Quic(anyhow::Error),
+
+    /// Setting up the process as a daemon failed, e.g. writing the pid file.
+    #[error(transparent)]
+    Daemon(#[from] anyhow::Error),
+
+    /// Accepting clients on the control port failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
 
 /// State structure for the server.
 pub struct Server {
@@ -32,11 +78,29 @@ pub struct Server {
     auth: Option<Authenticator>,
 
     /// Concurrent map of IDs to incoming connections.
-    connections: Arc<DashMap<Uuid, TcpStream>>,
+    connections: Arc<DashMap<Uuid, PendingConnection>>,
+
+    /// Failed authentication attempts on private tunnels.
+    failed_attempts: Arc<FailedAttempts>,
+
+    /// Shared tunnels by name, alive as long as a client serves them.
+    shared_tunnels: DashMap<String, Weak<Tunnel>>,
 
     /// Access port for tunneled
     control_port: u16,
 
+    /// Port for clients connecting over WebSocket
+    websocket_port: Option<u16>,
+
+    /// Reverse proxies whose forwarded headers name the WebSocket client
+    trusted_proxies: Vec<Cidr>,
+
+    /// UDP port for clients connecting over QUIC
+    quic_port: Option<u16>,
+
+    /// Certificate for QUIC, self-signed if not set
+    quic_certificate: Option<QuicCertificate>,
+
     /// Require Strawberry ID?
     require_id: bool,
 
@@ -45,6 +109,211 @@ pub struct Server {
 
     /// IP address where the tunneles will listen on
     tunnels_addr: String,
+
+    /// Pid file and privilege settings when running as a daemon
+    daemon: DaemonOptions,
+
+    /// Lifetime and idle limits of tunnels
+    limits: TunnelLimits,
+
+    /// Time limits of forwarded connections
+    connection_limits: ConnectionLimits,
+
+    /// Counters of forwarded connections
+    metrics: Arc<Metrics>,
+
+    /// Targets clients may reach through local forwarding
+    forward_acl: ForwardAcl,
+
+    /// Allow clients to compress forwarded connections
+    compression: bool,
+
+    /// Log every connection
+    verbose: bool,
+
+    /// Lifecycle events for subscribers
+    events: broadcast::Sender<ServerEvent>,
+
+    /// Sessions, live connections and bans shared with controls
+    state: Arc<ServerState>,
+}
+
+/// Settings for a new [`Server`], created with [`Server::builder`].
+pub struct ServerBuilder {
+    port_range: RangeInclusive<u16>,
+    secret: Option<String>,
+    control_port: u16,
+    websocket_port: Option<u16>,
+    trusted_proxies: Vec<Cidr>,
+    quic_port: Option<u16>,
+    quic_certificate: Option<QuicCertificate>,
+    require_id: bool,
+    whitelist_static_port: Vec<String>,
+    tunnels_addr: String,
+    daemon: DaemonOptions,
+    limits: TunnelLimits,
+    connection_limits: ConnectionLimits,
+    forward_acl: ForwardAcl,
+    compression: bool,
+    banned: Vec<IpAddr>,
+    verbose: bool,
+}
+
+impl ServerBuilder {
+    /// Secret clients have to know.
+    #[must_use]
+    pub fn secret(mut self, secret: Option<&str>) -> Self {
+        self.secret = secret.map(ToString::to_string);
+        self
+    }
+
+    /// Port clients connect to, 0 for any free port.
+    #[must_use]
+    pub const fn control_port(mut self, control_port: u16) -> Self {
+        self.control_port = control_port;
+        self
+    }
+
+    /// Port for clients connecting over WebSocket, `None` to only accept plain TCP.
+    #[must_use]
+    pub const fn websocket_port(mut self, websocket_port: Option<u16>) -> Self {
+        self.websocket_port = websocket_port;
+        self
+    }
+
+    /// Reverse proxies in front of the WebSocket port. For clients connecting
+    /// through them, the `Forwarded` or `X-Forwarded-For` header tells their
+    /// address, e.g. for bans.
+    #[must_use]
+    pub fn trusted_proxies(mut self, trusted_proxies: Vec<Cidr>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// UDP port for clients connecting over QUIC, `None` to disable QUIC.
+    #[must_use]
+    pub const fn quic_port(mut self, quic_port: Option<u16>) -> Self {
+        self.quic_port = quic_port;
+        self
+    }
+
+    /// Certificate for QUIC, a self-signed one is generated if not set.
+    #[must_use]
+    pub fn quic_certificate(mut self, quic_certificate: Option<QuicCertificate>) -> Self {
+        self.quic_certificate = quic_certificate;
+        self
+    }
+
+    /// Only accept clients with a valid Strawberry ID.
+    #[must_use]
+    pub const fn require_id(mut self, require_id: bool) -> Self {
+        self.require_id = require_id;
+        self
+    }
+
+    /// E-mail addresses of Strawberry ID users allowed to request static ports.
+    #[must_use]
+    pub fn whitelist_static_port(mut self, whitelist: Vec<String>) -> Self {
+        self.whitelist_static_port = whitelist;
+        self
+    }
+
+    /// IP address tunnels listen on.
+    #[must_use]
+    pub fn tunnels_addr(mut self, tunnels_addr: impl Into<String>) -> Self {
+        self.tunnels_addr = tunnels_addr.into();
+        self
+    }
+
+    /// Pid file and privilege settings when running as a daemon.
+    #[must_use]
+    pub fn daemon(mut self, daemon: DaemonOptions) -> Self {
+        self.daemon = daemon;
+        self
+    }
+
+    /// Lifetime and idle limits of tunnels.
+    #[must_use]
+    pub const fn limits(mut self, limits: TunnelLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Time limits of forwarded connections.
+    #[must_use]
+    pub const fn connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.connection_limits = connection_limits;
+        self
+    }
+
+    /// Targets clients may reach through local forwarding.
+    #[must_use]
+    pub fn forward_acl(mut self, forward_acl: ForwardAcl) -> Self {
+        self.forward_acl = forward_acl;
+        self
+    }
+
+    /// Allow clients to compress forwarded connections, enabled by default.
+    #[must_use]
+    pub const fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Addresses that may not connect as clients.
+    #[must_use]
+    pub fn banned(mut self, banned: Vec<IpAddr>) -> Self {
+        self.banned = banned;
+        self
+    }
+
+    /// Log every connection.
+    #[must_use]
+    pub const fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Create the server, without binding any port yet.
+    pub fn build(self) -> Result<Server, ServerError> {
+        if self.port_range.is_empty() {
+            return Err(ServerError::EmptyPortRange);
+        }
+        Ok(Server {
+            port_range: self.port_range,
+            connections: Arc::new(DashMap::new()),
+            failed_attempts: Arc::new(FailedAttempts::default()),
+            shared_tunnels: DashMap::new(),
+            auth: self.secret.as_deref().map(Authenticator::new),
+            control_port: self.control_port,
+            websocket_port: self.websocket_port,
+            trusted_proxies: self.trusted_proxies,
+            quic_port: self.quic_port,
+            quic_certificate: self.quic_certificate,
+            require_id: self.require_id,
+            whitelist_static_port: self.whitelist_static_port,
+            tunnels_addr: self.tunnels_addr,
+            daemon: self.daemon,
+            limits: self.limits,
+            connection_limits: self.connection_limits,
+            metrics: Arc::new(Metrics::default()),
+            forward_acl: self.forward_acl,
+            compression: self.compression,
+            verbose: self.verbose,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            state: Arc::new(ServerState::new(self.banned)),
+        })
+    }
+}
+
+/// Limits for how long tunnels are kept open.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TunnelLimits {
+    /// Maximum lifetime of a tunnel, regardless of what the client requests.
+    pub max_lifetime: Option<Duration>,
+
+    /// Time without external connections after which a tunnel is closed.
+    pub idle_timeout: Option<Duration>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -55,8 +324,19 @@ pub struct ServerHostConfig {
     pub max_port: u16,
     #[serde(rename = "control-port")]
     pub control_port: Option<u16>,
+    #[serde(rename = "websocket-port")]
+    pub websocket_port: Option<u16>,
+    #[serde(rename = "trusted-proxies", default)]
+    pub trusted_proxies: Vec<Cidr>,
+    #[serde(rename = "quic-port")]
+    pub quic_port: Option<u16>,
+    #[serde(rename = "quic-cert")]
+    pub quic_cert: Option<String>,
+    #[serde(rename = "quic-key")]
+    pub quic_key: Option<String>,
     #[serde(rename = "tunnels-addr")]
     pub tunnels_addr: Option<String>,
+    pub compression: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -74,11 +354,38 @@ pub struct ServerSecurityConfig {
     pub ip_blacklist: Option<Vec<String>>,
 }
 
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ServerLimitsConfig {
+    #[serde(rename = "max-lifetime", default, deserialize_with = "deserialize_duration")]
+    pub max_lifetime: Option<Duration>,
+    #[serde(rename = "idle-timeout", default, deserialize_with = "deserialize_duration")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(flatten)]
+    pub connection: ConnectionLimits,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ServerDaemonConfig {
+    #[serde(rename = "pid-file")]
+    pub pid_file: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ServerForwardConfig {
+    #[serde(default)]
+    pub allow: ForwardAcl,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub host: ServerHostConfig,
     pub auth: ServerAuthConfig,
     pub security: ServerSecurityConfig,
+    pub daemon: Option<ServerDaemonConfig>,
+    pub limits: Option<ServerLimitsConfig>,
+    pub forward: Option<ServerForwardConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -88,8 +395,7 @@ pub struct ServerConfig {
 
 pub fn read_config_file(file_path: &str) -> Result<ServerConfig, Box<dyn std::error::Error>> {
     let Ok(mut file) = File::open(file_path) else {
-        eprintln!("{RED}{BOLD} ! {RESET} File '{CYAN}{file_path}{RESET}' not found{C_RESET}");
-        std::process::exit(1);
+        return Err(format!("File '{CYAN}{file_path}{RESET}' not found").into());
     };
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
@@ -99,87 +405,719 @@ pub fn read_config_file(file_path: &str) -> Result<ServerConfig, Box<dyn std::er
 }
 
 impl Server {
-    /// Create a new server with a specified minimum port number.
-    pub fn new(
-        port_range: RangeInclusive<u16>,
-        secret: Option<&str>,
-        control_port: u16,
-        require_id: bool,
-        whitelist: Vec<String>,
-        tunnels_addr: String,
-    ) -> Self {
-        assert!(!port_range.is_empty(), "must provide at least one port");
-        Self {
+    /// Start building a server that assigns tunnels ports from the range.
+    #[must_use]
+    pub fn builder(port_range: RangeInclusive<u16>) -> ServerBuilder {
+        ServerBuilder {
             port_range,
-            connections: Arc::new(DashMap::new()),
-            auth: secret.map(Authenticator::new),
-            control_port,
-            require_id,
-            whitelist_static_port: whitelist,
-            tunnels_addr,
+            secret: None,
+            control_port: DEFAULT_CONTROL_PORT,
+            websocket_port: None,
+            trusted_proxies: Vec::new(),
+            quic_port: None,
+            quic_certificate: None,
+            require_id: false,
+            whitelist_static_port: Vec::new(),
+            tunnels_addr: "0.0.0.0".to_string(),
+            daemon: DaemonOptions::default(),
+            limits: TunnelLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            forward_acl: ForwardAcl::default(),
+            compression: true,
+            banned: Vec::new(),
+            verbose: false,
+        }
+    }
+
+    /// Receive lifecycle events of the server from now on.
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Handle to inspect and control the server while it listens.
+    #[must_use]
+    pub fn control(&self) -> ServerControl {
+        ServerControl {
+            state: Arc::clone(&self.state),
+            metrics: Arc::clone(&self.metrics),
+            port_range: self.port_range.clone(),
+            static_port_users: self.whitelist_static_port.len(),
+        }
+    }
+
+    fn emit(&self, event: ServerEvent) {
+        // Sending only fails if nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    /// Whether a client may connect from an address, logging refused ones.
+    fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        let banned = self.state.banned.contains(&addr.ip());
+        if banned && self.verbose {
+            CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] Refused connection from banned address"));
         }
+        !banned
+    }
+
+    /// Report a client that could not open a tunnel.
+    fn handshake_failed(&self, addr: &SocketAddr, reason: impl Into<String>) {
+        self.emit(ServerEvent::HandshakeFailed {
+            client: *addr,
+            reason: reason.into(),
+        });
+    }
+
+    /// Start the server, listening for new connections until the process is
+    /// asked to shut down.
+    pub async fn listen(self) -> Result<(), ServerError> {
+        self.listen_until(daemon::shutdown_signal()).await
     }
 
-    /// Start the server, listening for new connections.
-    pub async fn listen(self) -> Result<()> {
+    /// Start the server, listening for new connections until `shutdown` completes.
+    pub async fn listen_until(self, shutdown: impl Future<Output = ()>) -> Result<(), ServerError> {
         SERVER_LOG.ok(format!("Starting Tunneled server v{}", *VERSION));
 
+        let pid_file = self
+            .daemon
+            .pid_file
+            .as_deref()
+            .map(PidFile::create)
+            .transpose()?;
+
         let this = Arc::new(self);
-        let addr = SocketAddr::from(([0, 0, 0, 0], this.control_port));
-        let listener = TcpListener::bind(&addr).await?;
+        let listener = if let Some(listener) = daemon::listen_fds()?.into_iter().next() {
+            SERVER_LOG.info("Using control socket passed by the service manager");
+            TcpListener::from_std(listener)?
+        } else {
+            TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], this.control_port)))
+                .await
+                .map_err(|source| ServerError::Bind {
+                    port: this.control_port,
+                    source,
+                })?
+        };
+        let addr = listener.local_addr()?;
+
+        let websocket = match this.websocket_port {
+            Some(port) => Some(
+                TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+                    .await
+                    .map_err(|source| ServerError::Bind { port, source })?,
+            ),
+            None => None,
+        };
+        let quic = this
+            .quic_port
+            .map(|port| QuicListener::bind(port, this.quic_certificate.as_ref()))
+            .transpose()
+            .map_err(ServerError::Quic)?;
+
+        let owned = pid_file.iter().map(PidFile::path).collect::<Vec<_>>();
+        daemon::drop_privileges(this.daemon.user.as_deref(), this.daemon.group.as_deref(), &owned)?;
+        if let Some(user) = this.daemon.user.as_deref().or(this.daemon.group.as_deref()) {
+            SERVER_LOG.info(format!("Dropped privileges to {MAGENTA}{user}{C_RESET}"));
+        }
 
         SERVER_LOG.info(format!("Server is listening on {MAGENTA}{addr}{C_RESET}"));
+        if let Some(websocket) = &websocket {
+            SERVER_LOG.info(format!(
+                "Accepting WebSocket clients on {MAGENTA}{}{C_RESET}",
+                websocket.local_addr()?
+            ));
+        }
+        if let Some(quic) = &quic {
+            SERVER_LOG.info(format!(
+                "Accepting QUIC clients on {MAGENTA}{}{C_RESET} (UDP)",
+                quic.local_addr()?
+            ));
+            SERVER_LOG.info(format!(
+                "QUIC certificate fingerprint{}: {MAGENTA}{}{C_RESET}",
+                if quic.is_self_signed() { " (self-signed)" } else { "" },
+                quic.fingerprint()
+            ));
+            if quic.is_self_signed() {
+                SERVER_LOG.info("Clients have to pin it with quic://host:port?fingerprint=...");
+            }
+        }
+        this.log_settings();
+
+        if let Err(err) = daemon::notify(&format!("READY=1\nSTATUS=Listening on {addr}")) {
+            SERVER_LOG.warning(format!("Failed to notify service manager: {err}"));
+        }
+
+        // Pinged from the accept loop, so the service manager notices when it hangs.
+        let mut watchdog = daemon::watchdog_interval().map(tokio::time::interval);
+
+        this.emit(ServerEvent::Listening { addr });
+
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    let (stream, addr) = result?;
+                    tokio::spawn(
+                        Arc::clone(&this)
+                            .serve_client(Box::new(stream), addr, Transport::Tcp)
+                            .instrument(info_span!("control", ?addr)),
+                    );
+                }
+                result = accept_optional(websocket.as_ref()) => {
+                    let (stream, addr) = result?;
+                    tokio::spawn(
+                        Arc::clone(&this)
+                            .serve_websocket(stream, addr)
+                            .instrument(info_span!("control", ?addr)),
+                    );
+                }
+                Some(incoming) = accept_quic(quic.as_ref()) => {
+                    tokio::spawn(Arc::clone(&this).serve_quic(incoming));
+                }
+                _ = tick(watchdog.as_mut()) => {
+                    let _ = daemon::notify("WATCHDOG=1");
+                }
+                () = &mut shutdown => break,
+            }
+        }
+
+        SERVER_LOG.info("Shutting down");
+        let _ = daemon::notify("STOPPING=1");
+        Ok(())
+    }
+
+    /// Serve a connection of a client, after its transport was set up.
+    async fn serve_client(self: Arc<Self>, stream: TransportStream, addr: SocketAddr, transport: Transport) {
+        if !self.is_allowed(&addr) {
+            return;
+        }
+        if self.verbose {
+            CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] Incoming {transport} connection"));
+        }
+
+        if let Err(err) = self.handle_connection(stream, &addr, transport).await {
+            CLIENT_LOG.warning(format!(
+                "[{MAGENTA}{addr}{RESET}] Connection exited with error {err}"
+            ));
+        } else if self.verbose {
+            CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] Connection exited"));
+        }
+    }
+
+    /// Complete the WebSocket handshake of a client, then serve it.
+    async fn serve_websocket(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) {
+        // Behind a proxy, the client is only known after the handshake.
+        let proxied = self.trusted_proxies.iter().any(|proxy| proxy.contains(addr.ip()));
+        if !proxied && !self.is_allowed(&addr) {
+            return;
+        }
+        let trusted_proxies = if proxied { self.trusted_proxies.as_slice() } else { &[] };
+        match transport::accept(stream, trusted_proxies).await {
+            Ok((stream, client)) => {
+                let client = client.map_or(addr, |ip| SocketAddr::new(ip, addr.port()));
+                self.serve_client(stream, client, Transport::WebSocket).await;
+            }
+            Err(err) => {
+                CLIENT_LOG.warning(format!("[{MAGENTA}{addr}{RESET}] {err:#}"));
+                self.handshake_failed(&addr, format!("{err:#}"));
+            }
+        }
+    }
+
+    /// Serve every stream of a QUIC connection as a connection of its own.
+    async fn serve_quic(self: Arc<Self>, incoming: quinn::Incoming) {
+        let addr = incoming.remote_address();
+        if !self.is_allowed(&addr) {
+            incoming.refuse();
+            return;
+        }
+        let connection = match incoming.await {
+            Ok(connection) => connection,
+            Err(err) => {
+                CLIENT_LOG.warning(format!("QUIC handshake failed: {err}"));
+                self.handshake_failed(&addr, format!("QUIC handshake failed: {err}"));
+                return;
+            }
+        };
+
+        while let Some(stream) = QuicStream::accept(&connection).await {
+            // The address changes when the connection migrates.
+            let addr = connection.remote_address();
+            let this = Arc::clone(&self);
+            tokio::spawn(
+                async move {
+                    match stream.preface().await {
+                        Ok(stream) => this.serve_client(stream, addr, Transport::Quic).await,
+                        Err(err) => {
+                            CLIENT_LOG.warning(format!("[{MAGENTA}{addr}{RESET}] {err:#}"));
+                            this.handshake_failed(&addr, format!("{err:#}"));
+                        }
+                    }
+                }
+                .instrument(info_span!("control", ?addr)),
+            );
+        }
+    }
+
+    /// Log the settings of the server on startup.
+    fn log_settings(&self) {
         SERVER_LOG.info(format!(
             "Port range: {MAGENTA}{}-{}{C_RESET}",
-            this.port_range.start(),
-            this.port_range.end()
+            self.port_range.start(),
+            self.port_range.end()
         ));
         SERVER_LOG.info(format!(
             "Tunneling address: {MAGENTA}{}{C_RESET}",
-            this.tunnels_addr
+            self.tunnels_addr
         ));
-
-        if OPTIONS.server_options.verbose_logging {
+        if let Some(max_lifetime) = self.limits.max_lifetime {
+            SERVER_LOG.info(format!(
+                "Maximum tunnel lifetime: {MAGENTA}{}{C_RESET}",
+                format_duration(max_lifetime)
+            ));
+        }
+        if let Some(idle_timeout) = self.limits.idle_timeout {
+            SERVER_LOG.info(format!(
+                "Tunnel idle timeout: {MAGENTA}{}{C_RESET}",
+                format_duration(idle_timeout)
+            ));
+        }
+        if let Some(idle_timeout) = self.connection_limits.idle_timeout {
+            SERVER_LOG.info(format!(
+                "Connection idle timeout: {MAGENTA}{}{C_RESET}",
+                format_duration(idle_timeout)
+            ));
+        }
+        if let Some(write_timeout) = self.connection_limits.write_timeout {
+            SERVER_LOG.info(format!(
+                "Connection write timeout: {MAGENTA}{}{C_RESET}",
+                format_duration(write_timeout)
+            ));
+        }
+        if let Some(max_duration) = self.connection_limits.max_duration {
             SERVER_LOG.info(format!(
-                "Port range: {MAGENTA}{}-{}{C_RESET}",
-                this.port_range.start(),
-                this.port_range.end()
+                "Maximum connection duration: {MAGENTA}{}{C_RESET}",
+                format_duration(max_duration)
             ));
+        }
+
+        if self.verbose {
             SERVER_LOG.info(format!(
                 "Control port: {MAGENTA}{}{C_RESET}",
-                this.control_port
+                self.control_port
+            ));
+            if let Some(websocket_port) = self.websocket_port {
+                SERVER_LOG.info(format!("WebSocket port: {MAGENTA}{websocket_port}{C_RESET}"));
+            }
+            if let Some(quic_port) = self.quic_port {
+                SERVER_LOG.info(format!("QUIC port: {MAGENTA}{quic_port}{C_RESET}"));
+            }
+        }
+
+        if self.websocket_port.is_some() && !self.trusted_proxies.is_empty() {
+            let proxies = self.trusted_proxies.iter().map(ToString::to_string).collect::<Vec<_>>();
+            SERVER_LOG.info(format!(
+                "Trusting client addresses forwarded by: {MAGENTA}{}{C_RESET}",
+                proxies.join(", ")
             ));
         }
 
-        if this.require_id {
+        if !self.forward_acl.rules().is_empty() {
+            let rules = self.forward_acl.rules().iter().map(ToString::to_string).collect::<Vec<_>>();
+            SERVER_LOG.info(format!(
+                "Local forwarding allowed to: {MAGENTA}{}{C_RESET}",
+                rules.join(", ")
+            ));
+        }
+
+        if self.require_id {
             SERVER_LOG.info(format!(
                 "Using Strawberry ID Authentication ({STRAWBERRY_ID_API})"
             ));
-        } else if this.auth.is_some() {
+        } else if self.auth.is_some() {
             SERVER_LOG.info("Using secret authentication");
         } else {
             SERVER_LOG.info("No authentication");
         }
+    }
+
+    /// Tell the client that its tunnel expired, closing the control connection.
+    async fn expire(
+        stream: &mut Delimited<TransportStream>,
+        addr: &SocketAddr,
+        port: u16,
+        message: String,
+    ) -> Result<()> {
+        CLIENT_LOG.info(format!(
+            "[{MAGENTA}{addr}{C_RESET}] Closing tunnel on port {port}: {message}"
+        ));
+        stream.send(ServerMessage::Expired(message)).await?;
+        Ok(())
+    }
+
+    /// Turn away an external connection while the local service is down.
+    async fn reject_unhealthy(mut stream: TransportStream, protocol: TunnelProtocol) {
+        if protocol == TunnelProtocol::Http {
+            let response = http_response(
+                "503 Service Unavailable",
+                "Retry-After: 10\r\n",
+                "The service behind this tunnel is currently unavailable.\n",
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+        let _ = stream.shutdown().await;
+    }
+
+    /// Store an external connection until a client accepts it.
+    fn insert_connection(&self, connection: PendingConnection) -> Uuid {
+        let id = Uuid::new_v4();
+        let connections = Arc::clone(&self.connections);
+
+        connections.insert(id, connection);
+        tokio::spawn(async move {
+            // Remove stale entries to avoid memory leaks.
+            sleep(Duration::from_secs(10)).await;
+            if connections.remove(&id).is_some() {
+                CLIENT_LOG.warning(format!("Removed stale connection ({id})"));
+            }
+        });
+        id
+    }
+
+    /// Open a new tunnel for a client, or join the shared tunnel of the same name.
+    ///
+    /// Returns the tunnel and whether it already existed.
+    async fn open_tunnel(
+        &self,
+        port: u16,
+        static_port: Option<u16>,
+        id: Option<&ClientAuthentication>,
+        options: &TunnelOptions,
+        gate: Option<Arc<TunnelGate>>,
+    ) -> Result<(Arc<Tunnel>, bool), &'static str> {
+        let identity = TunnelIdentity {
+            password: options.password.clone(),
+            protocol: options.protocol,
+            owner: id.map(|id| id.strawberry_id.username.clone()),
+            sources: options.sources.clone(),
+        };
+
+        // Without credentials anybody could join and take a share of the visitors.
+        if options.share.is_some() && identity.password.is_none() && identity.owner.is_none() {
+            return Err("Sharing a tunnel needs a password or a Strawberry ID");
+        }
+
+        if let Some(name) = &options.share
+            && let Some(tunnel) = self.shared_tunnels.get(name).and_then(|tunnel| tunnel.upgrade())
+        {
+            Self::check_shared(&tunnel, &identity, port, static_port)?;
+            return Ok((tunnel, true));
+        }
+
+        let listener = self.create_listener(port, static_port, id).await?;
+        let addr = listener.local_addr().map_err(|_| "Failed to bind to port")?;
+        let tunnel = Arc::new(Tunnel::new(options.share.clone(), addr, identity, gate));
+
+        if let Some(name) = &options.share {
+            self.shared_tunnels.retain(|_, tunnel| tunnel.strong_count() > 0);
+            match self.shared_tunnels.entry(name.clone()) {
+                Entry::Occupied(entry) if let Some(existing) = entry.get().upgrade() => {
+                    // Another client registered the name while we were binding.
+                    drop(entry);
+                    Self::check_shared(&existing, &tunnel.identity, port, static_port)?;
+                    return Ok((existing, true));
+                }
+                Entry::Occupied(mut entry) => {
+                    entry.insert(Arc::downgrade(&tunnel));
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(Arc::downgrade(&tunnel));
+                }
+            }
+        }
+
+        let acceptor = tokio::spawn(Self::accept_connections(listener, Arc::downgrade(&tunnel), self.verbose));
+        tunnel.set_acceptor(acceptor.abort_handle());
+        Ok((tunnel, false))
+    }
+
+    /// Check that a client may join an existing shared tunnel.
+    fn check_shared(
+        tunnel: &Tunnel,
+        identity: &TunnelIdentity,
+        port: u16,
+        static_port: Option<u16>,
+    ) -> Result<(), &'static str> {
+        if tunnel.identity != *identity {
+            return Err("Shared tunnel is registered with different credentials or sources");
+        }
+        let requested = static_port.or_else(|| (port > 0).then_some(port));
+        if requested.is_some_and(|port| port != tunnel.addr.port()) {
+            return Err("Shared tunnel is running on a different port");
+        }
+        Ok(())
+    }
 
+    /// Accept external connections of a tunnel and hand them to its clients.
+    async fn accept_connections(listener: TcpListener, tunnel: Weak<Tunnel>, verbose: bool) {
         loop {
-            let (stream, addr) = listener.accept().await?;
-            let this = Arc::clone(&this);
-            tokio::spawn(
-                async move {
-                    if OPTIONS.server_options.verbose_logging {
-                        CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] Incoming connection"));
-                    }
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    CLIENT_LOG.warning(format!("Failed to accept external connection: {err}"));
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let Some(tunnel) = tunnel.upgrade() else {
+                return;
+            };
+            Self::admit(&tunnel, Box::new(stream), addr, verbose);
+        }
+    }
+
+    /// Let a visitor into a tunnel, authenticating it first if the tunnel is private.
+    fn admit(tunnel: &Arc<Tunnel>, stream: TransportStream, addr: SocketAddr, verbose: bool) {
+        let port = tunnel.addr.port();
+        if !tunnel.identity.sources.allows(addr.ip()) {
+            if verbose {
+                CLIENT_LOG.info(format!("Dropped connection from {addr}, source not allowed on port {port}"));
+            }
+            return;
+        }
+
+        if verbose {
+            CLIENT_LOG.info(format!("External connection at {addr}:{port}"));
+        }
 
-                    if let Err(err) = this.handle_connection(stream, &addr).await {
-                        CLIENT_LOG.warning(format!(
-                            "[{MAGENTA}{addr}{RESET}] Connection exited with error {err}"
-                        ));
-                    } else if OPTIONS.server_options.verbose_logging {
-                        CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] Connection exited"));
+        if !tunnel.is_available() {
+            if verbose {
+                CLIENT_LOG.info(format!("Rejected connection from {addr}, local service of port {port} is down"));
+            }
+            tokio::spawn(Self::reject_unhealthy(stream, tunnel.identity.protocol));
+            return;
+        }
+
+        let Some(gate) = &tunnel.gate else {
+            Self::dispatch(tunnel, stream, addr, Vec::new());
+            return;
+        };
+
+        let gate = Arc::clone(gate);
+        let tunnel = Arc::downgrade(tunnel);
+        tokio::spawn(async move {
+            let mut stream = stream;
+            match gate.authorize(&mut stream, addr.ip()).await {
+                Ok(Verdict::Granted(buffer)) => {
+                    if let Some(tunnel) = tunnel.upgrade() {
+                        Self::dispatch(&tunnel, stream, addr, buffer);
                     }
                 }
-                .instrument(info_span!("control", ?addr)),
-            );
+                Ok(Verdict::Challenged) => (),
+                Ok(Verdict::Denied(reason)) => CLIENT_LOG.warning(format!(
+                    "[{MAGENTA}{addr}{RESET}] {YELLOW}{BOLD}<!>{C_RESET} Access to private tunnel on port {port} denied ({reason})"
+                )),
+                Err(err) => CLIENT_LOG.warning(format!(
+                    "[{MAGENTA}{addr}{RESET}] Authentication for private tunnel on port {port} failed: {err}"
+                )),
+            }
+        });
+    }
+
+    /// Verify the Strawberry ID of a client, answering with an error if it is
+    /// missing or invalid. Returns none if the client was turned away.
+    async fn verify_id(
+        &self,
+        stream: &mut Delimited<TransportStream>,
+        addr: &SocketAddr,
+        id: Option<StrawberryIdAuthenticator>,
+    ) -> Result<Option<ClientAuthentication>> {
+        let Some(mut id) = id else {
+            CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] {YELLOW}{BOLD}<!>{C_RESET} Invalid Strawberry ID Auth (Client connected without Strawberry ID)"));
+            self.handshake_failed(addr, "Missing Strawberry ID");
+
+            stream.send(ServerMessage::Error(
+                "This server requires a Strawberry ID which you didn't provide. \
+                Please add the --auth Flag (and if not already done, log in with your Strawberry ID with tunneled auth)".to_string()
+            )).await?;
+
+            return Ok(None);
+        };
+        let (username, token) = id.clone().unwrap();
+
+        if self.verbose {
+            CLIENT_LOG.info(format!(
+                "[{MAGENTA}{addr}{RESET}] Received Strawberry ID Auth (@{username})"
+            ));
+        }
+
+        let auth = id.verify(&username, &token).await?;
+
+        if let Some(auth) = &auth {
+            CLIENT_LOG.info(format!(
+                "[{MAGENTA}{addr}{RESET}] Authentication successful ({GREEN}{}{C_RESET} ({ITALIC}{CYAN}@{}{C_RESET}))",
+                auth.strawberry_id.full_name, auth.strawberry_id.username
+            ));
+        } else {
+            CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] {YELLOW}{BOLD}<!>{C_RESET} Invalid Strawberry ID Auth (@{username})"));
+            self.handshake_failed(addr, format!("Invalid Strawberry ID (@{username})"));
+            stream
+                .send(ServerMessage::Error("Invalid Strawberry ID".to_string()))
+                .await?;
+        }
+        Ok(auth)
+    }
+
+    /// Connect a local forwarding client to a target reachable from the server.
+    async fn handle_forward(
+        &self,
+        mut stream: Delimited<TransportStream>,
+        addr: &SocketAddr,
+        target: ForwardTarget,
+        id: Option<StrawberryIdAuthenticator>,
+    ) -> Result<()> {
+        if self.require_id && self.verify_id(&mut stream, addr, id).await?.is_none() {
+            return Ok(());
+        }
+        if !self.forward_acl.allows(&target) {
+            CLIENT_LOG.warning(format!(
+                "[{MAGENTA}{addr}{RESET}] {YELLOW}{BOLD}<!>{C_RESET} Forwarding to {target} denied"
+            ));
+            stream
+                .send(ServerMessage::Error(format!("Forwarding to {target} is not allowed")))
+                .await?;
+            return Ok(());
+        }
+
+        if self.verbose {
+            CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] Forwarding to {target}"));
+        }
+
+        match target {
+            ForwardTarget::Tunnel(name) => {
+                let Some(tunnel) = self.shared_tunnels.get(&name).and_then(|tunnel| tunnel.upgrade()) else {
+                    stream
+                        .send(ServerMessage::Error(format!("No tunnel named '{name}'")))
+                        .await?;
+                    return Ok(());
+                };
+                stream.send(ServerMessage::Forwarding).await?;
+
+                // The client waits for the confirmation, so nothing was buffered yet.
+                let parts = stream.into_parts();
+                debug_assert!(parts.read_buf.is_empty(), "Framed read buffer not empty");
+                Self::admit(&tunnel, parts.io, *addr, self.verbose);
+            }
+            ForwardTarget::Address(host, port) => {
+                let mut target_stream = match connect_with_timeout(&host, port).await {
+                    Ok(target_stream) => target_stream,
+                    Err(err) => {
+                        stream.send(ServerMessage::Error(err.to_string())).await?;
+                        return Ok(());
+                    }
+                };
+                stream.send(ServerMessage::Forwarding).await?;
+
+                let mut parts = stream.into_parts();
+                target_stream.write_all(&parts.read_buf).await?;
+
+                self.metrics.opened();
+                let result = forward(&mut target_stream, &mut parts.io, &self.connection_limits).await;
+                self.metrics.closed(result.as_ref().ok());
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward an external connection through the stream a client accepted it with.
+    async fn accept_connection(
+        &self,
+        stream: Delimited<TransportStream>,
+        id: Uuid,
+        connection: PendingConnection,
+    ) -> Result<()> {
+        let PendingConnection { stream: mut stream2, peer, buffer, port, activity, compression } = connection;
+        let _active = ActiveConnection::new(activity);
+        let live = self.state.open(id, port, peer);
+        let mut parts = stream.into_parts();
+        debug_assert!(parts.write_buf.is_empty(), "Framed write buffer not empty");
+
+        self.emit(ServerEvent::ConnectionOpened {
+            id,
+            port,
+            peer: Some(peer),
+        });
+        self.metrics.opened();
+        let result = if let Some(compression) = compression {
+            let mut client = CompressedStream::new(parts.io, &parts.read_buf, compression);
+            async {
+                client.write_all(&buffer).await?;
+                client.flush().await?;
+                forward_tracked(&mut client, &mut stream2, &self.connection_limits, &live.transfer).await
+            }
+            .await
+            .map(|forwarded| Forwarded {
+                compressed: Some(CompressedBytes {
+                    sent: client.wire_read(),
+                    received: client.wire_written(),
+                }),
+                ..forwarded
+            })
+        } else {
+            async {
+                stream2.write_all(&parts.read_buf).await?;
+                parts.io.write_all(&buffer).await?;
+                forward_tracked(&mut parts.io, &mut stream2, &self.connection_limits, &live.transfer).await
+            }
+            .await
+        };
+        self.state.connections.remove(&id);
+        self.metrics.closed(result.as_ref().ok());
+        self.emit(ServerEvent::ConnectionClosed {
+            id,
+            port,
+            result: result.as_ref().map_or_else(|err| Err(err.to_string()), |forwarded| Ok(*forwarded)),
+        });
+
+        let forwarded = result?;
+        if forwarded.outcome.is_expired() {
+            CLIENT_LOG.info(format!(
+                "Closed connection ({id}) after {}, {} connections expired so far",
+                forwarded.outcome.reason(),
+                self.metrics.snapshot().expired()
+            ));
+        }
+        if self.verbose
+            && let Some(ratio) = forwarded.compression_ratio()
+        {
+            CLIENT_LOG.info(format!(
+                "Connection ({id}) compressed {} bytes to {} ({ratio:.1}x), {:.1}x overall",
+                forwarded.sent + forwarded.received,
+                forwarded.compressed.map_or(0, |compressed| compressed.sent + compressed.received),
+                self.metrics.snapshot().compression_ratio().unwrap_or(1.0)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Hand an external connection to a client of the tunnel, or turn it away.
+    fn dispatch(tunnel: &Tunnel, stream: TransportStream, peer: SocketAddr, buffer: Vec<u8>) {
+        let connection = PendingConnection {
+            stream,
+            peer,
+            buffer,
+            port: tunnel.addr.port(),
+            activity: Arc::clone(&tunnel.activity),
+            compression: None,
+        };
+        // Only visitors handed to a client count as activity, so rejected ones
+        // can't keep the tunnel from expiring.
+        match tunnel.dispatch(connection) {
+            Ok(()) => tunnel.activity.touch(),
+            Err(connection) => {
+                tokio::spawn(Self::reject_unhealthy(connection.stream, tunnel.identity.protocol));
+            }
         }
     }
 
@@ -245,138 +1183,271 @@ impl Server {
     }
 
     #[allow(clippy::too_many_lines)]
-    async fn handle_connection(&self, stream: TcpStream, addr: &SocketAddr) -> Result<()> {
+    async fn handle_connection(&self, stream: TransportStream, addr: &SocketAddr, transport: Transport) -> Result<()> {
         let mut stream = Delimited::new(stream);
         if let Some(auth) = &self.auth
             && let Err(err) = auth.server_handshake(&mut stream).await
         {
             SERVER_LOG.warning("Server handshake failed".to_string());
+            self.handshake_failed(addr, format!("Secret handshake failed - {err}"));
             stream
                 .send(ServerMessage::Error(format!("Handshake failed - {err}")))
                 .await?;
             return Ok(());
         }
 
-        match stream.recv_timeout().await? {
+        let (port, id, static_port, options) = match stream.recv_timeout().await? {
+            Some(ClientMessage::Hello(port, id, static_port)) => (port, id, static_port, TunnelOptions::default()),
+            Some(ClientMessage::HelloWithOptions(port, id, static_port, options)) => (port, id, static_port, options),
             Some(ClientMessage::Authenticate(_)) => {
                 SERVER_LOG.warning("Unexpected authenticate");
-                Ok(())
+                return Ok(());
             }
-            Some(ClientMessage::Hello(port, id, static_port)) => {
-                let strawberry_id = if self.require_id {
-                    if let Some(mut id) = id.clone() {
-                        let (username, token) = id.clone().unwrap();
+            Some(ClientMessage::Health(_)) => {
+                SERVER_LOG.warning("Unexpected health report");
+                return Ok(());
+            }
+            Some(ClientMessage::Ping(_)) => {
+                SERVER_LOG.warning("Unexpected ping");
+                return Ok(());
+            }
+            Some(ClientMessage::Forward(target, id)) => return self.handle_forward(stream, addr, target, id).await,
+            Some(ClientMessage::Accept(id)) => {
+                if self.verbose {
+                    SERVER_LOG.info(format!("Forwarding connection {id}"));
+                }
 
-                        if OPTIONS.server_options.verbose_logging {
-                            CLIENT_LOG.info(format!(
-                                "[{MAGENTA}{addr}{RESET}] Received Strawberry ID Auth (@{username})"
-                            ));
-                        }
+                match self.connections.remove(&id) {
+                    Some((_, connection)) => self.accept_connection(stream, id, connection).await?,
+                    None => SERVER_LOG.warning(format!("Missing connection ({id})")),
+                }
+                return Ok(());
+            }
+            None => {
+                SERVER_LOG.warning("Client sent empty response");
+                return Ok(());
+            }
+        };
 
-                        let auth = id.verify(&username, &token).await?;
+        let strawberry_id = if self.require_id {
+            let Some(auth) = self.verify_id(&mut stream, addr, id).await? else {
+                return Ok(());
+            };
+            Some(auth)
+        } else {
+            None
+        };
 
-                        if let Some(auth) = auth.clone() {
-                            CLIENT_LOG.info(format!(
-                                "[{MAGENTA}{addr}{RESET}] Authentication successful ({GREEN}{}{C_RESET} ({ITALIC}{CYAN}@{}{C_RESET}))",
-                                auth.strawberry_id.full_name, auth.strawberry_id.username
-                            ));
-                        } else {
-                            CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] {YELLOW}{BOLD}<!>{C_RESET} Invalid Strawberry ID Auth (@{username})"));
-                            stream
-                                .send(ServerMessage::Error("Invalid Strawberry ID".to_string()))
-                                .await?;
+        let gate = if let Some(hash) = options.password.as_deref() {
+            let Ok(gate) = TunnelGate::new(hash, options.protocol, Arc::clone(&self.failed_attempts)) else {
+                self.handshake_failed(addr, "Invalid tunnel password");
+                stream
+                    .send(ServerMessage::Error("Invalid tunnel password".to_string()))
+                    .await?;
+                return Ok(());
+            };
+            Some(Arc::new(gate))
+        } else {
+            None
+        };
 
-                            return Ok(());
-                        }
+        let (tunnel, joined) = match self
+            .open_tunnel(port, static_port, strawberry_id.as_ref(), &options, gate)
+            .await
+        {
+            Ok(tunnel) => tunnel,
+            Err(err) => {
+                self.handshake_failed(addr, err);
+                stream.send(ServerMessage::Error(err.into())).await?;
+                return Ok(());
+            }
+        };
 
-                        auth
-                    } else {
-                        CLIENT_LOG.info(format!("[{MAGENTA}{addr}{RESET}] {YELLOW}{BOLD}<!>{C_RESET} Invalid Strawberry ID Auth (Client connected without Strawberry ID)"));
+        let mut membership = tunnel.join(options.weight.unwrap_or(1));
+        let port = tunnel.addr.port();
 
-                        stream.send(ServerMessage::Error(
-                            "This server requires a Strawberry ID which you didn't provide. \
-                            Please add the --auth Flag (and if not already done, log in with your Strawberry ID with tunneled auth)".to_string()
-                        )).await?;
+        if joined {
+            CLIENT_LOG.info(format!(
+                "[{MAGENTA}{addr}{C_RESET}] Joined shared tunnel '{CYAN}{}{C_RESET}' on port {port}, {} clients serving it",
+                tunnel.name.as_deref().unwrap_or_default(),
+                tunnel.members()
+            ));
+        } else {
+            CLIENT_LOG.info(format!(
+                "[{MAGENTA}{}{C_RESET}] Created tunneling rule for {BLUE}{BOLD}{}{C_RESET}->{MAGENTA}{BOLD}{}:{port}{C_RESET}",
+                addr, addr.ip(), tunnel.addr.ip()
+            ));
+            if let Some(name) = &tunnel.name {
+                CLIENT_LOG.info(format!("[{MAGENTA}{addr}{C_RESET}] Tunnel on port {port} is shared as '{CYAN}{name}{C_RESET}'"));
+            }
+            if tunnel.identity.password.is_some() {
+                CLIENT_LOG.info(format!("[{MAGENTA}{addr}{C_RESET}] Tunnel on port {port} is private"));
+            }
+            if !tunnel.identity.sources.is_empty() {
+                CLIENT_LOG.info(format!("[{MAGENTA}{addr}{C_RESET}] Tunnel on port {port} filters visitors by source"));
+            }
+        }
 
-                        return Ok(());
-                    }
-                } else {
-                    None
-                };
+        let lifetime = match (options.ttl.map(Duration::from_secs), self.limits.max_lifetime) {
+            (Some(ttl), Some(max_lifetime)) => Some(ttl.min(max_lifetime)),
+            (ttl, max_lifetime) => ttl.or(max_lifetime),
+        };
+        if let Some(lifetime) = lifetime {
+            CLIENT_LOG.info(format!(
+                "[{MAGENTA}{addr}{C_RESET}] Tunnel on port {port} expires in {}",
+                format_duration(lifetime)
+            ));
+        }
+        let created = Instant::now();
 
-                let listener = match self
-                    .create_listener(port, static_port, strawberry_id.as_ref())
-                    .await
+        let compression = options.compression.filter(|_| self.compression);
+        if let Some(compression) = compression {
+            if self.verbose {
+                CLIENT_LOG.info(format!("[{MAGENTA}{addr}{C_RESET}] Compressing connections with {compression}"));
+            }
+            stream.send(ServerMessage::Compression(compression)).await?;
+        }
+        stream
+            .send(ServerMessage::Hello(tunnel.addr.ip().to_string(), port))
+            .await?;
+        self.emit(ServerEvent::TunnelOpened {
+            client: *addr,
+            port,
+            name: tunnel.name.clone(),
+        });
+        let (session_id, session) = self.state.open_session(Session {
+            addr: *addr,
+            transport,
+            opened: created,
+            user: strawberry_id.as_ref().map(|id| id.strawberry_id.username.clone()),
+            port,
+            name: tunnel.name.clone(),
+            disconnect: Notify::new(),
+        });
+
+        // Connections handed to the client that it has not accepted yet.
+        let mut handed = Vec::new();
+        let result = async {
+            loop {
+                const TIMEOUT: Duration = Duration::from_millis(500);
+                if let Some(lifetime) = lifetime
+                    && created.elapsed() >= lifetime
                 {
-                    Ok(listener) => listener,
-                    Err(err) => {
-                        stream.send(ServerMessage::Error(err.into())).await?;
+                    let message = format!("Tunnel expired after its maximum lifetime of {}", format_duration(lifetime));
+                    return Self::expire(&mut stream, addr, port, message).await;
+                }
+                if let Some(idle_timeout) = self.limits.idle_timeout
+                    && tunnel.activity.idle_for().is_some_and(|idle| idle >= idle_timeout)
+                {
+                    let message = format!("Tunnel expired after being idle for {}", format_duration(idle_timeout));
+                    return Self::expire(&mut stream, addr, port, message).await;
+                }
+                if stream.send(ServerMessage::Heartbeat).await.is_err() {
+                    // Assume that the TCP connection has been dropped.
+                    return Ok(());
+                }
+                tokio::select! {
+                    Some(mut connection) = membership.recv() => {
+                        connection.compression = compression;
+                        let peer = connection.peer;
+                        let id = self.insert_connection(connection);
+                        handed.retain(|id| self.connections.contains_key(id));
+                        handed.push(id);
+                        if options.peers {
+                            stream.send(ServerMessage::ConnectionFrom(id, peer)).await?;
+                        } else {
+                            stream.send(ServerMessage::Connection(id)).await?;
+                        }
+                    }
+                    message = stream.recv::<ClientMessage>() => match message? {
+                        Some(ClientMessage::Health(healthy)) => {
+                            if membership.set_healthy(healthy) {
+                                if healthy {
+                                    CLIENT_LOG.info(format!("[{MAGENTA}{addr}{C_RESET}] Local service of port {port} is {GREEN}up{C_RESET} again"));
+                                } else {
+                                    CLIENT_LOG.warning(format!("[{MAGENTA}{addr}{C_RESET}] Local service of port {port} is {RED}down{C_RESET}, rejecting connections"));
+                                }
+                            }
+                        }
+                        Some(ClientMessage::Ping(nonce)) => stream.send(ServerMessage::Pong(nonce)).await?,
+                        Some(_) => SERVER_LOG.warning("Unexpected message on control connection"),
+                        // The client closed the control connection.
+                        None => return Ok(()),
+                    },
+                    () = session.disconnect.notified() => {
+                        CLIENT_LOG.info(format!("[{MAGENTA}{addr}{C_RESET}] Disconnected from tunnel on port {port} by the operator"));
+                        stream.send(ServerMessage::Error("Disconnected by the server operator".to_string())).await?;
                         return Ok(());
                     }
-                };
+                    () = sleep(TIMEOUT) => (),
+                }
+            }
+        }
+        .await;
+        self.state.sessions.remove(&session_id);
 
-                let port = listener.local_addr()?.port();
+        // Fail over the connections the client will never accept to
+        // the remaining clients of a shared tunnel.
+        drop(membership);
+        for id in handed {
+            if let Some((_, connection)) = self.connections.remove(&id) {
+                let _ = tunnel.dispatch(connection);
+            }
+        }
 
-                CLIENT_LOG.info(format!(
-                    "[{MAGENTA}{}{C_RESET}] Created tunneling rule for {BLUE}{BOLD}{}{C_RESET}->{MAGENTA}{BOLD}{}:{port}{C_RESET}",
-                    addr, addr.ip(), listener.local_addr()?.ip()
-                ));
+        self.emit(ServerEvent::TunnelClosed { client: *addr, port });
+        result
+    }
+}
 
-                stream
-                    .send(ServerMessage::Hello(
-                        listener.local_addr()?.ip().to_string(),
-                        port,
-                    ))
-                    .await?;
+/// Accept a connection on an optional listener, never completing without one.
+async fn accept_optional(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
 
-                loop {
-                    const TIMEOUT: Duration = Duration::from_millis(500);
-                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
-                        // Assume that the TCP connection has been dropped.
-                        return Ok(());
-                    }
-                    if let Ok(result) = timeout(TIMEOUT, listener.accept()).await {
-                        let (stream2, addr) = result?;
+/// Accept a client on an optional QUIC endpoint, never completing without one.
+async fn accept_quic(listener: Option<&QuicListener>) -> Option<quinn::Incoming> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
 
-                        if OPTIONS.server_options.verbose_logging {
-                            CLIENT_LOG.info(format!("External connection at {addr}:{port}"));
-                        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::acl::ForwardRule;
+    use crate::core::shared::MAX_FRAME_LENGTH;
 
-                        let id = Uuid::new_v4();
-                        let connections = Arc::clone(&self.connections);
+    /// Send a forward request without Strawberry ID to a server and return its answer.
+    async fn forward_without_id(require_id: bool) -> Option<ServerMessage> {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = ForwardTarget::Address("127.0.0.1".to_string(), target.local_addr().unwrap().port());
+        let server = Server::builder(40000..=40100)
+            .require_id(require_id)
+            .forward_acl(ForwardAcl::new(vec![ForwardRule::parse("127.0.0.1:*").unwrap()]))
+            .build()
+            .unwrap();
 
-                        connections.insert(id, stream2);
-                        tokio::spawn(async move {
-                            // Remove stale entries to avoid memory leaks.
-                            sleep(Duration::from_secs(10)).await;
-                            if connections.remove(&id).is_some() {
-                                CLIENT_LOG.warning(format!("Removed stale connection ({id})"));
-                            }
-                        });
-                        stream.send(ServerMessage::Connection(id)).await?;
-                    }
-                }
-            }
-            Some(ClientMessage::Accept(id)) => {
-                if OPTIONS.server_options.verbose_logging {
-                    SERVER_LOG.info(format!("Forwarding connection {id}"));
-                }
+        let (client, stream) = tokio::io::duplex(MAX_FRAME_LENGTH);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+        tokio::spawn(async move { server.handle_connection(Box::new(stream), &addr, Transport::Tcp).await });
 
-                match self.connections.remove(&id) {
-                    Some((_, mut stream2)) => {
-                        let mut parts = stream.into_parts();
-                        debug_assert!(parts.write_buf.is_empty(), "Framed write buffer not empty");
-                        stream2.write_all(&parts.read_buf).await?;
-                        tokio::io::copy_bidirectional(&mut parts.io, &mut stream2).await?;
-                    }
-                    None => SERVER_LOG.warning(format!("Missing connection ({id})")),
-                }
-                Ok(())
-            }
-            None => {
-                SERVER_LOG.warning("Client sent empty response");
-                Ok(())
-            }
-        }
+        let mut client = Delimited::new(client);
+        client.send(ClientMessage::Forward(target, None)).await.unwrap();
+        client.recv_timeout().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn forward_without_id_is_refused_if_required() {
+        let Some(ServerMessage::Error(message)) = forward_without_id(true).await else {
+            panic!("forward request without Strawberry ID was not refused");
+        };
+        assert!(message.contains("requires a Strawberry ID"));
+
+        assert!(matches!(forward_without_id(false).await, Some(ServerMessage::Forwarding)));
     }
 }
@@ -0,0 +1,496 @@
+//! Access control for connections the server or a client opens on behalf of
+//! others.
+//!
+//! Everything is denied unless a rule allows the target. Rules are written
+//! like the targets themselves, with `*` as wildcard:
+//!
+//! - `db.internal:5432`, `*:22` or `10.0.0.5:*` for addresses
+//! - `*.corp.example:443` for all subdomains of a domain
+//! - `10.0.0.0/8:*` or `[fd00::/8]:443` for networks
+//! - `tunnel:demo` or `tunnel:*` for shared tunnels (server only)
+//!
+//! Clients can also restrict which visitors reach their tunnel, with a
+//! [`SourceFilter`] of networks that the server enforces.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::core::shared::ForwardTarget;
+
+/// Range of IP addresses, like `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse a network like `10.0.0.0/8`, or a single address.
+    #[must_use]
+    pub fn parse(input: &str) -> Option<Self> {
+        let (addr, prefix) = match input.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (input.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+
+        // Visitors are compared in their canonical form, so a network of
+        // IPv4-mapped addresses has to be one of IPv4 addresses as well.
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => Some(Self { addr: v4.into(), prefix: prefix - 96 }),
+            _ => Some(Self { addr, prefix }),
+        }
+    }
+
+    /// Whether the address lies within the network.
+    #[must_use]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    const fn is_single(&self) -> bool {
+        self.prefix == if self.addr.is_ipv4() { 32 } else { 128 }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_single() {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        Self::parse(&input).ok_or_else(|| {
+            de::Error::custom(format!("invalid network '{input}' (expected an address or address/prefix)"))
+        })
+    }
+}
+
+/// Hosts matched by an address rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// Any host.
+    Any,
+
+    /// A host name, compared case-insensitively.
+    Name(String),
+
+    /// All subdomains of a domain, from `*.<domain>`.
+    Subdomains(String),
+
+    /// IP addresses within a network.
+    Network(Cidr),
+}
+
+impl HostPattern {
+    fn parse(input: &str) -> Option<Self> {
+        if input == "*" {
+            return Some(Self::Any);
+        }
+        if let Some(domain) = input.strip_prefix("*.") {
+            return (!domain.is_empty()).then(|| Self::Subdomains(domain.to_ascii_lowercase()));
+        }
+        if let Some(network) = Cidr::parse(input) {
+            return Some(Self::Network(network));
+        }
+        (!input.is_empty() && !input.contains(['/', '*'])).then(|| Self::Name(input.to_ascii_lowercase()))
+    }
+
+    /// Whether the host, as given by the peer, matches without resolving it.
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Name(name) => name.eq_ignore_ascii_case(host),
+            Self::Subdomains(domain) => host
+                .len()
+                .checked_sub(domain.len() + 1)
+                .is_some_and(|split| {
+                    host.as_bytes()[split] == b'.' && host[split + 1..].eq_ignore_ascii_case(domain)
+                }),
+            Self::Network(network) => host.parse().is_ok_and(|addr| network.contains(addr)),
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "*"),
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Subdomains(domain) => write!(f, "*.{domain}"),
+            Self::Network(network) if network.addr.is_ipv6() => write!(f, "[{network}]"),
+            Self::Network(network) => write!(f, "{network}"),
+        }
+    }
+}
+
+/// A rule allowing connections to matching hosts and ports, `None` matches any port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressRule {
+    pub host: HostPattern,
+    pub port: Option<u16>,
+}
+
+impl AddressRule {
+    /// Parse a rule like `host:port`, see the module documentation for the syntax.
+    #[must_use]
+    pub fn parse(input: &str) -> Option<Self> {
+        let (host, port) = input.rsplit_once(':')?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().ok()?),
+        };
+        Some(Self {
+            host: HostPattern::parse(host)?,
+            port,
+        })
+    }
+
+    /// Whether the rule allows the host as given by the peer.
+    #[must_use]
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.port.is_none_or(|allowed| allowed == port) && self.host.matches(host)
+    }
+
+    /// Whether the rule allows a resolved address.
+    #[must_use]
+    pub fn matches_addr(&self, addr: SocketAddr) -> bool {
+        self.port.is_none_or(|allowed| allowed == addr.port())
+            && match &self.host {
+                HostPattern::Any => true,
+                HostPattern::Network(network) => network.contains(addr.ip()),
+                HostPattern::Name(_) | HostPattern::Subdomains(_) => false,
+            }
+    }
+}
+
+impl fmt::Display for AddressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{port}", self.host),
+            None => write!(f, "{}:*", self.host),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AddressRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        Self::parse(&input).ok_or_else(|| {
+            de::Error::custom(format!("invalid address rule '{input}' (expected host:port)"))
+        })
+    }
+}
+
+/// A rule allowing forwarding through the server to matching targets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardRule {
+    /// Host and port reachable from the server, matched as sent by the
+    /// client, without resolving it.
+    Address(AddressRule),
+
+    /// Shared tunnel by name, `None` matches any tunnel.
+    Tunnel(Option<String>),
+}
+
+impl ForwardRule {
+    /// Parse a rule, see the module documentation for the syntax.
+    #[must_use]
+    pub fn parse(input: &str) -> Option<Self> {
+        if let Some(name) = input.strip_prefix("tunnel:") {
+            return (!name.is_empty()).then(|| Self::Tunnel((name != "*").then(|| name.to_string())));
+        }
+        AddressRule::parse(input).map(Self::Address)
+    }
+
+    fn matches(&self, target: &ForwardTarget) -> bool {
+        match (self, target) {
+            (Self::Address(rule), ForwardTarget::Address(host, port)) => rule.matches(host, *port),
+            (Self::Tunnel(name), ForwardTarget::Tunnel(target_name)) => {
+                name.as_ref().is_none_or(|name| name == target_name)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ForwardRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(rule) => write!(f, "{rule}"),
+            Self::Tunnel(name) => write!(f, "tunnel:{}", name.as_deref().unwrap_or("*")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ForwardRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        Self::parse(&input).ok_or_else(|| {
+            de::Error::custom(format!("invalid forward rule '{input}' (expected host:port or tunnel:<name>)"))
+        })
+    }
+}
+
+/// Rules for local forwarding, denying everything if empty.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct ForwardAcl(Vec<ForwardRule>);
+
+impl ForwardAcl {
+    #[must_use]
+    pub const fn new(rules: Vec<ForwardRule>) -> Self {
+        Self(rules)
+    }
+
+    /// Whether any rule allows forwarding to the target.
+    #[must_use]
+    pub fn allows(&self, target: &ForwardTarget) -> bool {
+        self.0.iter().any(|rule| rule.matches(target))
+    }
+
+    #[must_use]
+    pub fn rules(&self) -> &[ForwardRule] {
+        &self.0
+    }
+}
+
+/// Destinations a client in proxy mode may dial, denying everything if empty.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct DestinationAcl(Vec<AddressRule>);
+
+impl DestinationAcl {
+    #[must_use]
+    pub const fn new(rules: Vec<AddressRule>) -> Self {
+        Self(rules)
+    }
+
+    /// Whether any rule allows the destination as requested by the peer.
+    #[must_use]
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.0.iter().any(|rule| rule.matches(host, port))
+    }
+
+    /// Whether any rule allows the resolved address of a destination.
+    #[must_use]
+    pub fn allows_addr(&self, addr: SocketAddr) -> bool {
+        self.0.iter().any(|rule| rule.matches_addr(addr))
+    }
+
+    #[must_use]
+    pub fn rules(&self) -> &[AddressRule] {
+        &self.0
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for DestinationAcl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, rule) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{rule}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Source networks a client lets into its tunnel, sent to the server in its hello.
+///
+/// Denied networks take precedence. Without allowed networks, every source
+/// that isn't denied may connect.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SourceFilter {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Cidr>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Cidr>,
+}
+
+impl SourceFilter {
+    /// Whether a visitor from the address may connect.
+    #[must_use]
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        !self.deny.iter().any(|network| network.contains(addr))
+            && (self.allow.is_empty() || self.allow.iter().any(|network| network.contains(addr)))
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(input: &str) -> IpAddr {
+        input.parse().unwrap()
+    }
+
+    fn cidr(input: &str) -> Cidr {
+        Cidr::parse(input).unwrap()
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(cidr("10.0.0.1").to_string(), "10.0.0.1");
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert_eq!(Cidr::parse("10.0.0.0/33"), None);
+        assert_eq!(Cidr::parse("::/129"), None);
+        assert_eq!(Cidr::parse("10.0.0.0/"), None);
+        assert_eq!(Cidr::parse("example.org"), None);
+    }
+
+    #[test]
+    fn cidr_contains() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+
+        assert!(cidr("203.0.113.7/32").contains(ip("203.0.113.7")));
+        assert!(!cidr("203.0.113.7/32").contains(ip("203.0.113.8")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn cidr_contains_ipv4_mapped() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn source_filter() {
+        assert!(SourceFilter::default().allows(ip("203.0.113.7")));
+
+        let deny_only = SourceFilter { allow: Vec::new(), deny: vec![cidr("198.51.100.0/24")] };
+        assert!(deny_only.allows(ip("203.0.113.7")));
+        assert!(!deny_only.allows(ip("198.51.100.1")));
+
+        let filter = SourceFilter {
+            allow: vec![cidr("10.0.0.0/8"), cidr("2001:db8::/32")],
+            deny: vec![cidr("10.0.0.66")],
+        };
+        assert!(filter.allows(ip("10.0.0.1")));
+        assert!(filter.allows(ip("::ffff:10.0.0.1")));
+        assert!(filter.allows(ip("2001:db8::1")));
+        assert!(!filter.allows(ip("10.0.0.66")));
+        assert!(!filter.allows(ip("::ffff:10.0.0.66")));
+        assert!(!filter.allows(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn host_wildcards() {
+        let rule = AddressRule::parse("*.corp.example:443").unwrap();
+        assert!(rule.matches("db.corp.example", 443));
+        assert!(rule.matches("a.b.CORP.example", 443));
+        assert!(!rule.matches("corp.example", 443));
+        assert!(!rule.matches("evilcorp.example", 443));
+        assert!(!rule.matches("db.corp.example", 80));
+
+        let rule = AddressRule::parse("*:22").unwrap();
+        assert!(rule.matches("anything", 22));
+        assert!(!rule.matches("anything", 23));
+
+        let rule = AddressRule::parse("Db.Internal:*").unwrap();
+        assert!(rule.matches("db.internal", 5432));
+        assert!(!rule.matches("db.internal.evil", 5432));
+
+        assert_eq!(AddressRule::parse("db*.internal:80"), None);
+        assert_eq!(AddressRule::parse("*.:80"), None);
+        assert_eq!(AddressRule::parse("db.internal"), None);
+    }
+
+    #[test]
+    fn network_rules() {
+        let rule = AddressRule::parse("10.0.0.0/8:*").unwrap();
+        assert!(rule.matches("10.1.2.3", 80));
+        assert!(!rule.matches("db.internal", 80));
+        assert!(rule.matches_addr("10.1.2.3:80".parse().unwrap()));
+
+        let rule = AddressRule::parse("[fd00::/8]:443").unwrap();
+        assert_eq!(rule.to_string(), "[fd00::/8]:443");
+        assert!(rule.matches("fd12::1", 443));
+        assert!(!rule.matches("fd12::1", 80));
+
+        // Names can't be checked against resolved addresses.
+        let rule = AddressRule::parse("db.internal:5432").unwrap();
+        assert!(!rule.matches_addr("10.1.2.3:5432".parse().unwrap()));
+    }
+
+    #[test]
+    fn forward_acl() {
+        let acl = ForwardAcl::new(
+            ["db.internal:5432", "tunnel:demo"].map(|rule| ForwardRule::parse(rule).unwrap()).to_vec(),
+        );
+        assert!(acl.allows(&ForwardTarget::Address("db.internal".into(), 5432)));
+        assert!(!acl.allows(&ForwardTarget::Address("db.internal".into(), 22)));
+        assert!(acl.allows(&ForwardTarget::Tunnel("demo".into())));
+        assert!(!acl.allows(&ForwardTarget::Tunnel("other".into())));
+
+        let any_tunnel = ForwardAcl::new(vec![ForwardRule::parse("tunnel:*").unwrap()]);
+        assert!(any_tunnel.allows(&ForwardTarget::Tunnel("other".into())));
+        assert!(!any_tunnel.allows(&ForwardTarget::Address("tunnel".into(), 80)));
+
+        assert!(!ForwardAcl::default().allows(&ForwardTarget::Tunnel("demo".into())));
+        assert_eq!(ForwardRule::parse("tunnel:"), None);
+    }
+
+    #[test]
+    fn destination_acl() {
+        let acl = DestinationAcl::new(
+            ["10.0.0.0/8:*", "*.corp.example:443"].map(|rule| AddressRule::parse(rule).unwrap()).to_vec(),
+        );
+        assert!(acl.allows("10.0.0.5", 22));
+        assert!(acl.allows("intranet.corp.example", 443));
+        assert!(!acl.allows("intranet.corp.example", 80));
+        assert!(!acl.allows("192.168.0.1", 22));
+        assert!(acl.allows_addr("10.0.0.5:22".parse().unwrap()));
+        assert!(!acl.allows_addr("192.168.0.1:443".parse().unwrap()));
+        assert!(!DestinationAcl::default().allows("10.0.0.5", 22));
+    }
+}
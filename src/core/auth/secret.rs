@@ -19,6 +19,12 @@ impl Authenticator {
         Self(Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"))
     }
 
+    /// Generate an authenticator from an already hashed secret.
+    #[must_use]
+    pub fn from_key(key: &[u8]) -> Self {
+        Self(Hmac::new_from_slice(key).expect("HMAC can take key of any size"))
+    }
+
     /// Generate a reply message for a challenge.
     #[must_use] 
     pub fn answer(&self, challenge: &Uuid) -> String {
@@ -30,7 +36,7 @@ impl Authenticator {
     /// Validate a reply to a challenge.
     ///
     /// ```
-    /// use bore_cli::auth::Authenticator;
+    /// use tunneled::core::auth::secret::Authenticator;
     /// use uuid::Uuid;
     ///
     /// let auth = Authenticator::new("secret");
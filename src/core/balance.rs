@@ -0,0 +1,247 @@
+//! Load balancing of forwarded connections across several local services.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use libstrawberry::colors::{BLUE, RESET};
+use serde::Deserialize;
+
+use crate::core::constants::CLIENT_LOG;
+use crate::core::output::Verbosity;
+use crate::core::target::{LocalStream, LocalTarget};
+
+/// Time an upstream is skipped after a failed connection attempt.
+pub const FAILURE_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// How connections are spread across upstreams.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceStrategy {
+    /// Use the upstreams in turn.
+    #[default]
+    RoundRobin,
+
+    /// Use the upstream with the fewest open connections.
+    LeastConnections,
+
+    /// Use a random upstream.
+    Random,
+}
+
+/// A local service that takes part in load balancing.
+struct Upstream {
+    target: LocalTarget,
+
+    /// Number of forwarded connections that are currently open.
+    active: AtomicUsize,
+
+    /// Time of the last failed connection attempt, if it is still relevant.
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn is_down(&self) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_some_and(|failed_at| failed_at.elapsed() < FAILURE_COOLDOWN)
+    }
+}
+
+/// Counts a connection towards its upstream until it is dropped.
+pub struct UpstreamGuard<'a>(&'a Upstream);
+
+impl Drop for UpstreamGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Local services that share the connections of one tunnel.
+pub struct Upstreams {
+    members: Vec<Upstream>,
+    strategy: BalanceStrategy,
+
+    /// Position of the next round-robin pick.
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    /// Balance connections across the given targets.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no target is given.
+    #[must_use]
+    pub fn new(targets: Vec<LocalTarget>, strategy: BalanceStrategy) -> Self {
+        assert!(!targets.is_empty(), "at least one upstream is required");
+        Self {
+            members: targets
+                .into_iter()
+                .map(|target| Upstream {
+                    target,
+                    active: AtomicUsize::new(0),
+                    failed_at: Mutex::new(None),
+                })
+                .collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Forward all connections to a single target.
+    #[must_use]
+    pub fn single(target: LocalTarget) -> Self {
+        Self::new(vec![target], BalanceStrategy::default())
+    }
+
+    /// All targets, in configuration order.
+    pub fn targets(&self) -> impl Iterator<Item = &LocalTarget> {
+        self.members.iter().map(|upstream| &upstream.target)
+    }
+
+    /// Connect to an upstream chosen by the strategy.
+    ///
+    /// Upstreams that recently failed are only tried after all others, and a
+    /// failed attempt falls through to the next candidate.
+    pub async fn connect(&self, verbosity: Verbosity) -> Result<(LocalStream, UpstreamGuard<'_>)> {
+        let mut last_err = None;
+
+        for upstream in self.candidates() {
+            match upstream.target.connect().await {
+                Ok(stream) => {
+                    *upstream.failed_at.lock().unwrap() = None;
+                    upstream.active.fetch_add(1, Ordering::Relaxed);
+                    return Ok((stream, UpstreamGuard(upstream)));
+                }
+                Err(err) => {
+                    if self.members.len() > 1 && !upstream.is_down() && verbosity.logs() {
+                        CLIENT_LOG.warning(format!(
+                            "Upstream {BLUE}{}{RESET} failed, skipping it for {}s: {err}",
+                            upstream.target,
+                            FAILURE_COOLDOWN.as_secs()
+                        ));
+                    }
+                    *upstream.failed_at.lock().unwrap() = Some(Instant::now());
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("No upstream available")))
+    }
+
+    /// Upstreams in the order they should be tried.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let len = self.members.len();
+        let start = match self.strategy {
+            BalanceStrategy::Random => fastrand::usize(..len),
+            _ => self.next.fetch_add(1, Ordering::Relaxed) % len,
+        };
+
+        let mut candidates: Vec<&Upstream> = self.members[start..]
+            .iter()
+            .chain(&self.members[..start])
+            .collect();
+
+        // Stable sorts keep the rotation above as tie breaker.
+        if self.strategy == BalanceStrategy::LeastConnections {
+            candidates.sort_by_key(|upstream| upstream.active.load(Ordering::Relaxed));
+        }
+        candidates.sort_by_key(|upstream| upstream.is_down());
+        candidates
+    }
+}
+
+impl fmt::Display for Upstreams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, target) in self.targets().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{target}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn target(port: u16) -> LocalTarget {
+        LocalTarget::Tcp {
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    /// Ports of the upstreams in the order the next connection tries them.
+    fn order(upstreams: &Upstreams) -> Vec<u16> {
+        upstreams
+            .candidates()
+            .iter()
+            .map(|upstream| match upstream.target {
+                LocalTarget::Tcp { port, .. } => port,
+                LocalTarget::Unix(_) => unreachable!("only TCP targets are used"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let upstreams = Upstreams::new(vec![target(1), target(2), target(3)], BalanceStrategy::RoundRobin);
+        assert_eq!(order(&upstreams), [1, 2, 3]);
+        assert_eq!(order(&upstreams), [2, 3, 1]);
+        assert_eq!(order(&upstreams), [3, 1, 2]);
+        assert_eq!(order(&upstreams), [1, 2, 3]);
+    }
+
+    #[test]
+    fn least_connections_first() {
+        let upstreams = Upstreams::new(vec![target(1), target(2), target(3)], BalanceStrategy::LeastConnections);
+        upstreams.members[0].active.store(2, Ordering::Relaxed);
+        upstreams.members[1].active.store(1, Ordering::Relaxed);
+        assert_eq!(order(&upstreams), [3, 2, 1]);
+    }
+
+    #[test]
+    fn down_upstreams_are_tried_last() {
+        let upstreams = Upstreams::new(vec![target(1), target(2), target(3)], BalanceStrategy::RoundRobin);
+        *upstreams.members[0].failed_at.lock().unwrap() = Some(Instant::now());
+        assert_eq!(order(&upstreams), [2, 3, 1]);
+        assert_eq!(order(&upstreams), [2, 3, 1]);
+        assert_eq!(order(&upstreams), [3, 2, 1]);
+
+        // After the cooldown, the upstream takes part in the rotation again.
+        *upstreams.members[0].failed_at.lock().unwrap() = Instant::now().checked_sub(FAILURE_COOLDOWN);
+        assert_eq!(order(&upstreams), [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn failed_upstream_is_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = listener.local_addr().unwrap().port();
+        let down = {
+            let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            closed.local_addr().unwrap().port()
+        };
+        let upstreams = Upstreams::new(vec![target(down), target(up)], BalanceStrategy::RoundRobin);
+
+        let (_stream, guard) = upstreams.connect(Verbosity::Quiet).await.unwrap();
+        assert!(upstreams.members[0].is_down());
+        assert_eq!(upstreams.members[1].active.load(Ordering::Relaxed), 1);
+        drop(guard);
+        assert_eq!(upstreams.members[1].active.load(Ordering::Relaxed), 0);
+
+        // The next round would start with the failed upstream, it is tried last instead.
+        assert_eq!(order(&upstreams), [up, down]);
+        drop(listener);
+        assert!(upstreams.connect(Verbosity::Quiet).await.is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Compression of forwarded connections between clients and the server.
+//!
+//! A client asks for an algorithm in its tunnel options. If the server
+//! allows compression, it confirms the algorithm right before its hello
+//! message, and both sides then compress every forwarded connection of the
+//! tunnel. External peers and local services still see the plain data.
+
+use std::fmt;
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+
+use async_compression::tokio::bufread::{DeflateDecoder, ZstdDecoder};
+use async_compression::tokio::write::{DeflateEncoder, ZstdEncoder};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadBuf};
+
+/// Compression algorithm of a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Zstandard, fast with a good ratio.
+    Zstd,
+
+    /// Deflate, slower but available everywhere.
+    Deflate,
+}
+
+impl Compression {
+    /// Parse the name of an algorithm, as given on the command line.
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zstd" => Some(Self::Zstd),
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Zstd => write!(f, "zstd"),
+            Self::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+/// Stream between a client and the server that compresses in both directions.
+pub struct CompressedStream {
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+    writer: Pin<Box<dyn AsyncWrite + Send>>,
+
+    /// Compressed bytes read from the stream.
+    read: Arc<AtomicU64>,
+
+    /// Compressed bytes written to the stream.
+    written: Arc<AtomicU64>,
+}
+
+impl CompressedStream {
+    /// Compress a stream, with data that was already read from it.
+    pub fn new<S>(stream: S, received: &[u8], compression: Compression) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let read = Arc::new(AtomicU64::new(0));
+        let written = Arc::new(AtomicU64::new(0));
+
+        let (read_half, write_half) = tokio::io::split(stream);
+        let reader = BufReader::new(Counted {
+            inner: Cursor::new(received.to_vec()).chain(read_half),
+            counter: Arc::clone(&read),
+        });
+        let writer = Counted {
+            inner: write_half,
+            counter: Arc::clone(&written),
+        };
+
+        let (reader, writer) = match compression {
+            Compression::Zstd => (
+                Box::pin(ZstdDecoder::new(reader)) as Pin<Box<dyn AsyncRead + Send>>,
+                Box::pin(ZstdEncoder::new(writer)) as Pin<Box<dyn AsyncWrite + Send>>,
+            ),
+            Compression::Deflate => (
+                Box::pin(DeflateDecoder::new(reader)) as Pin<Box<dyn AsyncRead + Send>>,
+                Box::pin(DeflateEncoder::new(writer)) as Pin<Box<dyn AsyncWrite + Send>>,
+            ),
+        };
+
+        Self {
+            reader,
+            writer,
+            read,
+            written,
+        }
+    }
+
+    /// Compressed bytes read from the underlying stream so far.
+    #[must_use]
+    pub fn wire_read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    /// Compressed bytes written to the underlying stream so far.
+    #[must_use]
+    pub fn wire_written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+impl AsyncRead for CompressedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.reader.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CompressedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.writer.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer.as_mut().poll_shutdown(cx)
+    }
+}
+
+/// Counts the bytes passing through a reader or writer.
+struct Counted<T> {
+    inner: T,
+    counter: Arc<AtomicU64>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.counter.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.counter.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -8,6 +8,12 @@ use libstrawberry::logging::Logger;
 pub const STRAWBERRY_ID_API: &str = "https://id.strawberryfoundations.org/v2/";
 // pub const STRAWBERRY_ID_API: &str = "http://192.168.0.194:8082/v1/";
 
+/// Server used by clients unless another one is given.
+pub const DEFAULT_SERVER: &str = "strawberryfoundations.org";
+
+/// Port of the control connection between clients and servers.
+pub const DEFAULT_CONTROL_PORT: u16 = 7835;
+
 pub static VERSION: LazyLock<String> = LazyLock::new(|| env!("CARGO_PKG_VERSION").to_string());
 
 pub static SERVER_LOG: LazyLock<Logger> = LazyLock::new(|| {
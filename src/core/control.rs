@@ -0,0 +1,344 @@
+//! Live state and remote control of a running client or server, e.g. for a
+//! dashboard.
+//!
+//! A [`ClientControl`] stays valid while the client listens, and across
+//! reconnects of its control connection. A [`ServerControl`] can be taken
+//! before the server listens, and stays valid until it stops.
+
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::{DashMap, DashSet};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+
+use crate::core::forward::Transfer;
+use crate::core::metrics::{Metrics, MetricsSnapshot};
+use crate::core::transport::Transport;
+
+/// Request to a running client, handled on its control connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientCommand {
+    /// Stop or resume taking connections, by reporting the tunnel as unhealthy.
+    Pause(bool),
+
+    /// Close the control connection and open the tunnel again.
+    Reconnect,
+}
+
+/// A connection that is currently forwarded by a client.
+#[derive(Debug)]
+pub struct LiveConnection {
+    /// Address of the visitor, if the server reported it.
+    pub peer: Option<SocketAddr>,
+
+    /// Time the server handed the connection to the client.
+    pub opened: Instant,
+
+    /// Bytes sent to and received from the visitor so far.
+    pub transfer: Transfer,
+
+    /// Closes the connection early.
+    pub(crate) kill: Notify,
+}
+
+impl LiveConnection {
+    pub(crate) fn new(peer: Option<SocketAddr>) -> Self {
+        Self {
+            peer,
+            opened: Instant::now(),
+            transfer: Transfer::default(),
+            kill: Notify::new(),
+        }
+    }
+
+    fn info(&self, id: Uuid) -> ConnectionInfo {
+        ConnectionInfo {
+            id,
+            peer: self.peer,
+            opened: self.opened,
+            sent: self.transfer.sent.load(Ordering::Relaxed),
+            received: self.transfer.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a [`LiveConnection`].
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    pub id: Uuid,
+    pub peer: Option<SocketAddr>,
+    pub opened: Instant,
+
+    /// Bytes sent from the local service to the visitor.
+    pub sent: u64,
+
+    /// Bytes received from the visitor.
+    pub received: u64,
+}
+
+/// State of a client shared with its controls.
+#[derive(Debug, Default)]
+pub(crate) struct ClientState {
+    /// Address and port the server exposes the tunnel on, changed by reconnects.
+    pub tunnel: Mutex<(String, u16)>,
+    pub connections: DashMap<Uuid, Arc<LiveConnection>>,
+    pub rtt: Mutex<Option<Duration>>,
+    pub paused: AtomicBool,
+}
+
+impl ClientState {
+    /// Track a new connection until it is removed again.
+    pub fn open(&self, id: Uuid, peer: Option<SocketAddr>) -> Arc<LiveConnection> {
+        let connection = Arc::new(LiveConnection::new(peer));
+        self.connections.insert(id, Arc::clone(&connection));
+        connection
+    }
+}
+
+/// Handle to inspect and control a running [`Client`](crate::commands::local::Client).
+#[derive(Debug, Clone)]
+pub struct ClientControl {
+    pub(crate) state: Arc<ClientState>,
+    pub(crate) commands: UnboundedSender<ClientCommand>,
+}
+
+impl ClientControl {
+    /// Address the server exposes the tunnel on.
+    #[must_use]
+    pub fn remote_addr(&self) -> String {
+        self.state.tunnel.lock().unwrap().0.clone()
+    }
+
+    /// Port the server exposes the tunnel on.
+    #[must_use]
+    pub fn remote_port(&self) -> u16 {
+        self.state.tunnel.lock().unwrap().1
+    }
+
+    /// Connections that are currently forwarded, oldest first.
+    #[must_use]
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections = self
+            .state
+            .connections
+            .iter()
+            .map(|entry| entry.info(*entry.key()))
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|connection| connection.opened);
+        connections
+    }
+
+    /// Round trip time of the control connection, once it was measured.
+    #[must_use]
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.state.rtt.lock().unwrap()
+    }
+
+    /// Whether the client stopped taking connections.
+    #[must_use]
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::Relaxed)
+    }
+
+    /// Close a forwarded connection, returning whether it was open.
+    #[must_use]
+    pub fn kill(&self, id: Uuid) -> bool {
+        self.state
+            .connections
+            .get(&id)
+            .map(|connection| connection.kill.notify_one())
+            .is_some()
+    }
+
+    /// Stop or resume taking connections.
+    pub fn pause(&self, paused: bool) {
+        let _ = self.commands.send(ClientCommand::Pause(paused));
+    }
+
+    /// Open the tunnel again on a new control connection.
+    pub fn reconnect(&self) {
+        let _ = self.commands.send(ClientCommand::Reconnect);
+    }
+}
+
+/// Control connection of a client serving a tunnel on the server.
+#[derive(Debug)]
+pub struct Session {
+    /// Address the client connected from.
+    pub addr: SocketAddr,
+
+    pub transport: Transport,
+
+    /// Time the tunnel was opened for the client.
+    pub opened: Instant,
+
+    /// Strawberry ID user the client authenticated as.
+    pub user: Option<String>,
+
+    /// Public port of the tunnel.
+    pub port: u16,
+
+    /// Name of the tunnel if it is shared.
+    pub name: Option<String>,
+
+    /// Closes the control connection.
+    pub(crate) disconnect: Notify,
+}
+
+/// Point-in-time copy of a [`Session`].
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    pub opened: Instant,
+    pub user: Option<String>,
+    pub port: u16,
+    pub name: Option<String>,
+}
+
+/// State of a server shared with its controls.
+#[derive(Debug, Default)]
+pub(crate) struct ServerState {
+    pub sessions: DashMap<u64, Arc<Session>>,
+
+    /// Forwarded connections by ID, with the port of their tunnel.
+    pub connections: DashMap<Uuid, (u16, Arc<LiveConnection>)>,
+
+    /// Addresses that may not connect as clients.
+    pub banned: DashSet<IpAddr>,
+
+    next_session: AtomicU64,
+}
+
+impl ServerState {
+    pub fn new(banned: Vec<IpAddr>) -> Self {
+        Self {
+            banned: banned.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Track a control session until it is removed again.
+    pub fn open_session(&self, session: Session) -> (u64, Arc<Session>) {
+        let id = self.next_session.fetch_add(1, Ordering::Relaxed);
+        let session = Arc::new(session);
+        self.sessions.insert(id, Arc::clone(&session));
+        (id, session)
+    }
+
+    /// Track a forwarded connection until it is removed again.
+    pub fn open(&self, id: Uuid, port: u16, peer: SocketAddr) -> Arc<LiveConnection> {
+        let connection = Arc::new(LiveConnection::new(Some(peer)));
+        self.connections.insert(id, (port, Arc::clone(&connection)));
+        connection
+    }
+}
+
+/// Handle to inspect and control a [`Server`](crate::commands::server::Server).
+#[derive(Debug, Clone)]
+pub struct ServerControl {
+    pub(crate) state: Arc<ServerState>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) port_range: RangeInclusive<u16>,
+    pub(crate) static_port_users: usize,
+}
+
+impl ServerControl {
+    /// Control sessions of clients, oldest first.
+    #[must_use]
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .state
+            .sessions
+            .iter()
+            .map(|entry| SessionInfo {
+                id: *entry.key(),
+                addr: entry.addr,
+                transport: entry.transport,
+                opened: entry.opened,
+                user: entry.user.clone(),
+                port: entry.port,
+                name: entry.name.clone(),
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    /// Connections that are currently forwarded, with the port of their tunnel, oldest first.
+    #[must_use]
+    pub fn connections(&self) -> Vec<(u16, ConnectionInfo)> {
+        let mut connections = self
+            .state
+            .connections
+            .iter()
+            .map(|entry| (entry.0, entry.1.info(*entry.key())))
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|(_, connection)| connection.opened);
+        connections
+    }
+
+    /// Counters of all connections the server forwarded.
+    #[must_use]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Range tunnels are assigned ports from.
+    #[must_use]
+    pub const fn port_range(&self) -> &RangeInclusive<u16> {
+        &self.port_range
+    }
+
+    /// Number of Strawberry ID users allowed to request static ports.
+    #[must_use]
+    pub const fn static_port_users(&self) -> usize {
+        self.static_port_users
+    }
+
+    /// Addresses that may not connect as clients.
+    #[must_use]
+    pub fn banned(&self) -> Vec<IpAddr> {
+        let mut banned = self.state.banned.iter().map(|ip| *ip).collect::<Vec<_>>();
+        banned.sort_unstable();
+        banned
+    }
+
+    /// Close the control connection of a client, returning whether it was open.
+    ///
+    /// The client may connect again, see [`Self::ban`].
+    #[must_use]
+    pub fn disconnect(&self, session: u64) -> bool {
+        self.state
+            .sessions
+            .get(&session)
+            .map(|session| session.disconnect.notify_one())
+            .is_some()
+    }
+
+    /// Refuse clients from an address until the server restarts, and
+    /// disconnect the ones already connected. Returns the number of
+    /// disconnected clients.
+    #[must_use]
+    pub fn ban(&self, ip: IpAddr) -> usize {
+        self.state.banned.insert(ip);
+        self.state
+            .sessions
+            .iter()
+            .filter(|session| session.addr.ip() == ip)
+            .map(|session| session.disconnect.notify_one())
+            .count()
+    }
+
+    /// Accept clients from a banned address again, returning whether it was banned.
+    #[must_use]
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.state.banned.remove(&ip).is_some()
+    }
+}
@@ -0,0 +1,331 @@
+//! Integration with service managers like systemd.
+//!
+//! This covers socket activation (`LISTEN_FDS`), readiness notification
+//! (`NOTIFY_SOCKET`), the watchdog (`WATCHDOG_USEC`), pid files and dropping
+//! root privileges once all privileged ports are bound. Every function is a
+//! no-op when the corresponding environment variable is not set, so the
+//! server behaves the same when it is started by hand.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+
+/// First file descriptor passed by the service manager, see `sd_listen_fds(3)`.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Options for running the server as a system daemon.
+#[derive(Debug, Default, Clone)]
+pub struct DaemonOptions {
+    /// Path of the pid file, written on startup and removed on shutdown.
+    pub pid_file: Option<PathBuf>,
+
+    /// User to switch to after the control port is bound.
+    pub user: Option<String>,
+
+    /// Group to switch to after the control port is bound.
+    pub group: Option<String>,
+}
+
+/// Pid file that is removed again once it is dropped.
+///
+/// After dropping privileges, the directory of the pid file may not be
+/// writable anymore. The file is emptied then, so nobody mistakes a stale pid
+/// for the server.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write the id of the current process to the given path.
+    pub fn create(path: &Path) -> Result<Self> {
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Could not write pid file {}", path.display()))?;
+        Ok(Self(path.to_path_buf()))
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if fs::remove_file(&self.0).is_err() {
+            let _ = fs::write(&self.0, "");
+        }
+    }
+}
+
+/// Take the listening sockets passed by the service manager, if any.
+///
+/// Returns an empty list if the process was not socket activated. The
+/// variables are left in place: `LISTEN_PID` names this process, so processes
+/// started by the server ignore them.
+#[cfg(unix)]
+pub fn listen_fds() -> Result<Vec<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let count = listen_fd_count(env::var("LISTEN_PID").ok().as_deref(), env::var("LISTEN_FDS").ok().as_deref())?;
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: the service manager hands these descriptors over to us and
+        // nothing else in this process has taken ownership of them.
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> Result<Vec<std::net::TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// Number of sockets passed to this process, from `LISTEN_PID` and `LISTEN_FDS`.
+#[cfg(unix)]
+fn listen_fd_count(pid: Option<&str>, count: Option<&str>) -> Result<i32> {
+    let Some(pid) = pid else {
+        return Ok(0);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(0);
+    }
+    count
+        .context("LISTEN_PID is set, but LISTEN_FDS is missing")?
+        .parse()
+        .context("LISTEN_FDS is not a number")
+}
+
+/// Send a state update like `READY=1` to the service manager.
+///
+/// Returns `Ok(false)` if no notification socket is configured.
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    notify_socket(&path.to_string_lossy(), state)?;
+    Ok(true)
+}
+
+/// Send a state update to the socket at `path`, abstract if it starts with `@`.
+#[cfg(unix)]
+fn notify_socket(path: &str, state: &str) -> Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        bail!("Abstract notify socket {name} is not supported on this platform");
+    } else {
+        socket
+            .send_to(state.as_bytes(), path)
+            .with_context(|| format!("Could not notify service manager at {path}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> Result<bool> {
+    Ok(false)
+}
+
+/// Interval in which the service manager expects `WATCHDOG=1` messages.
+///
+/// This is already halved, as recommended by `sd_watchdog_enabled(3)`.
+#[must_use]
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_of(env::var("WATCHDOG_PID").ok().as_deref(), env::var("WATCHDOG_USEC").ok().as_deref())
+}
+
+/// Interval from `WATCHDOG_PID` and `WATCHDOG_USEC`.
+fn watchdog_interval_of(pid: Option<&str>, usec: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Switch to an unprivileged user and group.
+///
+/// If only a user is given, its primary group is used. `files`, like the pid
+/// file, are handed over to the user and group first, so the server can
+/// still clean them up.
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, files: &[&Path]) -> Result<()> {
+    use std::ffi::CString;
+
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+
+    let passwd = match user {
+        Some(user) => {
+            let name = CString::new(user)?;
+            // SAFETY: `name` is a valid C string; the result is copied before
+            // the next call into libc.
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+            if passwd.is_null() {
+                bail!("Unknown user '{user}'");
+            }
+            // SAFETY: checked for null above.
+            Some(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) })
+        }
+        None => None,
+    };
+
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group)?;
+            // SAFETY: see above.
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                bail!("Unknown group '{group}'");
+            }
+            // SAFETY: checked for null above.
+            unsafe { (*entry).gr_gid }
+        }
+        None => passwd.map(|(_, gid)| gid).unwrap_or_default(),
+    };
+
+    for file in files {
+        std::os::unix::fs::chown(file, passwd.map(|(uid, _)| uid), Some(gid))
+            .with_context(|| format!("Could not hand {} over", file.display()))?;
+    }
+
+    // SAFETY: plain syscalls without pointers, except `setgroups` which gets a
+    // valid pointer to a single group id.
+    unsafe {
+        if libc::setgroups(1, &raw const gid) != 0 {
+            bail!("Could not set supplementary groups: {}", std::io::Error::last_os_error());
+        }
+        if libc::setgid(gid) != 0 {
+            bail!("Could not switch to group {gid}: {}", std::io::Error::last_os_error());
+        }
+        if let Some((uid, _)) = passwd
+            && libc::setuid(uid) != 0
+        {
+            bail!("Could not switch to user {uid}: {}", std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, _files: &[&Path]) -> Result<()> {
+    if user.is_some() || group.is_some() {
+        bail!("Dropping privileges is only supported on unix systems");
+    }
+    Ok(())
+}
+
+/// Wait until the process is asked to shut down.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn listen_fds_only_for_this_process() {
+        let pid = std::process::id().to_string();
+        assert_eq!(listen_fd_count(None, Some("2")).unwrap(), 0);
+        assert_eq!(listen_fd_count(Some("1"), Some("2")).unwrap(), 0);
+        assert_eq!(listen_fd_count(Some(&pid), Some("2")).unwrap(), 2);
+        assert!(listen_fd_count(Some(&pid), None).is_err());
+        assert!(listen_fd_count(Some(&pid), Some("two")).is_err());
+    }
+
+    #[test]
+    fn watchdog_interval_is_halved() {
+        let pid = std::process::id().to_string();
+        assert_eq!(watchdog_interval_of(None, Some("30000000")), Some(Duration::from_secs(15)));
+        assert_eq!(watchdog_interval_of(Some(&pid), Some("2000")), Some(Duration::from_millis(1)));
+        assert_eq!(watchdog_interval_of(Some("1"), Some("30000000")), None);
+        assert_eq!(watchdog_interval_of(None, Some("0")), None);
+        assert_eq!(watchdog_interval_of(None, None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notify_sends_states() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = env::temp_dir().join(format!("tunneled-test-{}.notify", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        let mut buffer = [0; 64];
+        for state in ["READY=1\nSTATUS=Listening on 0.0.0.0:7835", "WATCHDOG=1", "STOPPING=1"] {
+            notify_socket(&path.to_string_lossy(), state).unwrap();
+            let length = socket.recv(&mut buffer).unwrap();
+            assert_eq!(&buffer[..length], state.as_bytes());
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert!(notify_socket(&path.to_string_lossy(), "READY=1").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notify_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let name = format!("tunneled-test-{}", std::process::id());
+        let socket = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        notify_socket(&format!("@{name}"), "READY=1").unwrap();
+
+        let mut buffer = [0; 16];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"READY=1");
+    }
+
+    #[test]
+    fn pid_file_is_removed_on_drop() {
+        let path = env::temp_dir().join(format!("tunneled-test-{}.pid", std::process::id()));
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(pid_file.path()).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,73 @@
+//! Lifecycle events of clients and servers, for embedding tunneled.
+//!
+//! Events are broadcast, so subscribers that fall behind miss events instead
+//! of slowing down the tunnel.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::core::forward::Forwarded;
+
+/// Number of events buffered for each subscriber.
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Result of a forwarded connection, with the error message if it failed.
+pub type ConnectionResult = Result<Forwarded, String>;
+
+/// Event of a running [`Client`](crate::commands::local::Client).
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The server handed a new external connection to the client.
+    ConnectionOpened { id: Uuid, peer: Option<SocketAddr> },
+
+    /// A forwarded connection ended.
+    ConnectionClosed { id: Uuid, result: ConnectionResult },
+
+    /// The health check of the local service changed its result.
+    HealthChanged { healthy: bool },
+
+    /// The server reported an error, without closing the tunnel.
+    ServerError { message: String },
+
+    /// The client opened its tunnel again, possibly on a different port.
+    Reconnected { addr: String, port: u16 },
+
+    /// Opening the tunnel again failed, the client tries again after a delay.
+    ReconnectFailed { error: String, retry_in: Duration },
+}
+
+/// Event of a running [`Server`](crate::commands::server::Server).
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// The server accepts clients on its control port.
+    Listening { addr: SocketAddr },
+
+    /// A client started serving a tunnel, either a new one or a shared one.
+    TunnelOpened {
+        client: SocketAddr,
+        port: u16,
+        name: Option<String>,
+    },
+
+    /// A client stopped serving its tunnel.
+    TunnelClosed { client: SocketAddr, port: u16 },
+
+    /// A client failed to authenticate or its tunnel was refused.
+    HandshakeFailed { client: SocketAddr, reason: String },
+
+    /// A client accepted an external connection of a tunnel.
+    ConnectionOpened {
+        id: Uuid,
+        port: u16,
+        peer: Option<SocketAddr>,
+    },
+
+    /// A forwarded connection ended.
+    ConnectionClosed {
+        id: Uuid,
+        port: u16,
+        result: ConnectionResult,
+    },
+}
@@ -0,0 +1,268 @@
+//! Bidirectional forwarding of connections with optional time limits.
+
+use std::future::pending;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Instant, sleep, sleep_until, timeout};
+
+use crate::core::shared::deserialize_duration;
+
+/// Size of the buffer used for each direction of a connection.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Time given to both peers to shut down a connection that hit a limit.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time limits for a single forwarded connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ConnectionLimits {
+    /// Close the connection if no data was transferred in either direction.
+    #[serde(rename = "connection-idle-timeout", default, deserialize_with = "deserialize_duration")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Close the connection if a peer does not accept written data in time.
+    #[serde(rename = "connection-write-timeout", default, deserialize_with = "deserialize_duration")]
+    pub write_timeout: Option<Duration>,
+
+    /// Close the connection after this time, regardless of activity.
+    #[serde(rename = "max-connection-duration", default, deserialize_with = "deserialize_duration")]
+    pub max_duration: Option<Duration>,
+}
+
+impl ConnectionLimits {
+    /// Fill unset limits from another set of limits.
+    #[must_use]
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            idle_timeout: self.idle_timeout.or(other.idle_timeout),
+            write_timeout: self.write_timeout.or(other.write_timeout),
+            max_duration: self.max_duration.or(other.max_duration),
+        }
+    }
+}
+
+/// Reason why a forwarded connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardOutcome {
+    /// Both peers closed the connection.
+    Closed,
+
+    /// No data was transferred within the idle timeout.
+    IdleTimeout,
+
+    /// A peer did not accept data within the write timeout.
+    WriteTimeout,
+
+    /// The connection reached its maximum duration.
+    MaxDuration,
+}
+
+impl ForwardOutcome {
+    /// Whether the connection was closed because it hit a limit.
+    #[must_use]
+    pub const fn is_expired(self) -> bool {
+        !matches!(self, Self::Closed)
+    }
+
+    /// Short description for logs.
+    #[must_use]
+    pub const fn reason(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::IdleTimeout => "idle timeout",
+            Self::WriteTimeout => "write timeout",
+            Self::MaxDuration => "maximum duration",
+        }
+    }
+}
+
+/// Summary of a forwarded connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Forwarded {
+    /// Reason why the connection ended.
+    pub outcome: ForwardOutcome,
+
+    /// Bytes transferred from the first to the second stream.
+    pub sent: u64,
+
+    /// Bytes transferred from the second to the first stream.
+    pub received: u64,
+
+    /// Size of the transferred bytes between client and server, if compressed.
+    pub compressed: Option<CompressedBytes>,
+}
+
+/// Compressed size of the data of a forwarded connection.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressedBytes {
+    /// Compressed size of [`Forwarded::sent`].
+    pub sent: u64,
+
+    /// Compressed size of [`Forwarded::received`].
+    pub received: u64,
+}
+
+impl Forwarded {
+    /// Ratio of forwarded to compressed bytes, if the connection was compressed.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn compression_ratio(&self) -> Option<f64> {
+        let compressed = self.compressed?;
+        let wire = compressed.sent + compressed.received;
+        (wire > 0).then(|| (self.sent + self.received) as f64 / wire as f64)
+    }
+}
+
+/// Bytes transferred so far by a connection that is still being forwarded.
+#[derive(Debug, Default)]
+pub struct Transfer {
+    /// Bytes transferred from the first to the second stream.
+    pub sent: AtomicU64,
+
+    /// Bytes transferred from the second to the first stream.
+    pub received: AtomicU64,
+}
+
+/// Time of the last transfer, in milliseconds since the connection started.
+struct Activity {
+    start: Instant,
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        let elapsed = u64::try_from(self.start.elapsed().as_millis()).unwrap_or(u64::MAX);
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Forward data between two streams until both are closed or a limit is hit.
+///
+/// Without limits this behaves like [`tokio::io::copy_bidirectional`].
+pub async fn forward<A, B>(a: &mut A, b: &mut B, limits: &ConnectionLimits) -> io::Result<Forwarded>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    forward_tracked(a, b, limits, &Transfer::default()).await
+}
+
+/// Like [`forward`], counting the transferred bytes while the connection is open.
+pub async fn forward_tracked<A, B>(
+    a: &mut A,
+    b: &mut B,
+    limits: &ConnectionLimits,
+    transfer: &Transfer,
+) -> io::Result<Forwarded>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let activity = Activity {
+        start: Instant::now(),
+        last: AtomicU64::new(0),
+    };
+    let Transfer { sent, received } = transfer;
+
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+
+    let outcome = {
+        let transfer = async {
+            tokio::try_join!(
+                pipe(&mut a_read, &mut b_write, limits.write_timeout, &activity, sent),
+                pipe(&mut b_read, &mut a_write, limits.write_timeout, &activity, received),
+            )
+        };
+
+        let idle = async {
+            let Some(idle_timeout) = limits.idle_timeout else {
+                return pending().await;
+            };
+            loop {
+                let deadline = activity.last() + idle_timeout;
+                if Instant::now() >= deadline {
+                    return;
+                }
+                sleep_until(deadline).await;
+            }
+        };
+
+        let max_duration = async {
+            match limits.max_duration {
+                Some(max_duration) => sleep(max_duration).await,
+                None => pending().await,
+            }
+        };
+
+        tokio::select! {
+            result = transfer => match result {
+                Ok(_) => ForwardOutcome::Closed,
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => ForwardOutcome::WriteTimeout,
+                Err(err) => return Err(err),
+            },
+            () = idle => ForwardOutcome::IdleTimeout,
+            () = max_duration => ForwardOutcome::MaxDuration,
+        }
+    };
+
+    if outcome.is_expired() {
+        // Best effort, a peer that stopped reading must not keep us waiting.
+        let _ = timeout(SHUTDOWN_TIMEOUT, async {
+            let _ = tokio::join!(a_write.shutdown(), b_write.shutdown());
+        })
+        .await;
+    }
+
+    Ok(Forwarded {
+        outcome,
+        sent: sent.load(Ordering::Relaxed),
+        received: received.load(Ordering::Relaxed),
+        compressed: None,
+    })
+}
+
+/// Copy data from a reader to a writer, shutting the writer down on EOF.
+async fn pipe<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    write_timeout: Option<Duration>,
+    activity: &Activity,
+    counter: &AtomicU64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        activity.touch();
+
+        let write = async {
+            writer.write_all(&buf[..n]).await?;
+            writer.flush().await
+        };
+        match write_timeout {
+            Some(write_timeout) => timeout(write_timeout, write)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "write timed out"))??,
+            None => write.await?,
+        }
+
+        activity.touch();
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,71 @@
+//! Health checks of the local service behind a tunnel.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::core::shared::{NETWORK_TIMEOUT, deserialize_duration};
+
+/// Interval between two probes, if not configured otherwise.
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of response bytes read from an HTTP probe.
+const MAX_RESPONSE_LENGTH: u64 = 64 * 1024;
+
+/// How the client probes its local service.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct HealthCheck {
+    /// Time between two probes.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub interval: Option<Duration>,
+
+    /// HTTP path that has to answer with a successful status code.
+    ///
+    /// If unset, a successful TCP connect is enough.
+    pub path: Option<String>,
+}
+
+impl HealthCheck {
+    /// Time between two probes.
+    #[must_use]
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(DEFAULT_HEALTH_INTERVAL)
+    }
+
+    /// Probe a connection to the local service, returning whether it is healthy.
+    pub async fn probe<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S, host: &str) -> bool {
+        match &self.path {
+            Some(path) => timeout(NETWORK_TIMEOUT, http_status(stream, host, path))
+                .await
+                .ok()
+                .flatten()
+                .is_some_and(|status| (200..400).contains(&status)),
+            None => true,
+        }
+    }
+}
+
+/// Send a `GET` request and return the response status code.
+pub(crate) async fn http_status<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, host: &str, path: &str) -> Option<u16> {
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: tunneled\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await.ok()?;
+
+    // Drain the response, so the service does not see its connection reset.
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_LENGTH)
+        .read_to_end(&mut response)
+        .await
+        .ok()?;
+
+    // Status line: HTTP/1.1 200 OK
+    String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
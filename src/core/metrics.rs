@@ -0,0 +1,115 @@
+//! Counters for forwarded connections.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::core::forward::{ForwardOutcome, Forwarded};
+
+/// Connection counters of a client or server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Connections that are currently forwarded.
+    active: AtomicU64,
+
+    /// Connections that finished forwarding.
+    closed: AtomicU64,
+
+    /// Connections closed because of the idle timeout.
+    idle_timeouts: AtomicU64,
+
+    /// Connections closed because of the write timeout.
+    write_timeouts: AtomicU64,
+
+    /// Connections closed because of the maximum duration.
+    max_durations: AtomicU64,
+
+    /// Bytes forwarded from external peers to local services.
+    bytes_in: AtomicU64,
+
+    /// Bytes forwarded from local services to external peers.
+    bytes_out: AtomicU64,
+
+    /// Bytes forwarded over compressed connections, before compression.
+    uncompressed_bytes: AtomicU64,
+
+    /// Size of those bytes after compression.
+    compressed_bytes: AtomicU64,
+}
+
+/// Point-in-time copy of [`Metrics`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MetricsSnapshot {
+    pub active: u64,
+    pub closed: u64,
+    pub idle_timeouts: u64,
+    pub write_timeouts: u64,
+    pub max_durations: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl MetricsSnapshot {
+    /// Connections closed because they hit any limit.
+    #[must_use]
+    pub const fn expired(&self) -> u64 {
+        self.idle_timeouts + self.write_timeouts + self.max_durations
+    }
+
+    /// Ratio of forwarded to compressed bytes, over all compressed connections.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.compressed_bytes > 0).then(|| self.uncompressed_bytes as f64 / self.compressed_bytes as f64)
+    }
+}
+
+impl Metrics {
+    /// Count a connection that started forwarding.
+    pub fn opened(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection that finished forwarding, or failed with an error.
+    ///
+    /// The first stream passed to [`forward`](crate::core::forward::forward)
+    /// is expected to lead to the local service.
+    pub fn closed(&self, forwarded: Option<&Forwarded>) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.closed.fetch_add(1, Ordering::Relaxed);
+
+        let Some(forwarded) = forwarded else {
+            return;
+        };
+        self.bytes_out.fetch_add(forwarded.sent, Ordering::Relaxed);
+        self.bytes_in.fetch_add(forwarded.received, Ordering::Relaxed);
+        if let Some(compressed) = forwarded.compressed {
+            self.uncompressed_bytes.fetch_add(forwarded.sent + forwarded.received, Ordering::Relaxed);
+            self.compressed_bytes.fetch_add(compressed.sent + compressed.received, Ordering::Relaxed);
+        }
+
+        let counter = match forwarded.outcome {
+            ForwardOutcome::IdleTimeout => &self.idle_timeouts,
+            ForwardOutcome::WriteTimeout => &self.write_timeouts,
+            ForwardOutcome::MaxDuration => &self.max_durations,
+            ForwardOutcome::Closed => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a consistent enough copy of all counters.
+    #[must_use]
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            active: self.active.load(Ordering::Relaxed),
+            closed: self.closed.load(Ordering::Relaxed),
+            idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+            write_timeouts: self.write_timeouts.load(Ordering::Relaxed),
+            max_durations: self.max_durations.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            uncompressed_bytes: self.uncompressed_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
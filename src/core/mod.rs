@@ -1,3 +1,20 @@
+pub mod acl;
 pub mod auth;
+pub mod balance;
+pub mod compression;
 pub mod constants;
-pub mod shared;
\ No newline at end of file
+pub mod control;
+pub mod daemon;
+pub mod events;
+pub mod forward;
+pub mod health;
+pub mod metrics;
+pub mod outbound;
+pub mod output;
+pub mod private;
+pub mod proxy;
+pub mod quic;
+pub mod shared;
+pub mod target;
+pub mod transport;
+pub mod tunnel;
\ No newline at end of file
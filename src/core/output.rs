@@ -0,0 +1,588 @@
+//! What clients report on stdout, either as colored logs, as one JSON event
+//! per line for scripts, or on an interactive dashboard.
+
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use libstrawberry::colors::{BOLD, C_RESET, GREEN, RED, RESET, YELLOW};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::commands::local::{Client, ClientError};
+use crate::core::constants::CLIENT_LOG;
+use crate::core::events::ClientEvent;
+use crate::core::shared::format_duration;
+use crate::tui::client::ClientDashboard;
+
+/// Time between pings of the control connection, to show its round trip time.
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Output that belongs to no particular service, in the state directory of
+/// services running in the background.
+pub const MAIN_LOG: &str = "compose.log";
+
+/// What a service is doing, as shown in the summary on exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceState {
+    /// Waiting for its dependencies or its local service.
+    Waiting,
+    Starting,
+    Running,
+    Restarting,
+
+    /// The tunnel ended and the restart policy keeps it down.
+    Stopped,
+
+    /// The tunnel failed and the restart policy keeps it down.
+    Failed,
+}
+
+/// Last known state of a service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub service: String,
+    pub state: ServiceState,
+    pub address: Option<String>,
+    pub restarts: u32,
+    pub error: Option<String>,
+}
+
+/// How much a client logs to stdout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Nothing, for callers that report events themselves.
+    Quiet,
+
+    /// Startup information, warnings and errors.
+    #[default]
+    Normal,
+
+    /// Every connection as well.
+    Verbose,
+}
+
+impl Verbosity {
+    /// Whether anything is logged.
+    #[must_use]
+    pub const fn logs(self) -> bool {
+        !matches!(self, Self::Quiet)
+    }
+
+    /// Whether every connection is logged.
+    #[must_use]
+    pub const fn is_verbose(self) -> bool {
+        matches!(self, Self::Verbose)
+    }
+}
+
+/// Format of the client output on stdout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Colored logs for humans.
+    #[default]
+    Text,
+
+    /// One JSON event per line, without any logs.
+    Json,
+
+    /// Live dashboard in the terminal, without any logs.
+    Tui,
+}
+
+/// A line of JSON output.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Ready {
+        service: Option<&'a str>,
+        server: &'a str,
+        address: &'a str,
+        port: u16,
+    },
+    ConnectionOpened {
+        service: Option<&'a str>,
+        id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        peer: Option<SocketAddr>,
+    },
+    ConnectionClosed {
+        service: Option<&'a str>,
+        id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        outcome: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sent: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        received: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compressed_sent: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compressed_received: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<&'a str>,
+    },
+    Health {
+        service: Option<&'a str>,
+        healthy: bool,
+    },
+    Error {
+        service: Option<&'a str>,
+        message: String,
+    },
+    Reconnected {
+        service: Option<&'a str>,
+        server: &'a str,
+        address: &'a str,
+        port: u16,
+    },
+    Disconnected {
+        service: Option<&'a str>,
+    },
+    Removed {
+        service: &'a str,
+    },
+    Output {
+        service: &'a str,
+        stream: &'static str,
+        line: &'a str,
+    },
+    Summary {
+        services: &'a [ServiceStatus],
+    },
+}
+
+impl Event<'_> {
+    fn print(&self) {
+        if let Ok(line) = serde_json::to_string(self) {
+            println!("{line}");
+        }
+    }
+}
+
+/// Public address of a running tunnel, as written to the assignments file.
+#[derive(Debug, Clone, Serialize)]
+struct Assignment {
+    service: Option<String>,
+    server: String,
+    address: String,
+    port: u16,
+}
+
+/// Reports what clients do and keeps the assignments file up to date.
+#[derive(Debug, Default)]
+pub struct Output {
+    format: OutputFormat,
+    assignments_file: Option<PathBuf>,
+    assignments: Mutex<Vec<Assignment>>,
+    dashboard: Option<Arc<ClientDashboard>>,
+
+    /// State directory of compose running in the background, whose log
+    /// files get what happens to every service.
+    logs: Option<PathBuf>,
+}
+
+impl Output {
+    /// Report in the format, writing the assignments of open tunnels to the
+    /// file if one is given.
+    #[must_use]
+    pub fn new(format: OutputFormat, assignments_file: Option<PathBuf>) -> Self {
+        Self {
+            format,
+            assignments_file,
+            assignments: Mutex::default(),
+            dashboard: (format == OutputFormat::Tui).then(Arc::default),
+            logs: None,
+        }
+    }
+
+    /// Also write what happens to every service to its log file in the state
+    /// directory, instead of letting clients log on their own.
+    #[must_use]
+    pub fn logging_to(mut self, state: PathBuf) -> Self {
+        self.logs = Some(state);
+        self
+    }
+
+    /// Verbosity of clients reporting here, which must only log as text.
+    #[must_use]
+    pub const fn verbosity(&self, verbose: bool) -> Verbosity {
+        if self.logs.is_some() {
+            return Verbosity::Quiet;
+        }
+        match self.format {
+            OutputFormat::Json | OutputFormat::Tui => Verbosity::Quiet,
+            OutputFormat::Text if verbose => Verbosity::Verbose,
+            OutputFormat::Text => Verbosity::Normal,
+        }
+    }
+
+    /// Time between pings of clients reporting here, only the dashboard shows
+    /// the round trip time.
+    #[must_use]
+    pub const fn ping_interval(&self) -> Option<Duration> {
+        match self.format {
+            OutputFormat::Tui => Some(PING_INTERVAL),
+            OutputFormat::Text | OutputFormat::Json => None,
+        }
+    }
+
+    /// Dashboard the clients are shown on, which the caller runs.
+    #[must_use]
+    pub fn dashboard(&self) -> Option<Arc<ClientDashboard>> {
+        self.dashboard.clone()
+    }
+
+    /// Report a tunnel the server opened, and watch its events.
+    pub fn ready(self: &Arc<Self>, service: Option<&str>, client: &Client) {
+        let remote_addr = client.remote_addr();
+        let address = public_host(&remote_addr, client.server());
+        if self.format == OutputFormat::Json {
+            Event::Ready {
+                service,
+                server: client.server(),
+                address,
+                port: client.remote_port(),
+            }
+            .print();
+        }
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.add(service, client);
+        }
+        self.log(service, &format!("Listening at {address}:{}", client.remote_port()));
+
+        if self.format == OutputFormat::Json || self.assignments_file.is_some() || self.logs.is_some() {
+            tokio::spawn(Arc::clone(self).watch(
+                service.map(ToString::to_string),
+                client.server().to_string(),
+                client.subscribe(),
+            ));
+        }
+        self.assign(service, client.server(), address, client.remote_port());
+    }
+
+    /// Report a client that stopped, either because it lost the server or failed.
+    pub fn stopped(&self, service: Option<&str>, result: &Result<(), ClientError>) {
+        self.update_assignments(|assignments| {
+            assignments.retain(|assignment| assignment.service.as_deref() != service);
+        });
+
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.stopped(service, result.as_ref().err());
+            return;
+        }
+        match result {
+            Ok(()) if self.format == OutputFormat::Json => Event::Disconnected { service }.print(),
+            Ok(()) => self.log(service, "The server closed the tunnel"),
+            Err(err) => self.error(service, err),
+        }
+    }
+
+    /// Report a service that was stopped because it was removed from the
+    /// services file.
+    pub fn removed(&self, service: &str) {
+        self.update_assignments(|assignments| {
+            assignments.retain(|assignment| assignment.service.as_deref() != Some(service));
+        });
+
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.remove(service);
+        }
+        self.log(Some(service), "Removed from the services file");
+        if self.format == OutputFormat::Json {
+            Event::Removed { service }.print();
+        }
+    }
+
+    /// Report a line the backend process of a service printed, prefixed with
+    /// the service.
+    pub fn process(&self, service: &str, line: &str, stderr: bool) {
+        if self.logs.is_some() {
+            self.log(Some(service), line);
+            return;
+        }
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.output(service, line, stderr);
+            if dashboard.is_running() {
+                return;
+            }
+        }
+        match self.format {
+            OutputFormat::Json => Event::Output {
+                service,
+                stream: if stderr { "stderr" } else { "stdout" },
+                line,
+            }
+            .print(),
+            OutputFormat::Text | OutputFormat::Tui if stderr => eprintln!("{BOLD}{service} |{C_RESET} {line}"),
+            OutputFormat::Text | OutputFormat::Tui => println!("{BOLD}{service} |{C_RESET} {line}"),
+        }
+    }
+
+    /// Report what happens to the services as a whole, e.g. on reload.
+    pub fn info(&self, message: &str) {
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.info(message);
+            if dashboard.is_running() {
+                return;
+            }
+        }
+        if self.format != OutputFormat::Json {
+            CLIENT_LOG.info(message);
+        }
+    }
+
+    /// Report the state every service of a compose file ended up in.
+    pub fn summary(&self, services: &[ServiceStatus]) {
+        if self.dashboard.as_ref().is_some_and(|dashboard| dashboard.is_running()) {
+            return;
+        }
+        if self.format == OutputFormat::Json {
+            Event::Summary { services }.print();
+            return;
+        }
+
+        let width = services.iter().map(|status| status.service.len()).max().unwrap_or(0);
+        println!("\n{BOLD}Services:{C_RESET}");
+        for status in services {
+            let color = match status.state {
+                ServiceState::Running => GREEN,
+                ServiceState::Waiting
+                | ServiceState::Starting
+                | ServiceState::Restarting
+                | ServiceState::Stopped => YELLOW,
+                ServiceState::Failed => RED,
+            };
+            let state = serde_json::to_value(status.state).ok();
+            let state = state.as_ref().and_then(|state| state.as_str()).unwrap_or_default();
+            let mut details = status.address.clone().into_iter().collect::<Vec<_>>();
+            match status.restarts {
+                0 => (),
+                1 => details.push("1 restart".to_string()),
+                restarts => details.push(format!("{restarts} restarts")),
+            }
+            details.extend(status.error.clone());
+            println!(
+                "  {:width$}  {color}{state:10}{RESET}  {}",
+                status.service,
+                details.join(", ")
+            );
+        }
+    }
+
+    /// Report an error, on stderr unless the output is JSON or the dashboard
+    /// is shown.
+    pub fn error(&self, service: Option<&str>, err: &impl Display) {
+        if service.is_some() {
+            self.log(service, &format!("Error: {err}"));
+        }
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.error(service, &err.to_string());
+            if dashboard.is_running() {
+                return;
+            }
+        }
+        match self.format {
+            OutputFormat::Json => Event::Error {
+                service,
+                message: err.to_string(),
+            }
+            .print(),
+            OutputFormat::Text | OutputFormat::Tui => eprintln!("{RED}{BOLD} ! {C_RESET} {err}"),
+        }
+    }
+
+    /// Print the events of a client as JSON and follow its address, until it stops.
+    async fn watch(self: Arc<Self>, service: Option<String>, server: String, mut events: broadcast::Receiver<ClientEvent>) {
+        let service = service.as_deref();
+        let json = self.format == OutputFormat::Json;
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    self.error(service, &format!("Output fell behind, {missed} events were dropped"));
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            match event {
+                ClientEvent::Reconnected { addr, port } => {
+                    let address = public_host(&addr, &server);
+                    if json {
+                        Event::Reconnected {
+                            service,
+                            server: &server,
+                            address,
+                            port,
+                        }
+                        .print();
+                    }
+                    self.log(service, &format!("Reconnected, listening at {address}:{port}"));
+                    self.assign(service, &server, address, port);
+                }
+                ClientEvent::ConnectionOpened { id, peer } => {
+                    if json {
+                        Event::ConnectionOpened { service, id, peer }.print();
+                    }
+                    let from = peer.map(|peer| format!(" from {peer}")).unwrap_or_default();
+                    self.log(service, &format!("Connection {id} opened{from}"));
+                }
+                ClientEvent::ConnectionClosed { id, result } => {
+                    let forwarded = result.as_ref().ok();
+                    if json {
+                        let compressed = forwarded.and_then(|forwarded| forwarded.compressed);
+                        Event::ConnectionClosed {
+                            service,
+                            id,
+                            outcome: forwarded.map(|forwarded| forwarded.outcome.reason()),
+                            sent: forwarded.map(|forwarded| forwarded.sent),
+                            received: forwarded.map(|forwarded| forwarded.received),
+                            compressed_sent: compressed.map(|compressed| compressed.sent),
+                            compressed_received: compressed.map(|compressed| compressed.received),
+                            error: result.as_ref().err().map(String::as_str),
+                        }
+                        .print();
+                    }
+                    match &result {
+                        Ok(forwarded) => self.log(
+                            service,
+                            &format!(
+                                "Connection {id} closed ({}), {} bytes sent, {} received",
+                                forwarded.outcome.reason(),
+                                forwarded.sent,
+                                forwarded.received
+                            ),
+                        ),
+                        Err(err) => self.log(service, &format!("Connection {id} failed: {err}")),
+                    }
+                }
+                ClientEvent::HealthChanged { healthy } => {
+                    if json {
+                        Event::Health { service, healthy }.print();
+                    }
+                    self.log(service, if healthy { "Healthy" } else { "Unhealthy" });
+                }
+                ClientEvent::ServerError { message } => {
+                    self.log(service, &format!("Server error: {message}"));
+                    if json {
+                        Event::Error { service, message }.print();
+                    }
+                }
+                ClientEvent::ReconnectFailed { error, retry_in } => {
+                    let message = format!("Could not reconnect: {error}, retrying in {}", format_duration(retry_in));
+                    self.log(service, &message);
+                    if json {
+                        Event::Error { service, message }.print();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Append a line to the log file of a service, when running in the background.
+    fn log(&self, service: Option<&str>, message: &str) {
+        let Some(state) = &self.logs else {
+            return;
+        };
+        let path = log_file(state, service);
+        let line = format!("{} {message}\n", timestamp(SystemTime::now()));
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| OpenOptions::new().create(true).append(true).open(&path))
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(err) = written {
+            eprintln!("{RED}{BOLD} ! {C_RESET} Could not write to {}: {err}", path.display());
+        }
+    }
+
+    /// Record the public address of a tunnel in the assignments file.
+    fn assign(&self, service: Option<&str>, server: &str, address: &str, port: u16) {
+        self.update_assignments(|assignments| {
+            assignments.retain(|assignment| assignment.service.as_deref() != service);
+            assignments.push(Assignment {
+                service: service.map(ToString::to_string),
+                server: server.to_string(),
+                address: address.to_string(),
+                port,
+            });
+        });
+    }
+
+    /// Change the assignments and rewrite the file.
+    fn update_assignments(&self, update: impl FnOnce(&mut Vec<Assignment>)) {
+        let Some(path) = &self.assignments_file else {
+            return;
+        };
+        let mut assignments = self.assignments.lock().unwrap();
+        update(&mut assignments);
+
+        if let Err(err) = write_atomically(path, &assignments) {
+            self.error(None, &format!("Could not write assignments to {}: {err}", path.display()));
+        }
+    }
+}
+
+/// Host visitors reach the tunnel at, which is the server itself if the
+/// tunnel listens on all of its addresses.
+pub(crate) fn public_host<'a>(addr: &'a str, server: &'a str) -> &'a str {
+    match addr.parse::<IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => server,
+        _ => addr,
+    }
+}
+
+/// Log file of a service, or of compose itself.
+#[must_use]
+pub fn log_file(state: &Path, service: Option<&str>) -> PathBuf {
+    let Some(service) = service else {
+        return state.join(MAIN_LOG);
+    };
+    let name = service
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect::<String>();
+    state.join("logs").join(format!("{name}.log"))
+}
+
+/// A time in UTC, as `2024-05-01T12:00:00Z`.
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, rest) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date from days since 1970-01-01, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days.cast_signed() + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+/// Replace the file at once, so readers never see it half written.
+fn write_atomically(path: &Path, assignments: &[Assignment]) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, serde_json::to_string_pretty(assignments)? + "\n")?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
@@ -0,0 +1,348 @@
+//! Password protection for private tunnels.
+//!
+//! External connections to a private tunnel are only forwarded to the client
+//! after the visitor proved knowledge of the tunnel password. Raw TCP tunnels
+//! use a challenge-response preface (see `tunneled connect`), HTTP tunnels use
+//! HTTP Basic authentication.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::core::auth::secret::Authenticator;
+use crate::core::shared::{
+    ClientMessage, Delimited, NETWORK_TIMEOUT, ServerMessage, TunnelProtocol, http_response,
+};
+
+/// Number of failed attempts after which a visitor address is blocked.
+pub const MAX_FAILED_ATTEMPTS: u32 = 5;
+
+/// Window in which failed attempts are counted.
+pub const FAILED_ATTEMPTS_WINDOW: Duration = Duration::from_mins(5);
+
+/// Maximum size of the HTTP request head read during Basic authentication.
+const MAX_HTTP_HEAD: usize = 16 * 1024;
+
+/// Header asking HTTP visitors for credentials.
+const BASIC_CHALLENGE: &str = "WWW-Authenticate: Basic realm=\"tunneled\"\r\n";
+
+/// Hash a tunnel password, so that it never leaves the client in plain text.
+#[must_use]
+pub fn hash_password(password: &str) -> String {
+    hex::encode(Sha256::digest(password))
+}
+
+/// Failed authentication attempts per visitor address, shared by all tunnels.
+#[derive(Default)]
+pub struct FailedAttempts(DashMap<IpAddr, (u32, Instant)>);
+
+impl FailedAttempts {
+    /// Check whether an address has exceeded the number of failed attempts.
+    #[must_use]
+    pub fn is_blocked(&self, addr: IpAddr) -> bool {
+        self.0.get(&addr).is_some_and(|entry| {
+            let (count, since) = *entry;
+            count >= MAX_FAILED_ATTEMPTS && since.elapsed() < FAILED_ATTEMPTS_WINDOW
+        })
+    }
+
+    /// Record a failed attempt of an address.
+    pub fn record(&self, addr: IpAddr) {
+        let mut entry = self.0.entry(addr).or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= FAILED_ATTEMPTS_WINDOW {
+            *entry = (0, Instant::now());
+        }
+        entry.0 += 1;
+    }
+
+    /// Forget the failures of an address after a successful attempt.
+    pub fn reset(&self, addr: IpAddr) {
+        self.0.remove(&addr);
+    }
+}
+
+/// Outcome of authenticating a visitor.
+pub enum Verdict {
+    /// The visitor may pass, with bytes that were already read from the stream.
+    Granted(Vec<u8>),
+
+    /// The visitor was asked for credentials, which does not count as a failure.
+    Challenged,
+
+    /// The visitor did not authenticate, with a reason for the logs.
+    Denied(&'static str),
+}
+
+/// Gatekeeper for the external connections of one private tunnel.
+pub struct TunnelGate {
+    /// Authenticator keyed with the password hash sent by the client.
+    auth: Authenticator,
+
+    /// Hex encoded password hash sent by the client.
+    hash: String,
+
+    /// Protocol that decides how visitors authenticate.
+    protocol: TunnelProtocol,
+
+    /// Failed attempts, shared with the other tunnels of the server.
+    failures: Arc<FailedAttempts>,
+}
+
+impl TunnelGate {
+    /// Create a gate from the password hash in the client's hello message.
+    pub fn new(hash: &str, protocol: TunnelProtocol, failures: Arc<FailedAttempts>) -> Result<Self> {
+        let key = hex::decode(hash)?;
+        Ok(Self {
+            auth: Authenticator::from_key(&key),
+            hash: hash.to_lowercase(),
+            protocol,
+            failures,
+        })
+    }
+
+    /// Authenticate a visitor before its connection is forwarded.
+    pub async fn authorize<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        addr: IpAddr,
+    ) -> Result<Verdict> {
+        if self.failures.is_blocked(addr) {
+            if self.protocol == TunnelProtocol::Http {
+                stream
+                    .write_all(http_response("429 Too Many Requests", "", "").as_bytes())
+                    .await?;
+            }
+            return Ok(Verdict::Denied("too many failed attempts"));
+        }
+
+        let verdict = match self.protocol {
+            TunnelProtocol::Tcp => self.authorize_tcp(stream).await?,
+            TunnelProtocol::Http => self.authorize_http(stream).await?,
+        };
+
+        match verdict {
+            Verdict::Granted(_) => self.failures.reset(addr),
+            Verdict::Challenged => (),
+            Verdict::Denied(_) => self.failures.record(addr),
+        }
+        Ok(verdict)
+    }
+
+    async fn authorize_tcp<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S) -> Result<Verdict> {
+        let mut stream = Delimited::new(stream);
+        let challenge = Uuid::new_v4();
+        stream.send(ServerMessage::Challenge(challenge)).await?;
+
+        match stream.recv_timeout().await {
+            Ok(Some(ClientMessage::Authenticate(tag))) if self.auth.validate(&challenge, &tag) => {
+                stream.send(ServerMessage::Authenticated).await?;
+                Ok(Verdict::Granted(stream.into_parts().read_buf.to_vec()))
+            }
+            Ok(Some(ClientMessage::Authenticate(_))) => {
+                stream
+                    .send(ServerMessage::Error("Invalid password".to_string()))
+                    .await?;
+                Ok(Verdict::Denied("invalid password"))
+            }
+            _ => Ok(Verdict::Denied("no authentication preface")),
+        }
+    }
+
+    async fn authorize_http<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S) -> Result<Verdict> {
+        let Ok(Ok(head)) = timeout(NETWORK_TIMEOUT, read_http_head(stream)).await else {
+            return Ok(Verdict::Denied("incomplete http request"));
+        };
+
+        let credentials = String::from_utf8_lossy(&head)
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+            .and_then(|(_, value)| {
+                value
+                    .trim()
+                    .strip_prefix("Basic ")
+                    .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+            })
+            .map(|decoded| String::from_utf8_lossy(&decoded).into_owned());
+
+        let Some(credentials) = credentials else {
+            stream
+                .write_all(http_response("401 Unauthorized", BASIC_CHALLENGE, "").as_bytes())
+                .await?;
+            return Ok(Verdict::Challenged);
+        };
+
+        // The user name is ignored, only the password protects the tunnel.
+        let password = credentials
+            .split_once(':')
+            .map_or(credentials.as_str(), |(_, password)| password);
+
+        if constant_time_eq(hash_password(password).as_bytes(), self.hash.as_bytes()) {
+            Ok(Verdict::Granted(strip_authorization(&head)))
+        } else {
+            stream
+                .write_all(http_response("401 Unauthorized", BASIC_CHALLENGE, "").as_bytes())
+                .await?;
+            Ok(Verdict::Denied("invalid http credentials"))
+        }
+    }
+}
+
+/// Compare two secrets in a time that only depends on their length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The request head without its `Authorization` header, so the tunnel
+/// password never reaches the local service.
+///
+/// Only the first request on a connection is authenticated, so the head also
+/// asks for `Connection: close`, and the visitor has to authenticate the next
+/// request on a new connection. WebSocket and other upgrades keep their
+/// `Connection` header, the connection stops speaking HTTP after them.
+fn strip_authorization(head: &[u8]) -> Vec<u8> {
+    let end = head
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(head.len(), |position| position + 4);
+    let (headers, body) = head.split_at(end);
+
+    // Lower case name and value of a header line.
+    let header = |line: &[u8]| {
+        let colon = line.iter().position(|&byte| byte == b':')?;
+        let (name, value) = line.split_at(colon);
+        Some((name.trim_ascii().to_ascii_lowercase(), value[1..].trim_ascii().to_ascii_lowercase()))
+    };
+    let upgrade = headers
+        .split_inclusive(|&byte| byte == b'\n')
+        .filter_map(header)
+        .any(|(name, value)| {
+            name == b"connection" && value.split(|&byte| byte == b',').any(|token| token.trim_ascii() == b"upgrade")
+        });
+
+    let mut stripped = Vec::with_capacity(head.len() + 19);
+    for line in headers.split_inclusive(|&byte| byte == b'\n') {
+        if line == b"\r\n" && !upgrade {
+            stripped.extend_from_slice(b"Connection: close\r\n");
+        }
+        let name = header(line).map(|(name, _)| name).unwrap_or_default();
+        let keeps_alive = !upgrade && (name == b"connection" || name == b"keep-alive");
+        if name != b"authorization" && !keeps_alive {
+            stripped.extend_from_slice(line);
+        }
+    }
+    stripped.extend_from_slice(body);
+    stripped
+}
+
+/// Read from a stream until the end of an HTTP request head.
+async fn read_http_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 2048];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("connection closed during request head");
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_HTTP_HEAD {
+            bail!("request head too large");
+        }
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn strip(head: &str) -> String {
+        String::from_utf8(strip_authorization(head.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn authorization_is_stripped_in_any_casing() {
+        for name in ["Authorization", "authorization", "AUTHORIZATION"] {
+            assert_eq!(
+                strip(&format!("GET / HTTP/1.1\r\nHost: example.org\r\n{name}: Basic Omh1bnRlcjI=\r\n\r\n")),
+                "GET / HTTP/1.1\r\nHost: example.org\r\nConnection: close\r\n\r\n"
+            );
+        }
+    }
+
+    #[test]
+    fn other_headers_and_body_are_kept() {
+        assert_eq!(
+            strip(
+                "POST /form HTTP/1.1\r\nHost: example.org\r\nAuthorization: Basic Omh1bnRlcjI=\r\n\
+                 Accept: */*\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\nContent-Length: 4\r\n\r\nbody"
+            ),
+            "POST /form HTTP/1.1\r\nHost: example.org\r\nAccept: */*\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody"
+        );
+    }
+
+    #[test]
+    fn head_without_authorization_is_closed() {
+        assert_eq!(
+            strip("GET / HTTP/1.1\r\nHost: example.org\r\n\r\n"),
+            "GET / HTTP/1.1\r\nHost: example.org\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn upgrade_keeps_connection_header() {
+        assert_eq!(
+            strip("GET /ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\nauthorization: Basic x\r\n\r\n"),
+            "GET /ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn constant_time_eq_compares_content_and_length() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn address_is_blocked_after_five_failures() {
+        let attempts = FailedAttempts::default();
+        for _ in 1..MAX_FAILED_ATTEMPTS {
+            attempts.record(ADDR);
+        }
+        assert!(!attempts.is_blocked(ADDR));
+        attempts.record(ADDR);
+        assert!(attempts.is_blocked(ADDR));
+        assert!(!attempts.is_blocked(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)));
+
+        attempts.reset(ADDR);
+        assert!(!attempts.is_blocked(ADDR));
+    }
+
+    #[test]
+    fn failures_expire_after_the_window() {
+        let attempts = FailedAttempts::default();
+        let expired = Instant::now().checked_sub(FAILED_ATTEMPTS_WINDOW).unwrap();
+        attempts.0.insert(ADDR, (MAX_FAILED_ATTEMPTS, expired));
+        assert!(!attempts.is_blocked(ADDR));
+
+        // The next failure starts a new window instead of adding up.
+        attempts.record(ADDR);
+        assert_eq!(attempts.0.get(&ADDR).unwrap().0, 1);
+        assert!(!attempts.is_blocked(ADDR));
+    }
+}
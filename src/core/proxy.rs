@@ -0,0 +1,381 @@
+//! Dynamic forwarding for clients in proxy mode.
+//!
+//! Visitors of the tunnel speak SOCKS5 or HTTP CONNECT, and the client dials
+//! the requested destination in its own network if its allowlist permits it.
+//! The protocol is detected from the first byte of a connection.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Result, anyhow, bail};
+use libstrawberry::colors::{BLUE, RESET};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::time::timeout;
+
+use crate::core::acl::DestinationAcl;
+use crate::core::constants::CLIENT_LOG;
+use crate::core::output::Verbosity;
+use crate::core::shared::NETWORK_TIMEOUT;
+use crate::core::target::{LocalStream, connect_with_timeout};
+
+/// Maximum length of an HTTP CONNECT request header.
+const MAX_HEADER_LENGTH: usize = 8 * 1024;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 1;
+
+/// Proxy protocol spoken by a visitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Socks5,
+    HttpConnect,
+}
+
+/// Outcome of a proxy request, reported back to the visitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    Success,
+    Denied,
+    Unreachable,
+    CommandNotSupported,
+    AddressNotSupported,
+}
+
+impl Reply {
+    const fn socks_code(self) -> u8 {
+        match self {
+            Self::Success => 0,
+            Self::Denied => 2,
+            Self::Unreachable => 4,
+            Self::CommandNotSupported => 7,
+            Self::AddressNotSupported => 8,
+        }
+    }
+
+    const fn http_response(self) -> &'static [u8] {
+        match self {
+            Self::Success => b"HTTP/1.1 200 Connection Established\r\n\r\n",
+            Self::Denied => b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            Self::Unreachable => b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            Self::CommandNotSupported | Self::AddressNotSupported => {
+                b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            }
+        }
+    }
+}
+
+/// Reads the handshake of a visitor, starting with bytes that were already received.
+struct Handshake<'a, S> {
+    stream: &'a mut S,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshake<'_, S> {
+    /// Read more bytes from the visitor, failing on EOF.
+    async fn read_more(&mut self) -> Result<()> {
+        let mut chunk = [0; 1024];
+        let len = self.stream.read(&mut chunk).await?;
+        if len == 0 {
+            bail!("Proxy client closed the connection during the handshake");
+        }
+        self.buffer.extend_from_slice(&chunk[..len]);
+        Ok(())
+    }
+
+    /// Take the next bytes of the handshake.
+    async fn take(&mut self, len: usize) -> Result<&[u8]> {
+        while self.buffer.len() - self.position < len {
+            self.read_more().await?;
+        }
+        self.position += len;
+        Ok(&self.buffer[self.position - len..self.position])
+    }
+
+    async fn take_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1).await?[0])
+    }
+
+    async fn reply(&mut self, protocol: Protocol, reply: Reply) -> Result<()> {
+        match protocol {
+            Protocol::Socks5 => {
+                // The bound address is not meaningful through a tunnel.
+                let message = [SOCKS_VERSION, reply.socks_code(), 0, 1, 0, 0, 0, 0, 0, 0];
+                self.stream.write_all(&message).await?;
+            }
+            Protocol::HttpConnect => self.stream.write_all(reply.http_response()).await?,
+        }
+        Ok(())
+    }
+
+    /// Detect the protocol of the visitor and read its request, returning the destination.
+    async fn request(&mut self) -> Result<(Protocol, String, u16)> {
+        if self.buffer.is_empty() {
+            self.read_more().await?;
+        }
+        if self.buffer[0] == SOCKS_VERSION {
+            let (host, port) = self.socks5().await?;
+            Ok((Protocol::Socks5, host, port))
+        } else {
+            let (host, port) = self.http_connect().await?;
+            Ok((Protocol::HttpConnect, host, port))
+        }
+    }
+
+    /// Read a SOCKS5 greeting and connect request, returning the destination.
+    async fn socks5(&mut self) -> Result<(String, u16)> {
+        let _version = self.take_u8().await?;
+        let methods = usize::from(self.take_u8().await?);
+        if !self.take(methods).await?.contains(&SOCKS_NO_AUTH) {
+            self.stream.write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD]).await?;
+            bail!("SOCKS5 client does not support connecting without authentication");
+        }
+        self.stream.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH]).await?;
+
+        let &[version, command, _, address_type] = self.take(4).await? else {
+            unreachable!("took four bytes");
+        };
+        if version != SOCKS_VERSION {
+            bail!("Invalid SOCKS5 request version {version}");
+        }
+
+        let host = match address_type {
+            1 => {
+                let octets: [u8; 4] = self.take(4).await?.try_into()?;
+                Ipv4Addr::from(octets).to_string()
+            }
+            3 => {
+                let len = usize::from(self.take_u8().await?);
+                String::from_utf8(self.take(len).await?.to_vec())?
+            }
+            4 => {
+                let octets: [u8; 16] = self.take(16).await?.try_into()?;
+                Ipv6Addr::from(octets).to_string()
+            }
+            _ => {
+                self.reply(Protocol::Socks5, Reply::AddressNotSupported).await?;
+                bail!("Unsupported SOCKS5 address type {address_type}");
+            }
+        };
+        let port = u16::from_be_bytes(self.take(2).await?.try_into()?);
+
+        if command != SOCKS_CONNECT {
+            self.reply(Protocol::Socks5, Reply::CommandNotSupported).await?;
+            bail!("Unsupported SOCKS5 command {command}");
+        }
+        Ok((host, port))
+    }
+
+    /// Read an HTTP CONNECT request, returning the destination.
+    async fn http_connect(&mut self) -> Result<(String, u16)> {
+        let end = loop {
+            if let Some(end) = self.buffer[self.position..]
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+            {
+                break self.position + end + 4;
+            }
+            if self.buffer.len() - self.position > MAX_HEADER_LENGTH {
+                bail!("HTTP CONNECT request header too long");
+            }
+            self.read_more().await?;
+        };
+        let header = String::from_utf8_lossy(&self.buffer[self.position..end]).into_owned();
+        self.position = end;
+
+        let mut request_line = header.lines().next().unwrap_or_default().split_whitespace();
+        let (Some(method), Some(authority)) = (request_line.next(), request_line.next()) else {
+            bail!("Invalid HTTP request");
+        };
+        if !method.eq_ignore_ascii_case("CONNECT") {
+            self.reply(Protocol::HttpConnect, Reply::CommandNotSupported).await?;
+            bail!("Unsupported HTTP method {method}, only CONNECT is proxied");
+        }
+
+        let Some((host, port)) = authority
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        else {
+            self.reply(Protocol::HttpConnect, Reply::AddressNotSupported).await?;
+            bail!("Invalid HTTP CONNECT authority {authority}");
+        };
+        Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port))
+    }
+}
+
+/// Run the proxy handshake of a visitor on the tunnel and dial the requested
+/// destination, returning the connection to it.
+///
+/// `received` holds bytes of the visitor that were already read from the tunnel.
+pub async fn serve<S>(
+    acl: &DestinationAcl,
+    tunnel: &mut S,
+    received: &[u8],
+    verbosity: Verbosity,
+) -> Result<LocalStream>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = Handshake {
+        stream: tunnel,
+        buffer: received.to_vec(),
+        position: 0,
+    };
+
+    let (protocol, host, port) = timeout(NETWORK_TIMEOUT, handshake.request())
+        .await
+        .map_err(|_| anyhow!("Proxy client did not finish its handshake in time"))??;
+
+    let mut local_conn = match dial(acl, &host, port).await {
+        Ok(local_conn) => local_conn,
+        Err(reply) => {
+            handshake.reply(protocol, reply).await?;
+            if reply == Reply::Denied {
+                if verbosity.logs() {
+                    CLIENT_LOG.warning(format!("Proxy request to {BLUE}{host}:{port}{RESET} denied"));
+                }
+                bail!("Proxy request to {host}:{port} denied");
+            }
+            bail!("Could not connect to {host}:{port}");
+        }
+    };
+
+    if verbosity.is_verbose() {
+        CLIENT_LOG.info(format!("Proxying to {BLUE}{host}:{port}{RESET}"));
+    }
+    handshake.reply(protocol, Reply::Success).await?;
+
+    // Visitors may send data right after their request, without waiting for the reply.
+    local_conn
+        .write_all(&handshake.buffer[handshake.position..])
+        .await?;
+    Ok(Box::new(local_conn))
+}
+
+/// Connect to a destination if the allowlist permits it.
+///
+/// Host names are allowed either by name, or if they resolve to an address
+/// within an allowed network. In the latter case only the allowed addresses
+/// are dialed, so the name can't be pointed elsewhere in between.
+async fn dial(acl: &DestinationAcl, host: &str, port: u16) -> Result<TcpStream, Reply> {
+    if acl.allows(host, port) {
+        return connect_with_timeout(host, port)
+            .await
+            .map_err(|_| Reply::Unreachable);
+    }
+
+    let resolved = match timeout(NETWORK_TIMEOUT, lookup_host((host, port))).await {
+        Ok(Ok(addrs)) => addrs.filter(|addr| acl.allows_addr(*addr)).collect::<Vec<SocketAddr>>(),
+        _ => Vec::new(),
+    };
+    if resolved.is_empty() {
+        return Err(Reply::Denied);
+    }
+
+    match timeout(NETWORK_TIMEOUT, TcpStream::connect(&resolved[..])).await {
+        Ok(Ok(stream)) => Ok(stream),
+        _ => Err(Reply::Unreachable),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::core::acl::AddressRule;
+
+    /// Parse a request the visitor already sent in full.
+    async fn request(bytes: &[u8]) -> Result<(Protocol, String, u16)> {
+        let (mut stream, _visitor) = duplex(1024);
+        let mut handshake = Handshake {
+            stream: &mut stream,
+            buffer: bytes.to_vec(),
+            position: 0,
+        };
+        handshake.request().await
+    }
+
+    /// A SOCKS5 greeting without authentication and a connect request.
+    fn socks5(address: &[u8], port: u16) -> Vec<u8> {
+        let mut bytes = vec![SOCKS_VERSION, 1, SOCKS_NO_AUTH, SOCKS_VERSION, SOCKS_CONNECT, 0];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(&port.to_be_bytes());
+        bytes
+    }
+
+    /// Run a proxy handshake against an allowlist, returning what the visitor received.
+    async fn serve_request(acl: &DestinationAcl, bytes: &[u8]) -> (Result<LocalStream>, Vec<u8>) {
+        let (mut tunnel, mut visitor) = duplex(1024);
+        let result = serve(acl, &mut tunnel, bytes, Verbosity::Quiet).await;
+        drop(tunnel);
+        let mut received = Vec::new();
+        visitor.read_to_end(&mut received).await.unwrap();
+        (result, received)
+    }
+
+    #[tokio::test]
+    async fn socks5_requests() {
+        let (protocol, host, port) = request(&socks5(&[1, 10, 0, 0, 5], 22)).await.unwrap();
+        assert_eq!((protocol, host.as_str(), port), (Protocol::Socks5, "10.0.0.5", 22));
+
+        let mut domain = vec![3, 11];
+        domain.extend_from_slice(b"db.internal");
+        let (_, host, port) = request(&socks5(&domain, 5432)).await.unwrap();
+        assert_eq!((host.as_str(), port), ("db.internal", 5432));
+
+        let mut ipv6 = vec![4];
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        let (_, host, port) = request(&socks5(&ipv6, 443)).await.unwrap();
+        assert_eq!((host.as_str(), port), ("::1", 443));
+
+        assert!(request(&socks5(&[9], 80)).await.is_err());
+        assert!(request(&[SOCKS_VERSION, 1, 2]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn http_connect_requests() {
+        let (protocol, host, port) = request(b"CONNECT db.internal:5432 HTTP/1.1\r\nHost: db.internal:5432\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!((protocol, host.as_str(), port), (Protocol::HttpConnect, "db.internal", 5432));
+
+        let (_, host, port) = request(b"CONNECT [::1]:443 HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!((host.as_str(), port), ("::1", 443));
+
+        assert!(request(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(request(b"CONNECT db.internal HTTP/1.1\r\n\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn allowed_destination_is_dialed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acl = DestinationAcl::new(vec![AddressRule::parse("127.0.0.1:*").unwrap()]);
+
+        let (result, received) = serve_request(&acl, &socks5(&[1, 127, 0, 0, 1], port)).await;
+        assert!(result.is_ok());
+        assert_eq!(received, [SOCKS_VERSION, SOCKS_NO_AUTH, SOCKS_VERSION, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let request = format!("CONNECT 127.0.0.1:{port} HTTP/1.1\r\n\r\n");
+        let (result, received) = serve_request(&acl, request.as_bytes()).await;
+        assert!(result.is_ok());
+        assert_eq!(received, Reply::Success.http_response());
+    }
+
+    #[tokio::test]
+    async fn denied_destination_is_refused() {
+        let acl = DestinationAcl::new(vec![AddressRule::parse("10.0.0.0/8:*").unwrap()]);
+
+        let (result, received) = serve_request(&acl, &socks5(&[1, 127, 0, 0, 1], 22)).await;
+        assert!(result.is_err());
+        assert_eq!(received[2..4], [SOCKS_VERSION, Reply::Denied.socks_code()]);
+
+        let (result, received) = serve_request(&acl, b"CONNECT 127.0.0.1:22 HTTP/1.1\r\n\r\n").await;
+        assert!(result.is_err());
+        assert_eq!(received, Reply::Denied.http_response());
+    }
+}
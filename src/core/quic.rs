@@ -0,0 +1,401 @@
+//! QUIC transport between clients and the server.
+//!
+//! A client keeps a single QUIC connection to the server. The control
+//! connection and every forwarded connection are bidirectional streams on it,
+//! so forwarding a connection needs no new handshake and a lost packet only
+//! stalls its own stream. The connection migrates when the address of the
+//! client changes, keeping its tunnel open.
+//!
+//! The server uses the configured certificate or generates a self-signed one.
+//! Clients verify it against the system's root certificates, or pin its
+//! fingerprint with `quic://host:port?fingerprint=<sha256>`. A self-signed
+//! certificate can't be verified, so clients of such a server need the pin.
+
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, ensure};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use quinn::rustls::crypto::{
+    WebPkiSupportedAlgorithms, aws_lc_rs, verify_tls12_signature, verify_tls13_signature,
+};
+use quinn::rustls::pki_types::pem::PemObject;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use quinn::rustls::{self, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use quinn::{Connection, IdleTimeout, Incoming, RecvStream, SendStream, TransportConfig, VarInt};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::lookup_host;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::core::shared::NETWORK_TIMEOUT;
+use crate::core::transport::TransportStream;
+
+/// Application protocol negotiated during the TLS handshake.
+const ALPN: &[u8] = b"tunneled";
+
+/// First byte of every stream a client opens.
+///
+/// The server only learns about a stream once data arrives on it, but it
+/// speaks first when authenticating clients.
+const STREAM_PREFACE: u8 = 1;
+
+/// Interval of keep-alive packets, so lost paths are noticed quickly.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time without packets after which a connection is considered lost.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Streams a client may have open at once, i.e. concurrent forwarded connections.
+const MAX_STREAMS: u32 = 4096;
+
+/// Certificate chain and private key of the server, both PEM files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuicCertificate {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// QUIC endpoint of the server.
+pub struct QuicListener {
+    endpoint: quinn::Endpoint,
+
+    /// SHA-256 fingerprint of the server certificate.
+    fingerprint: String,
+
+    /// Whether the certificate was generated on startup.
+    self_signed: bool,
+}
+
+impl QuicListener {
+    /// Listen on a UDP port, with a self-signed certificate if none is given.
+    pub fn bind(port: u16, certificate: Option<&QuicCertificate>) -> Result<Self> {
+        let (chain, key) = if let Some(certificate) = certificate {
+            (
+                CertificateDer::pem_file_iter(&certificate.cert)
+                    .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+                    .with_context(|| format!("Could not read certificate {}", certificate.cert.display()))?,
+                PrivateKeyDer::from_pem_file(&certificate.key)
+                    .with_context(|| format!("Could not read private key {}", certificate.key.display()))?,
+            )
+        } else {
+            let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+            (
+                vec![generated.cert.der().clone()],
+                PrivatePkcs8KeyDer::from(generated.signing_key.serialize_der()).into(),
+            )
+        };
+        let fingerprint = chain
+            .first()
+            .map(|cert| hex::encode(Sha256::digest(cert)))
+            .ok_or_else(|| anyhow!("Certificate file contains no certificate"))?;
+
+        let mut tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+        config.transport_config(transport_config());
+
+        let endpoint = quinn::Endpoint::server(config, SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+            .with_context(|| format!("Could not listen on UDP port {port}"))?;
+        Ok(Self {
+            endpoint,
+            fingerprint,
+            self_signed: certificate.is_none(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// SHA-256 fingerprint of the server certificate, as hex.
+    #[must_use]
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Whether the certificate was generated on startup.
+    #[must_use]
+    pub const fn is_self_signed(&self) -> bool {
+        self.self_signed
+    }
+
+    /// Wait for the next client, `None` once the endpoint is closed.
+    pub async fn accept(&self) -> Option<Incoming> {
+        self.endpoint.accept().await
+    }
+}
+
+/// Client side of the QUIC connection, shared by the control and forwarded connections.
+pub struct QuicSession {
+    host: String,
+    port: u16,
+
+    /// Pinned SHA-256 fingerprint of the server certificate.
+    fingerprint: Option<Vec<u8>>,
+
+    /// Connection to the server, established with the first stream.
+    connection: Mutex<Option<Connection>>,
+}
+
+impl fmt::Debug for QuicSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicSession")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish_non_exhaustive()
+    }
+}
+
+impl QuicSession {
+    #[must_use]
+    pub const fn new(host: String, port: u16, fingerprint: Option<Vec<u8>>) -> Self {
+        Self {
+            host,
+            port,
+            fingerprint,
+            connection: Mutex::const_new(None),
+        }
+    }
+
+    #[must_use]
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Open a new stream to the server, connecting first if needed.
+    pub async fn open(&self) -> Result<TransportStream> {
+        let connection = {
+            let mut current = self.connection.lock().await;
+            match &*current {
+                Some(connection) if connection.close_reason().is_none() => connection.clone(),
+                _ => {
+                    let connection = self.connect().await?;
+                    *current = Some(connection.clone());
+                    connection
+                }
+            }
+        };
+
+        let (mut send, recv) = connection.open_bi().await?;
+        send.write_all(&[STREAM_PREFACE]).await?;
+        Ok(Box::new(QuicStream { send, recv }))
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let addr = lookup_host((self.host.as_str(), self.port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Could not resolve {}", self.host))?;
+        let local = if addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        let mut endpoint = quinn::Endpoint::client(local)?;
+        endpoint.set_default_client_config(self.client_config()?);
+        timeout(NETWORK_TIMEOUT, endpoint.connect(addr, &self.host)?)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result.map_err(anyhow::Error::from))
+            .with_context(|| {
+                let hint = if self.fingerprint.is_none() {
+                    ", pin the fingerprint the server logs if its certificate is self-signed"
+                } else {
+                    ""
+                };
+                format!("QUIC handshake with {}:{} failed{hint}", self.host, self.port)
+            })
+    }
+
+    fn client_config(&self) -> Result<quinn::ClientConfig> {
+        let builder = rustls::ClientConfig::builder();
+        let mut tls = if let Some(fingerprint) = &self.fingerprint {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                    fingerprint: fingerprint.clone(),
+                    algorithms: aws_lc_rs::default_provider().signature_verification_algorithms,
+                }))
+                .with_no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+        config.transport_config(transport_config());
+        Ok(config)
+    }
+}
+
+/// Parse a certificate fingerprint, hex with optional colons.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<Vec<u8>> {
+    let fingerprint = hex::decode(fingerprint.replace(':', "")).context("Invalid certificate fingerprint")?;
+    ensure!(fingerprint.len() == 32, "Certificate fingerprint must be a SHA-256 hash");
+    Ok(fingerprint)
+}
+
+/// Stream on a QUIC connection.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    /// Accept the next stream a client opens, `None` once the connection is closed.
+    pub async fn accept(connection: &Connection) -> Option<Self> {
+        let (send, recv) = connection.accept_bi().await.ok()?;
+        Some(Self { send, recv })
+    }
+
+    /// Read the preface of a stream opened by a client.
+    pub async fn preface(mut self) -> Result<TransportStream> {
+        let mut preface = [0];
+        timeout(NETWORK_TIMEOUT, self.recv.read_exact(&mut preface))
+            .await
+            .context("Stream preface timed out")??;
+        ensure!(preface[0] == STREAM_PREFACE, "Invalid stream preface");
+        Ok(Box::new(self))
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+/// Accepts exactly the server certificate with the pinned fingerprint.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: Vec<u8>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_idle_timeout(IdleTimeout::try_from(IDLE_TIMEOUT).ok())
+        .max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS));
+    Arc::new(transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::shared::{ClientMessage, Delimited};
+
+    #[tokio::test]
+    async fn hello_over_loopback_with_pinned_fingerprint() {
+        let listener = QuicListener::bind(0, None).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let fingerprint = parse_fingerprint(listener.fingerprint()).unwrap();
+
+        let server = tokio::spawn(async move {
+            let connection = listener.accept().await.unwrap().await.unwrap();
+            let stream = QuicStream::accept(&connection).await.unwrap().preface().await.unwrap();
+            Delimited::new(stream).recv::<ClientMessage>().await.unwrap()
+        });
+
+        let session = QuicSession::new("127.0.0.1".to_string(), port, Some(fingerprint));
+        let mut stream = Delimited::new(session.open().await.unwrap());
+        stream.send(ClientMessage::Hello(0, None, None)).await.unwrap();
+        assert!(matches!(server.await.unwrap(), Some(ClientMessage::Hello(0, None, None))));
+    }
+
+    #[tokio::test]
+    async fn self_signed_certificate_needs_pin() {
+        let listener = QuicListener::bind(0, None).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Some(incoming) = listener.accept().await {
+                let _ = incoming.await;
+            }
+        });
+
+        let session = QuicSession::new("127.0.0.1".to_string(), port, None);
+        assert!(session.open().await.is_err());
+
+        let wrong = QuicSession::new("127.0.0.1".to_string(), port, Some(vec![0; 32]));
+        assert!(wrong.open().await.is_err());
+    }
+}
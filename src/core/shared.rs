@@ -3,22 +3,129 @@
 use anyhow::{Context, Result};
 use futures_util::{SinkExt, StreamExt};
 use libstrawberry::colors::{BOLD, C_RESET, RED};
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+use std::fmt;
+use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::time::timeout;
+use tokio::time::{Interval, timeout};
 use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
 use tracing::trace;
 use uuid::Uuid;
 
+use crate::core::acl::SourceFilter;
 use crate::core::auth::authenticator::StrawberryIdAuthenticator;
+use crate::core::compression::Compression;
 
 /// Maximum byte length for a JSON frame in the stream.
-pub const MAX_FRAME_LENGTH: usize = 256;
+///
+/// Large enough for a hello with tunnel options, i.e. a password hash, a
+/// share name and a few source networks. Peers without tunnel options read
+/// at most 256 bytes, which a plain [`ClientMessage::Hello`] still fits in.
+pub const MAX_FRAME_LENGTH: usize = 1024;
 
 /// Timeout for network connections and initial protocol messages.
 pub const NETWORK_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Application protocol spoken through a tunnel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelProtocol {
+    /// Opaque TCP traffic.
+    #[default]
+    Tcp,
+
+    /// HTTP traffic, which allows HTTP specific handling on the server.
+    Http,
+}
+
+/// Additional settings of a tunnel, requested by the client in its hello message.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TunnelOptions {
+    /// Hex encoded SHA-256 hash of the password protecting a private tunnel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    /// Application protocol of the tunnel.
+    pub protocol: TunnelProtocol,
+
+    /// Requested maximum lifetime of the tunnel in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+
+    /// Name under which several clients serve the same tunnel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share: Option<String>,
+
+    /// Share of the connections of a shared tunnel, 0 makes the client a standby.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+
+    /// Requested compression of forwarded connections.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+
+    /// Networks visitors may or may not connect from.
+    #[serde(skip_serializing_if = "SourceFilter::is_empty")]
+    pub sources: SourceFilter,
+
+    /// Announce connections with the address of the visitor, see [`ServerMessage::ConnectionFrom`].
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub peers: bool,
+}
+
+impl TunnelOptions {
+    /// Whether no option is set, so a plain hello is enough.
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        self.password.is_none()
+            && self.protocol == TunnelProtocol::Tcp
+            && self.ttl.is_none()
+            && self.share.is_none()
+            && self.weight.is_none()
+            && self.compression.is_none()
+            && self.sources.is_empty()
+            && !self.peers
+    }
+}
+
+/// Destination of a local forwarding connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ForwardTarget {
+    /// Host and port reachable from the server.
+    Address(String, u16),
+
+    /// Shared tunnel of another client, by name.
+    Tunnel(String),
+}
+
+impl ForwardTarget {
+    /// Parse a target like `host:port`, `[::1]:port` or `tunnel:<name>`.
+    #[must_use]
+    pub fn parse(input: &str) -> Option<Self> {
+        if let Some(name) = input.strip_prefix("tunnel:") {
+            return (!name.is_empty()).then(|| Self::Tunnel(name.to_string()));
+        }
+        let (host, port) = input.rsplit_once(':')?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self::Address(host.to_string(), port.parse().ok()?))
+    }
+}
+
+impl fmt::Display for ForwardTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(host, port) if host.contains(':') => write!(f, "[{host}]:{port}"),
+            Self::Address(host, port) => write!(f, "{host}:{port}"),
+            Self::Tunnel(name) => write!(f, "tunnel:{name}"),
+        }
+    }
+}
+
 /// A message from the client on the control connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
@@ -30,6 +137,20 @@ pub enum ClientMessage {
 
     /// Accepts an incoming TCP connection, using this stream as a proxy.
     Accept(Uuid),
+
+    /// Reports whether the local service is healthy.
+    Health(bool),
+
+    /// Asks the server to connect this stream to a target, for local forwarding,
+    /// with the Strawberry ID of the client if the server requires one.
+    Forward(ForwardTarget, Option<StrawberryIdAuthenticator>),
+
+    /// Measures the round trip time of the control connection, answered with a pong.
+    Ping(u64),
+
+    /// Like [`Self::Hello`], with tunnel options. Only sent if any option is
+    /// set, so servers without tunnel options still understand plain tunnels.
+    HelloWithOptions(u16, Option<StrawberryIdAuthenticator>, Option<u16>, TunnelOptions),
 }
 
 /// A message from the server on the control connection.
@@ -38,6 +159,9 @@ pub enum ServerMessage {
     /// Authentication challenge, sent as the first message, if enabled.
     Challenge(Uuid),
 
+    /// Confirms a successful authentication on a private tunnel.
+    Authenticated,
+
     /// Response to a client's initial message, with actual public port.
     Hello(String, u16),
 
@@ -47,8 +171,92 @@ pub enum ServerMessage {
     /// Asks the client to accept a forwarded TCP connection.
     Connection(Uuid),
 
+    /// Like [`Self::Connection`], with the address of the visitor, if the client asked for it.
+    ConnectionFrom(Uuid, SocketAddr),
+
     /// Indicates a server error that terminates the connection.
     Error(String),
+
+    /// The tunnel reached its lifetime or idle limit and is closed.
+    Expired(String),
+
+    /// Confirms a forward request, the stream carries raw data from now on.
+    Forwarding,
+
+    /// Confirms the requested compression, sent right before the hello message.
+    Compression(Compression),
+
+    /// Answers a ping of the client.
+    Pong(u64),
+}
+
+/// Parse a human readable duration like `90`, `30s`, `15m`, `2h` or `7d`.
+///
+/// Plain numbers are interpreted as seconds.
+#[must_use]
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let input = input.trim();
+    let (value, unit) = input
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((input, ""), |index| input.split_at(index));
+    let value: u64 = value.parse().ok()?;
+
+    let seconds = match unit.trim() {
+        "" | "s" => value,
+        "m" => value.checked_mul(60)?,
+        "h" => value.checked_mul(60 * 60)?,
+        "d" => value.checked_mul(24 * 60 * 60)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+/// Format a duration in the largest unit that represents it exactly.
+#[must_use]
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0 => format!("{}ms", duration.as_millis()),
+        s if s % (24 * 60 * 60) == 0 => format!("{}d", s / (24 * 60 * 60)),
+        s if s % (60 * 60) == 0 => format!("{}h", s / (60 * 60)),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Deserialize an optional duration from a string like `2h` or a number of seconds.
+pub fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(u64),
+        Text(String),
+    }
+
+    match Option::<Raw>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Raw::Seconds(seconds)) => Ok(Some(Duration::from_secs(seconds))),
+        Some(Raw::Text(text)) => parse_duration(&text)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid duration '{text}'"))),
+    }
+}
+
+/// Build a minimal HTTP response with a plain text body.
+#[must_use]
+pub fn http_response(status: &str, headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\n{headers}Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Wait for the next tick of an optional interval, never completing without one.
+pub async fn tick(interval: Option<&mut Interval>) -> tokio::time::Instant {
+    match interval {
+        Some(interval) => interval.tick().await,
+        None => std::future::pending().await,
+    }
 }
 
 /// Transport stream with JSON frames delimited by null characters.
@@ -0,0 +1,81 @@
+//! Local services that tunneled connections are forwarded to.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::core::shared::NETWORK_TIMEOUT;
+
+/// Any bidirectional stream to a local service.
+pub trait LocalIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> LocalIo for T {}
+
+/// Connection to a local service, independent of its transport.
+pub type LocalStream = Box<dyn LocalIo>;
+
+/// Address of the local service behind a tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalTarget {
+    /// Service listening on a TCP port.
+    Tcp { host: String, port: u16 },
+
+    /// Service listening on a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl LocalTarget {
+    /// Open a new connection to the local service.
+    pub async fn connect(&self) -> Result<LocalStream> {
+        match self {
+            Self::Tcp { host, port } => Ok(Box::new(connect_with_timeout(host, *port).await?)),
+            Self::Unix(path) => connect_unix(path).await,
+        }
+    }
+
+    /// Host name used for requests to the local service, e.g. in health checks.
+    #[must_use]
+    pub fn host(&self) -> &str {
+        match self {
+            Self::Tcp { host, .. } => host,
+            Self::Unix(_) => "localhost",
+        }
+    }
+}
+
+impl fmt::Display for LocalTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp { host, port } => write!(f, "{host}:{port}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Open a TCP connection, giving up after [`NETWORK_TIMEOUT`].
+pub async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
+    match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
+        Ok(res) => res,
+        Err(err) => Err(err.into()),
+    }
+    .with_context(|| format!("Could not connect to {to}:{port}"))
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &std::path::Path) -> Result<LocalStream> {
+    let stream = match timeout(NETWORK_TIMEOUT, tokio::net::UnixStream::connect(path)).await {
+        Ok(res) => res,
+        Err(err) => Err(err.into()),
+    }
+    .with_context(|| format!("Could not connect to {}", path.display()))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &std::path::Path) -> Result<LocalStream> {
+    anyhow::bail!("Unix domain sockets are not supported on this platform")
+}
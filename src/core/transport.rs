@@ -0,0 +1,315 @@
+//! Transports between clients and the server.
+//!
+//! Besides plain TCP on the control port, clients can reach the server with a
+//! WebSocket upgrade, e.g. in networks that only let HTTP(S) through or when
+//! the server sits behind a reverse proxy. The framed protocol and forwarded
+//! data then run unchanged inside binary WebSocket messages. On lossy links,
+//! QUIC avoids a handshake per connection, see [`crate::core::quic`].
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll, ready};
+
+use anyhow::{Context, Result, anyhow, bail};
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderMap;
+use tokio_tungstenite::tungstenite::{Bytes, Error as WsError, Message};
+use tokio_tungstenite::{WebSocketStream, accept_hdr_async, client_async_tls};
+
+use crate::core::acl::Cidr;
+use crate::core::outbound::{OutboundProxy, connect_via};
+use crate::core::quic::{QuicSession, parse_fingerprint};
+use crate::core::shared::NETWORK_TIMEOUT;
+/// Any bidirectional stream between a client and the server.
+///
+/// Unlike local connections, these are kept in the server's shared state and
+/// have to be `Sync` as well.
+pub trait TransportIo: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> TransportIo for T {}
+
+/// Connection between a client and the server, independent of its transport.
+pub type TransportStream = Box<dyn TransportIo>;
+
+/// Transport a client connects to the server with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+    Quic,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "TCP"),
+            Self::WebSocket => write!(f, "WebSocket"),
+            Self::Quic => write!(f, "QUIC"),
+        }
+    }
+}
+
+/// How a client reaches the server.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    /// Plain TCP on the control port.
+    Tcp { host: String, port: u16 },
+
+    /// WebSocket upgrade at a `ws://` or `wss://` URL.
+    WebSocket { url: String, host: String, port: u16 },
+
+    /// Streams on a single QUIC connection, from a `quic://` URL.
+    Quic(Arc<QuicSession>),
+}
+
+impl Endpoint {
+    /// Parse the server of a client, a host name or a WebSocket or QUIC URL.
+    ///
+    /// WebSocket URLs default to port 80 or 443, the others to the control port.
+    pub fn parse(server: &str, control_port: u16) -> Result<Self> {
+        let Some((scheme, rest)) = server.split_once("://") else {
+            return Ok(Self::Tcp {
+                host: server.to_string(),
+                port: control_port,
+            });
+        };
+        let default_port = match scheme {
+            "ws" => 80,
+            "wss" => 443,
+            "quic" => control_port,
+            _ => bail!("Unsupported server scheme '{scheme}' (expected ws, wss or quic)"),
+        };
+
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let authority = rest.split(['/', '#']).next().unwrap_or_default();
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => bracketed
+                .split_once(']')
+                .map(|(host, port)| (host, port.strip_prefix(':')))
+                .ok_or_else(|| anyhow!("Invalid server address '{authority}'"))?,
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            bail!("Server URL '{server}' has no host");
+        }
+        let port = match port {
+            Some(port) => port.parse().context("Invalid server port")?,
+            None => default_port,
+        };
+
+        if scheme == "quic" {
+            let fingerprint = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("fingerprint="))
+                .map(parse_fingerprint)
+                .transpose()?;
+            return Ok(Self::Quic(Arc::new(QuicSession::new(host.to_string(), port, fingerprint))));
+        }
+
+        Ok(Self::WebSocket {
+            url: server.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Host name or address of the server.
+    #[must_use]
+    pub fn host(&self) -> &str {
+        match self {
+            Self::Tcp { host, .. } | Self::WebSocket { host, .. } => host,
+            Self::Quic(session) => session.host(),
+        }
+    }
+
+    #[must_use]
+    pub const fn transport(&self) -> Transport {
+        match self {
+            Self::Tcp { .. } => Transport::Tcp,
+            Self::WebSocket { .. } => Transport::WebSocket,
+            Self::Quic(_) => Transport::Quic,
+        }
+    }
+
+    /// Open a connection to the server, through the proxy unless it is bypassed.
+    pub async fn connect(&self, proxy: Option<&OutboundProxy>) -> Result<TransportStream> {
+        match self {
+            Self::Tcp { host, port } => Ok(Box::new(connect_via(proxy, host, *port).await?)),
+            Self::WebSocket { url, host, port } => {
+                let stream = connect_via(proxy, host, *port).await?;
+                let (socket, _) = timeout(NETWORK_TIMEOUT, client_async_tls(url.as_str(), stream))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result.map_err(anyhow::Error::from))
+                    .with_context(|| format!("WebSocket handshake with {url} failed"))?;
+                Ok(Box::new(WebSocket::new(socket)))
+            }
+            Self::Quic(session) => {
+                if let Some(proxy) = proxy.filter(|proxy| !proxy.bypasses(session.host())) {
+                    bail!("QUIC can't be used through proxy {proxy}, use --proxy none");
+                }
+                session.open().await
+            }
+        }
+    }
+}
+
+/// Complete the WebSocket handshake of a client connecting to the server.
+///
+/// Any request path is accepted, so a reverse proxy can forward a path of its
+/// choice. If the connection comes from one of the trusted proxies, the
+/// address of the client is taken from the headers the proxies added.
+pub async fn accept(stream: TcpStream, trusted_proxies: &[Cidr]) -> Result<(TransportStream, Option<IpAddr>)> {
+    let mut client = None;
+    // The error type is given by tungstenite, it is never returned here.
+    #[allow(clippy::result_large_err)]
+    let read_client = |request: &Request, response: Response| {
+        if !trusted_proxies.is_empty() {
+            client = forwarded_client(request.headers(), trusted_proxies);
+        }
+        Ok(response)
+    };
+    let socket = timeout(NETWORK_TIMEOUT, accept_hdr_async(stream, read_client))
+        .await
+        .context("WebSocket handshake timed out")?
+        .context("WebSocket handshake failed")?;
+    Ok((Box::new(WebSocket::new(socket)), client))
+}
+
+/// Address of a client behind trusted proxies, from the `Forwarded` header or
+/// else `X-Forwarded-For`. Every proxy appends the address it got the request
+/// from, so the client is the last address that is not a trusted proxy.
+fn forwarded_client(headers: &HeaderMap, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let join = |name: &str| {
+        let values = headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().ok())
+            .collect::<Option<Vec<_>>>()?;
+        (!values.is_empty()).then(|| values.join(","))
+    };
+    let addresses = if let Some(forwarded) = join("forwarded") {
+        forwarded
+            .split(',')
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| value.trim_matches('"').to_string())
+                })
+            })
+            .collect::<Option<Vec<_>>>()?
+    } else {
+        join("x-forwarded-for")?
+            .split(',')
+            .map(|address| address.trim().to_string())
+            .collect()
+    };
+
+    let mut client = None;
+    for address in addresses.iter().rev() {
+        // Addresses may carry a port, IPv6 ones are then in brackets.
+        let ip = address
+            .parse::<IpAddr>()
+            .ok()
+            .or_else(|| address.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+            .or_else(|| address.strip_prefix('[')?.strip_suffix(']')?.parse().ok())?;
+        client = Some(ip);
+        if !trusted_proxies.iter().any(|proxy| proxy.contains(ip)) {
+            break;
+        }
+    }
+    client
+}
+
+/// Byte stream over binary WebSocket messages.
+struct WebSocket<S> {
+    socket: WebSocketStream<S>,
+
+    /// Rest of the last message that didn't fit into the read buffer.
+    pending: Bytes,
+}
+
+impl<S> WebSocket<S> {
+    const fn new(socket: WebSocketStream<S>) -> Self {
+        Self {
+            socket,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if !self.pending.is_empty() {
+                let len = self.pending.len().min(buf.remaining());
+                buf.put_slice(&self.pending.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.socket).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.pending = data,
+                // Pings are answered by the WebSocket implementation itself.
+                Some(Ok(Message::Text(_) | Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => (),
+                Some(Ok(Message::Close(_)) | Err(WsError::ConnectionClosed | WsError::AlreadyClosed)) | None => {
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Err(err)) => return Poll::Ready(Err(into_io_error(err))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut socket = Pin::new(&mut self.socket);
+        ready!(socket.as_mut().poll_ready(cx)).map_err(into_io_error)?;
+        socket
+            .as_mut()
+            .start_send(Message::binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+
+        // Messages are only buffered until flushed, but not every writer flushes.
+        if let Poll::Ready(Err(err)) = socket.poll_flush(cx) {
+            return Poll::Ready(Err(into_io_error(err)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx).map_err(into_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.socket).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(err) => Poll::Ready(Err(into_io_error(err))),
+        }
+    }
+}
+
+fn into_io_error(err: WsError) -> io::Error {
+    match err {
+        WsError::Io(err) => err,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => io::ErrorKind::BrokenPipe.into(),
+        err => io::Error::other(err),
+    }
+}
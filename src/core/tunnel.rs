@@ -0,0 +1,394 @@
+//! Tunnels on the server and the clients serving them.
+//!
+//! A tunnel owns the listener for external connections. Usually a single
+//! client serves it, but clients registering the same shared name join one
+//! tunnel, and its connections are spread across them by weight.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use libstrawberry::colors::{C_RESET, CYAN};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::AbortHandle;
+
+use crate::core::acl::SourceFilter;
+use crate::core::compression::Compression;
+use crate::core::constants::CLIENT_LOG;
+use crate::core::private::TunnelGate;
+use crate::core::shared::TunnelProtocol;
+use crate::core::transport::TransportStream;
+
+/// External connection waiting to be accepted by a client.
+pub struct PendingConnection {
+    /// Connection of the external peer.
+    pub stream: TransportStream,
+
+    /// Address of the external peer.
+    pub peer: SocketAddr,
+
+    /// Bytes already read from the peer, e.g. while authenticating it.
+    pub buffer: Vec<u8>,
+
+    /// Port of the tunnel the connection arrived on.
+    pub port: u16,
+
+    /// Activity of the tunnel the connection belongs to.
+    pub activity: Arc<TunnelActivity>,
+
+    /// Compression negotiated with the client the connection is handed to.
+    pub compression: Option<Compression>,
+}
+
+/// Activity of a tunnel, used to detect idle tunnels.
+pub struct TunnelActivity {
+    /// Number of forwarded connections that are currently open.
+    active: AtomicUsize,
+
+    /// Time of the last external connection.
+    last_seen: Mutex<Instant>,
+}
+
+impl Default for TunnelActivity {
+    fn default() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            last_seen: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl TunnelActivity {
+    pub fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since the tunnel was last used, if no connection is open.
+    pub fn idle_for(&self) -> Option<Duration> {
+        (self.active.load(Ordering::SeqCst) == 0).then(|| self.last_seen.lock().unwrap().elapsed())
+    }
+}
+
+/// Marks a forwarded connection as active for as long as it lives.
+pub struct ActiveConnection(Arc<TunnelActivity>);
+
+impl ActiveConnection {
+    #[must_use]
+    pub fn new(activity: Arc<TunnelActivity>) -> Self {
+        activity.active.fetch_add(1, Ordering::SeqCst);
+        Self(activity)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Settings that all clients of a shared tunnel have to agree on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelIdentity {
+    /// Hex encoded password hash of a private tunnel.
+    pub password: Option<String>,
+
+    /// Application protocol of the tunnel.
+    pub protocol: TunnelProtocol,
+
+    /// Strawberry ID user that registered the tunnel.
+    pub owner: Option<String>,
+
+    /// Networks visitors may connect from.
+    pub sources: SourceFilter,
+}
+
+/// A client serving a tunnel.
+struct Member {
+    id: u64,
+
+    /// Share of connections, members with weight 0 are only used as standby.
+    weight: u32,
+
+    /// Running score of the smooth weighted round-robin.
+    current: i64,
+
+    /// Last health state reported by the client.
+    healthy: bool,
+
+    /// Hands external connections to the client's control connection.
+    tx: UnboundedSender<PendingConnection>,
+}
+
+/// A tunnel with its clients.
+pub struct Tunnel {
+    /// Name under which clients share the tunnel.
+    pub name: Option<String>,
+
+    /// Address the tunnel listens on.
+    pub addr: SocketAddr,
+
+    /// Settings every client of the tunnel has to match.
+    pub identity: TunnelIdentity,
+
+    /// Activity of the tunnel, shared by all of its clients.
+    pub activity: Arc<TunnelActivity>,
+
+    /// Authenticates visitors of a private tunnel.
+    pub gate: Option<Arc<TunnelGate>>,
+
+    members: Mutex<Vec<Member>>,
+    next_id: AtomicU64,
+
+    /// Task accepting external connections, stopped with the tunnel.
+    acceptor: Mutex<Option<AbortHandle>>,
+}
+
+impl Tunnel {
+    #[must_use]
+    pub fn new(
+        name: Option<String>,
+        addr: SocketAddr,
+        identity: TunnelIdentity,
+        gate: Option<Arc<TunnelGate>>,
+    ) -> Self {
+        Self {
+            name,
+            addr,
+            identity,
+            activity: Arc::new(TunnelActivity::default()),
+            gate,
+            members: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+            acceptor: Mutex::new(None),
+        }
+    }
+
+    /// Attach the task accepting external connections, which is aborted once
+    /// the last client left.
+    pub fn set_acceptor(&self, acceptor: AbortHandle) {
+        *self.acceptor.lock().unwrap() = Some(acceptor);
+    }
+
+    /// Add a client to the tunnel.
+    #[must_use]
+    pub fn join(self: &Arc<Self>, weight: u32) -> Membership {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.members.lock().unwrap().push(Member {
+            id,
+            weight,
+            current: 0,
+            healthy: true,
+            tx,
+        });
+        Membership {
+            tunnel: Arc::clone(self),
+            id,
+            rx,
+        }
+    }
+
+    /// Number of clients serving the tunnel.
+    pub fn members(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    /// Whether any client can take a connection.
+    pub fn is_available(&self) -> bool {
+        self.members.lock().unwrap().iter().any(|member| member.healthy)
+    }
+
+    /// Hand an external connection to a healthy client, chosen by weight.
+    ///
+    /// Returns the connection if no client can take it.
+    pub fn dispatch(&self, connection: PendingConnection) -> Result<(), PendingConnection> {
+        let mut members = self.members.lock().unwrap();
+
+        // Standby members only take connections if no weighted member is healthy.
+        let standby = !members.iter().any(|member| member.healthy && member.weight > 0);
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+
+        for i in 0..members.len() {
+            let member = &mut members[i];
+            if !member.healthy || (member.weight == 0 && !standby) {
+                continue;
+            }
+            let weight = i64::from(member.weight.max(1));
+            member.current += weight;
+            total += weight;
+            if best.is_none_or(|best| members[i].current > members[best].current) {
+                best = Some(i);
+            }
+        }
+
+        let Some(best) = best else {
+            return Err(connection);
+        };
+        members[best].current -= total;
+        members[best].tx.send(connection).map_err(|err| err.0)
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Some(acceptor) = self.acceptor.get_mut().unwrap().take() {
+            acceptor.abort();
+        }
+    }
+}
+
+/// A client's place in a tunnel, which it leaves when this is dropped.
+pub struct Membership {
+    tunnel: Arc<Tunnel>,
+    id: u64,
+    rx: UnboundedReceiver<PendingConnection>,
+}
+
+impl Membership {
+    /// Wait for the next external connection handed to this client.
+    pub async fn recv(&mut self) -> Option<PendingConnection> {
+        self.rx.recv().await
+    }
+
+    /// Record the health reported by the client, returning whether it changed.
+    #[must_use]
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        self.tunnel
+            .members
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|member| member.id == self.id)
+            .is_some_and(|member| std::mem::replace(&mut member.healthy, healthy) != healthy)
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let remaining = {
+            let mut members = self.tunnel.members.lock().unwrap();
+            members.retain(|member| member.id != self.id);
+            members.len()
+        };
+
+        // Fail over connections that were not handed to the client yet.
+        self.rx.close();
+        while let Ok(connection) = self.rx.try_recv() {
+            let _ = self.tunnel.dispatch(connection);
+        }
+
+        if let Some(name) = &self.tunnel.name {
+            CLIENT_LOG.info(format!(
+                "Client left shared tunnel '{CYAN}{name}{C_RESET}', {remaining} clients remaining"
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tunnel() -> Arc<Tunnel> {
+        let identity = TunnelIdentity {
+            password: None,
+            protocol: TunnelProtocol::Tcp,
+            owner: None,
+            sources: SourceFilter::default(),
+        };
+        Arc::new(Tunnel::new(Some("demo".to_string()), SocketAddr::from(([127, 0, 0, 1], 40000)), identity, None))
+    }
+
+    /// An external connection, told apart by the port of its peer.
+    fn connection(tunnel: &Tunnel, peer_port: u16) -> PendingConnection {
+        PendingConnection {
+            stream: Box::new(tokio::io::duplex(64).0),
+            peer: SocketAddr::from(([203, 0, 113, 7], peer_port)),
+            buffer: Vec::new(),
+            port: tunnel.addr.port(),
+            activity: Arc::clone(&tunnel.activity),
+            compression: None,
+        }
+    }
+
+    /// Peer ports of the connections handed to a member so far.
+    fn received(member: &mut Membership) -> Vec<u16> {
+        std::iter::from_fn(|| member.rx.try_recv().ok()).map(|connection| connection.peer.port()).collect()
+    }
+
+    #[test]
+    fn connections_are_spread_by_weight() {
+        let tunnel = tunnel();
+        let mut heavy = tunnel.join(3);
+        let mut light = tunnel.join(1);
+
+        for port in 1..=8 {
+            assert!(tunnel.dispatch(connection(&tunnel, port)).is_ok());
+        }
+        // Smooth weighted round-robin interleaves the light member instead of
+        // sending it a burst after the heavy one.
+        assert_eq!(received(&mut heavy), [1, 2, 4, 5, 6, 8]);
+        assert_eq!(received(&mut light), [3, 7]);
+    }
+
+    #[test]
+    fn unhealthy_members_are_skipped() {
+        let tunnel = tunnel();
+        let mut first = tunnel.join(1);
+        let mut second = tunnel.join(1);
+
+        assert!(first.set_healthy(false));
+        assert!(!first.set_healthy(false));
+        for port in 1..=3 {
+            assert!(tunnel.dispatch(connection(&tunnel, port)).is_ok());
+        }
+        assert!(received(&mut first).is_empty());
+        assert_eq!(received(&mut second), [1, 2, 3]);
+
+        assert!(second.set_healthy(false));
+        assert!(!tunnel.is_available());
+        assert!(tunnel.dispatch(connection(&tunnel, 4)).is_err());
+    }
+
+    #[test]
+    fn standby_only_without_weighted_members() {
+        let tunnel = tunnel();
+        let mut standby = tunnel.join(0);
+        let mut active = tunnel.join(1);
+
+        assert!(tunnel.dispatch(connection(&tunnel, 1)).is_ok());
+        assert!(received(&mut standby).is_empty());
+        assert_eq!(received(&mut active), [1]);
+
+        assert!(active.set_healthy(false));
+        assert!(tunnel.dispatch(connection(&tunnel, 2)).is_ok());
+        assert_eq!(received(&mut standby), [2]);
+
+        assert!(active.set_healthy(true));
+        assert!(tunnel.dispatch(connection(&tunnel, 3)).is_ok());
+        assert_eq!(received(&mut active), [3]);
+
+        drop(active);
+        assert!(tunnel.dispatch(connection(&tunnel, 4)).is_ok());
+        assert_eq!(received(&mut standby), [4]);
+    }
+
+    #[test]
+    fn queued_connections_fail_over_when_a_member_leaves() {
+        let tunnel = tunnel();
+        let leaving = tunnel.join(1);
+        let mut staying = tunnel.join(1);
+
+        for port in 1..=4 {
+            assert!(tunnel.dispatch(connection(&tunnel, port)).is_ok());
+        }
+        assert_eq!(received(&mut staying), [2, 4]);
+
+        drop(leaving);
+        assert_eq!(tunnel.members(), 1);
+        assert_eq!(received(&mut staying), [1, 3]);
+    }
+}
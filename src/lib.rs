@@ -0,0 +1,48 @@
+#![warn(clippy::all, clippy::nursery, clippy::pedantic)]
+#![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+//! A modern, simple TCP tunnel in Rust that exposes local ports to a remote
+//! server, bypassing standard NAT connection firewalls.
+//!
+//! This is the library crate documentation. If you're looking for usage
+//! information about the binary, see the command below.
+//!
+//! ```shell
+//! $ tunneled help
+//! ```
+//!
+//! There are two components to the crate, offering implementations of the
+//! server network daemon and client local forwarding proxy. Both are public
+//! members and can be run programmatically with a Tokio 1.0 runtime.
+//!
+//! ```no_run
+//! use tunneled::core::target::LocalTarget;
+//! use tunneled::{Backend, Client, Server};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let server = Server::builder(40000..=40100).control_port(17835).build()?;
+//! tokio::spawn(server.listen_until(std::future::pending()));
+//!
+//! let target = LocalTarget::Tcp { host: "localhost".into(), port: 3000 };
+//! let client = Client::builder(Backend::local(target))
+//!     .server("localhost")
+//!     .control_port(17835)
+//!     .connect()
+//!     .await?;
+//! println!("Tunnel open on port {}", client.remote_port());
+//!
+//! let mut events = client.subscribe();
+//! tokio::spawn(client.listen());
+//! while let Ok(event) = events.recv().await {
+//!     println!("{event:?}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod commands;
+pub mod core;
+pub mod tui;
+
+pub use crate::commands::local::{Backend, Client, ClientBuilder, ClientError};
+pub use crate::commands::server::{Server, ServerBuilder, ServerError};
+pub use crate::core::events::{ClientEvent, ServerEvent};
@@ -1,108 +1,217 @@
 #![warn(clippy::all, clippy::nursery, clippy::pedantic)]
 #![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
-//! A modern, simple TCP tunnel in Rust that exposes local ports to a remote
-//! server, bypassing standard NAT connection firewalls.
-//!
-//! This is the library crate documentation. If you're looking for usage
-//! information about the binary, see the command below.
-//!
-//! ```shell
-//! $ tunneled help
-//! ```
-//!
-//! There are two components to the crate, offering implementations of the
-//! server network daemon and client local forwarding proxy. Both are public
-//! members and can be run programmatically with a Tokio 1.0 runtime.
-use anyhow::Result;
+//! Command line interface of tunneled, see the library crate for embedding
+//! clients and servers.
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
 use libstrawberry::colors::{BOLD, C_RESET, RED, RESET};
 
-use crate::cli::args::Command;
+use tunneled::commands;
+use tunneled::commands::login::login;
+use tunneled::commands::compose::{self, background, compose};
+use tunneled::commands::server::{read_config_file, TunnelLimits};
+use tunneled::core::acl::{DestinationAcl, ForwardAcl};
+use tunneled::core::auth::Auth;
+use tunneled::core::constants::DEFAULT_CONTROL_PORT;
+use tunneled::core::daemon::DaemonOptions;
+use tunneled::core::outbound::OutboundProxy;
+use tunneled::core::output::Output;
+use tunneled::core::private::hash_password;
+use tunneled::core::quic::QuicCertificate;
+use tunneled::core::shared::TunnelOptions;
+use tunneled::core::target::LocalTarget;
+use tunneled::tui::server::ServerDashboard;
+use tunneled::{Backend, Client, Server, ServerBuilder};
+
+use crate::cli::args::{Command, ComposeAction};
 use crate::cli::{ARGS, OPTIONS};
-use crate::commands::login::login;
-use crate::commands::compose::compose;
-use crate::commands::local::Client;
-use crate::commands::server::{read_config_file, Server};
-use crate::core::auth::Auth;
 
-pub mod cli;
-pub mod commands;
-pub mod core;
+mod cli;
 
 #[tokio::main]
+#[allow(clippy::too_many_lines)]
 async fn main() -> Result<()> {
     match ARGS.command {
-        Command::Local => {
-            let client = Client::new(
-                &OPTIONS.client_options.host,
-                OPTIONS.client_options.port,
-                &OPTIONS.client_options.server,
-                OPTIONS.client_options.secret.as_deref(),
-                OPTIONS.client_options.static_port,
-                OPTIONS.client_options.control_port,
-                OPTIONS.client_options.auth,
-                None,
-            )
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
+        Command::Local | Command::Proxy => {
+            let backend = if matches!(ARGS.command, Command::Proxy) {
+                if OPTIONS.client_options.proxy_rules.is_empty() {
+                    eprintln!("{RED}{BOLD} ! {RESET} Proxy mode needs at least one destination (--allow-dest){C_RESET}");
+                    std::process::exit(1);
+                }
+                Backend::Proxy(DestinationAcl::new(OPTIONS.client_options.proxy_rules.clone()))
+            } else {
+                Backend::local(OPTIONS.client_options.unix_socket.as_ref().map_or_else(
+                    || LocalTarget::Tcp {
+                        host: OPTIONS.client_options.host.clone(),
+                        port: OPTIONS.client_options.port,
+                    },
+                    |path| LocalTarget::Unix(path.into()),
+                ))
+            };
+
+            let output = Arc::new(output());
+            let proxy = OutboundProxy::resolve(OPTIONS.client_options.outbound_proxy.as_deref()).unwrap_or_else(|err| {
+                output.error(None, &format!("{err:#}"));
                 std::process::exit(1)
             });
+            let client = Client::builder(backend)
+                .server(&OPTIONS.client_options.server)
+                .control_port(OPTIONS.client_options.control_port)
+                .secret(OPTIONS.client_options.secret.as_deref())
+                .static_port(OPTIONS.client_options.static_port)
+                .require_auth(OPTIONS.client_options.auth)
+                .options(TunnelOptions {
+                    password: OPTIONS.client_options.password.as_deref().map(hash_password),
+                    protocol: OPTIONS.client_options.protocol,
+                    ttl: OPTIONS.client_options.ttl.map(|ttl| ttl.as_secs()),
+                    share: OPTIONS.client_options.share.clone(),
+                    weight: OPTIONS.client_options.weight,
+                    compression: OPTIONS.client_options.compression,
+                    sources: OPTIONS.client_options.sources.clone(),
+                    ..TunnelOptions::default()
+                })
+                .connection_limits(OPTIONS.client_options.connection_limits)
+                .health_check(OPTIONS.client_options.health_check.clone())
+                .proxy(proxy)
+                .ping_interval(output.ping_interval())
+                .verbosity(output.verbosity(OPTIONS.client_options.verbose_logging))
+                .connect()
+                .await
+                .unwrap_or_else(|err| {
+                    output.error(None, &err);
+                    std::process::exit(1)
+                });
 
-            client.listen().await.unwrap_or_else(|err| {
-                eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
+            output.ready(None, &client);
+            if let Some(dashboard) = output.dashboard() {
+                tokio::spawn(async move {
+                    let result = client.listen().await;
+                    output.stopped(None, &result);
+                });
+                dashboard.run().await?;
+                return Ok(());
+            }
+
+            let result = client.listen().await;
+            output.stopped(None, &result);
+            if let Err(err) = result {
+                std::process::exit(err.exit_code());
+            }
+        }
+        Command::Connect => commands::connect::connect(
+            &OPTIONS.client_options.server,
+            OPTIONS.client_options.port,
+            OPTIONS.client_options.listen_port,
+            OPTIONS.client_options.password.as_deref(),
+            OPTIONS.client_options.verbose_logging,
+        )
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
+            std::process::exit(1)
+        }),
+        Command::Forward => commands::forward::forward(
+            &OPTIONS.client_options.server,
+            OPTIONS.client_options.control_port,
+            OPTIONS.client_options.forward_target.as_deref(),
+            OPTIONS.client_options.listen_port,
+            OPTIONS.client_options.secret.as_deref(),
+            OPTIONS.client_options.password.as_deref(),
+            OPTIONS.client_options.auth,
+            OPTIONS.client_options.verbose_logging,
+        )
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
+            std::process::exit(1)
+        }),
+        Command::Compose => {
+            let path = OPTIONS.client_options.compose_file.as_deref();
+            let file = Path::new(path.unwrap_or(compose::DEFAULT_FILE));
+            let proxy = OPTIONS.client_options.outbound_proxy.as_deref();
+            let profiles = &OPTIONS.client_options.profiles;
+            let output = Arc::new(background::detached().map_or_else(output, |state| output().logging_to(state)));
+            let result = match OPTIONS.client_options.compose_action {
+                ComposeAction::Up if !OPTIONS.client_options.detach_flags.is_empty() => {
+                    if output.dashboard().is_some() {
+                        output.error(None, &"The dashboard cannot run in the background");
+                        std::process::exit(1);
+                    }
+                    match compose::resolve(path, proxy, profiles) {
+                        Ok(_) => {
+                            let args = ARGS.args.iter().enumerate().filter_map(|(position, arg)| {
+                                (!OPTIONS.client_options.detach_flags.contains(&position)).then_some(arg.as_str())
+                            });
+                            background::detach(file, args).await
+                        }
+                        Err(err) => Err(err.context("Failed to read service file")),
+                    }
+                }
+                ComposeAction::Up => {
+                    let services = compose(
+                        path,
+                        proxy,
+                        profiles,
+                        OPTIONS.client_options.verbose_logging,
+                        OPTIONS.client_options.watch,
+                        Arc::clone(&output),
+                    );
+                    if let Some(dashboard) = output.dashboard() {
+                        tokio::spawn(async move {
+                            if let Err(err) = services.await {
+                                output.error(None, &format!("{err:#}"));
+                            }
+                        });
+                        dashboard.run().await?;
+                        return Ok(());
+                    }
+                    services.await
+                }
+                ComposeAction::Ps => background::ps(file, &output).await,
+                ComposeAction::Logs => {
+                    background::logs(
+                        file,
+                        OPTIONS.client_options.compose_service.as_deref(),
+                        OPTIONS.client_options.follow,
+                    )
+                    .await
+                }
+                ComposeAction::Down => background::down(file, &output).await,
+                ComposeAction::Config => compose::config(path, proxy, profiles),
+            };
+            result.unwrap_or_else(|err| {
+                output.error(None, &format!("{err:#}"));
                 std::process::exit(1)
             });
         }
-        Command::Compose => compose(OPTIONS.client_options.compose_file.as_deref())
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
-                std::process::exit(1)
-            }),
         Command::Server => {
-            if let Some(config_file) = OPTIONS.server_options.config_file.as_deref() {
-                let config = read_config_file(config_file).unwrap_or_else(|err| {
+            let server = OPTIONS
+                .server_options
+                .config_file
+                .as_deref()
+                .map_or_else(server_from_args, server_from_config)
+                .and_then(|builder| {
+                    builder
+                        .verbose(OPTIONS.server_options.verbose_logging)
+                        .build()
+                        .map_err(Into::into)
+                })
+                .unwrap_or_else(|err| {
                     eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
                     std::process::exit(1)
                 });
 
-                let port_range = config.server.host.min_port..=config.server.host.max_port;
-                if port_range.is_empty() {
-                    eprintln!("{RED}{BOLD} ! {RESET} Port range is empty{C_RESET}");
-                }
-                Server::new(
-                    port_range,
-                    config.server.auth.secret.as_deref(),
-                    config.server.host.control_port.unwrap_or(7835),
-                    config.server.auth.require_id.unwrap_or(false),
-                    config.server.auth.allow_static_port.unwrap_or_default(),
-                    config
-                        .server
-                        .host
-                        .tunnels_addr
-                        .as_deref()
-                        .unwrap_or("0.0.0.0")
-                        .to_string(),
-                )
-                .listen()
-                .await?;
+            let result = if OPTIONS.server_options.tui {
+                ServerDashboard::run(server).await
             } else {
-                let port_range = OPTIONS.server_options.min_port..=OPTIONS.server_options.max_port;
-                if port_range.is_empty() {
-                    eprintln!("{RED}{BOLD} ! {RESET} Port range is empty{C_RESET}");
-                }
-
-                Server::new(
-                    port_range,
-                    OPTIONS.server_options.secret.as_deref(),
-                    OPTIONS.server_options.control_port,
-                    OPTIONS.server_options.require_id,
-                    Vec::new(),
-                    OPTIONS.server_options.tunnels_addr.clone(),
-                )
-                .listen()
-                .await?;
-            }
+                server.listen().await
+            };
+            result.unwrap_or_else(|err| {
+                eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
+                std::process::exit(1)
+            });
         }
         Command::Login => login(Auth::strawberry_id()).await?,
         Command::About => commands::about::about(),
@@ -112,3 +221,107 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Output of clients as selected on the command line.
+fn output() -> Output {
+    Output::new(
+        OPTIONS.client_options.output,
+        OPTIONS.client_options.assignments_file.as_ref().map(Into::into),
+    )
+}
+
+/// Server configured from a config file, with command line options taking precedence.
+fn server_from_config(config_file: &str) -> Result<ServerBuilder> {
+    let config = read_config_file(config_file).unwrap_or_else(|err| {
+        eprintln!("{RED}{BOLD} ! {C_RESET} {err}");
+        std::process::exit(1)
+    });
+
+    let daemon = config.server.daemon.clone().unwrap_or_default();
+    let limits = config.server.limits.clone().unwrap_or_default();
+    let mut forward_rules = OPTIONS.server_options.forward_rules.clone();
+    if let Some(forward) = &config.server.forward {
+        forward_rules.extend_from_slice(forward.allow.rules());
+    }
+    let mut trusted_proxies = OPTIONS.server_options.trusted_proxies.clone();
+    trusted_proxies.extend_from_slice(&config.server.host.trusted_proxies);
+    let banned = config
+        .server
+        .security
+        .ip_blacklist
+        .iter()
+        .flatten()
+        .map(|ip| {
+            ip.parse().unwrap_or_else(|_| {
+                eprintln!("{RED}{BOLD} ! {RESET} Invalid address '{ip}' in ip-blacklist{C_RESET}");
+                std::process::exit(1)
+            })
+        })
+        .collect();
+    Ok(Server::builder(config.server.host.min_port..=config.server.host.max_port)
+        .secret(config.server.auth.secret.as_deref())
+        .control_port(config.server.host.control_port.unwrap_or(DEFAULT_CONTROL_PORT))
+        .websocket_port(OPTIONS.server_options.websocket_port.or(config.server.host.websocket_port))
+        .trusted_proxies(trusted_proxies)
+        .quic_port(OPTIONS.server_options.quic_port.or(config.server.host.quic_port))
+        .quic_certificate(quic_certificate(
+            OPTIONS.server_options.quic_cert.clone().or(config.server.host.quic_cert),
+            OPTIONS.server_options.quic_key.clone().or(config.server.host.quic_key),
+        )?)
+        .require_id(config.server.auth.require_id.unwrap_or(false))
+        .whitelist_static_port(config.server.auth.allow_static_port.unwrap_or_default())
+        .tunnels_addr(config.server.host.tunnels_addr.as_deref().unwrap_or("0.0.0.0"))
+        .daemon(DaemonOptions {
+            pid_file: OPTIONS.server_options.pid_file.clone().or(daemon.pid_file).map(Into::into),
+            user: OPTIONS.server_options.user.clone().or(daemon.user),
+            group: OPTIONS.server_options.group.clone().or(daemon.group),
+        })
+        .limits(TunnelLimits {
+            max_lifetime: OPTIONS.server_options.max_lifetime.or(limits.max_lifetime),
+            idle_timeout: OPTIONS.server_options.idle_timeout.or(limits.idle_timeout),
+        })
+        .connection_limits(OPTIONS.server_options.connection_limits.or(limits.connection))
+        .forward_acl(ForwardAcl::new(forward_rules))
+        .compression(!OPTIONS.server_options.no_compression && config.server.host.compression.unwrap_or(true))
+        .banned(banned))
+}
+
+/// Server configured from command line options only.
+fn server_from_args() -> Result<ServerBuilder> {
+    Ok(Server::builder(OPTIONS.server_options.min_port..=OPTIONS.server_options.max_port)
+        .secret(OPTIONS.server_options.secret.as_deref())
+        .control_port(OPTIONS.server_options.control_port)
+        .websocket_port(OPTIONS.server_options.websocket_port)
+        .trusted_proxies(OPTIONS.server_options.trusted_proxies.clone())
+        .quic_port(OPTIONS.server_options.quic_port)
+        .quic_certificate(quic_certificate(
+            OPTIONS.server_options.quic_cert.clone(),
+            OPTIONS.server_options.quic_key.clone(),
+        )?)
+        .require_id(OPTIONS.server_options.require_id)
+        .tunnels_addr(&OPTIONS.server_options.tunnels_addr)
+        .daemon(DaemonOptions {
+            pid_file: OPTIONS.server_options.pid_file.as_ref().map(Into::into),
+            user: OPTIONS.server_options.user.clone(),
+            group: OPTIONS.server_options.group.clone(),
+        })
+        .limits(TunnelLimits {
+            max_lifetime: OPTIONS.server_options.max_lifetime,
+            idle_timeout: OPTIONS.server_options.idle_timeout,
+        })
+        .connection_limits(OPTIONS.server_options.connection_limits)
+        .forward_acl(ForwardAcl::new(OPTIONS.server_options.forward_rules.clone()))
+        .compression(!OPTIONS.server_options.no_compression))
+}
+
+/// Certificate for QUIC, which needs both the certificate and its key.
+fn quic_certificate(cert: Option<String>, key: Option<String>) -> Result<Option<QuicCertificate>> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some(QuicCertificate {
+            cert: cert.into(),
+            key: key.into(),
+        })),
+        (None, None) => Ok(None),
+        _ => bail!("QUIC needs both a certificate and a private key"),
+    }
+}
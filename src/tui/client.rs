@@ -0,0 +1,545 @@
+//! Dashboard of the tunnels opened by `local` and `compose`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::Stylize;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Paragraph, Row, Table, TableState};
+use ratatui::Frame;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::commands::local::Client;
+use crate::core::control::{ClientControl, ConnectionInfo};
+use crate::core::events::ClientEvent;
+use crate::core::output::public_host;
+use crate::core::shared::format_duration;
+use crate::tui::{
+    EVENT_HISTORY, MESSAGE_DURATION, REDRAW_INTERVAL, SAMPLE_INTERVAL, Screen, Throughput, block, copy_to_clipboard,
+    format_age, format_bytes, format_rate, header, highlight_style, render_footer, step,
+};
+
+/// Key bindings shown in the footer.
+const KEYS: [(&str, &str); 7] = [
+    ("↑↓", "select"),
+    ("tab", "switch table"),
+    ("c", "copy address"),
+    ("k", "kill connection"),
+    ("p", "pause"),
+    ("r", "reconnect"),
+    ("q", "quit"),
+];
+
+/// State of a tunnel as far as the dashboard knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Online,
+    Unhealthy,
+    Reconnecting,
+    Disconnected,
+    Failed,
+}
+
+/// A tunnel shown on the dashboard.
+#[derive(Debug)]
+struct Tunnel {
+    service: Option<String>,
+    server: String,
+    address: String,
+    port: u16,
+    status: Status,
+    control: ClientControl,
+
+    /// Failed connections and errors reported by the server.
+    errors: u64,
+    rates: HashMap<Uuid, Throughput>,
+}
+
+impl Tunnel {
+    fn name(&self) -> &str {
+        self.service.as_deref().unwrap_or("local")
+    }
+
+    fn public_address(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+
+    /// Whether the client still listens and takes commands.
+    const fn is_running(&self) -> bool {
+        !matches!(self.status, Status::Disconnected | Status::Failed)
+    }
+
+    fn status(&self) -> Span<'static> {
+        match self.status {
+            Status::Online if self.control.is_paused() => "paused".yellow(),
+            Status::Online => "online".green(),
+            Status::Unhealthy => "unhealthy".yellow(),
+            Status::Reconnecting => "reconnecting".yellow(),
+            Status::Disconnected => "disconnected".red(),
+            Status::Failed => "failed".red(),
+        }
+    }
+}
+
+/// A line of the events pane.
+#[derive(Debug)]
+struct LogEntry {
+    time: Instant,
+    service: Option<String>,
+    message: String,
+    error: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    tunnels: Vec<Tunnel>,
+
+    /// Most recent first.
+    events: VecDeque<LogEntry>,
+}
+
+impl State {
+    fn tunnel(&mut self, service: Option<&str>) -> Option<&mut Tunnel> {
+        self.tunnels
+            .iter_mut()
+            .find(|tunnel| tunnel.service.as_deref() == service)
+    }
+
+    fn log(&mut self, service: Option<&str>, message: String, error: bool) {
+        self.events.truncate(EVENT_HISTORY - 1);
+        self.events.push_front(LogEntry {
+            time: Instant::now(),
+            service: service.map(ToString::to_string),
+            message,
+            error,
+        });
+    }
+}
+
+/// Table of the dashboard that arrow keys move in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    #[default]
+    Tunnels,
+    Connections,
+}
+
+/// Selection and messages of the dashboard, only touched by the drawing thread.
+#[derive(Debug, Default)]
+struct View {
+    focus: Focus,
+    tunnels: TableState,
+    connections: TableState,
+    message: Option<(String, Instant)>,
+}
+
+impl View {
+    fn show(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+    }
+}
+
+/// Live dashboard of the tunnels of one or more clients, shown with `--tui`.
+#[derive(Debug, Default)]
+pub struct ClientDashboard {
+    state: Mutex<State>,
+    running: AtomicBool,
+}
+
+impl ClientDashboard {
+    /// Show a tunnel the server opened, and follow its events.
+    pub fn add(self: &Arc<Self>, service: Option<&str>, client: &Client) {
+        let tunnel = Tunnel {
+            service: service.map(ToString::to_string),
+            server: client.server().to_string(),
+            address: public_host(&client.remote_addr(), client.server()).to_string(),
+            port: client.remote_port(),
+            status: Status::Online,
+            control: client.control(),
+            errors: 0,
+            rates: HashMap::new(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.log(service, format!("Listening at {}", tunnel.public_address()), false);
+        state.tunnels.retain(|tunnel| tunnel.service.as_deref() != service);
+        state.tunnels.push(tunnel);
+        drop(state);
+
+        tokio::spawn(Arc::clone(self).watch(service.map(ToString::to_string), client.subscribe()));
+    }
+
+    /// Show a client that stopped, either because it lost the server or failed.
+    pub fn stopped(&self, service: Option<&str>, error: Option<&impl Display>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(tunnel) = state.tunnel(service) {
+            tunnel.status = if error.is_some() { Status::Failed } else { Status::Disconnected };
+        }
+        match error {
+            Some(err) => state.log(service, err.to_string(), true),
+            None => state.log(service, "Server closed the tunnel".to_string(), true),
+        }
+    }
+
+    /// Show an error, of a tunnel if it belongs to a service.
+    pub fn error(&self, service: Option<&str>, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(tunnel) = state.tunnel(service) {
+            tunnel.errors += 1;
+        }
+        state.log(service, message.to_string(), true);
+    }
+
+    /// Stop showing the tunnel of a service.
+    pub fn remove(&self, service: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.tunnels.retain(|tunnel| tunnel.service.as_deref() != Some(service));
+        state.log(Some(service), "Removed".to_string(), false);
+    }
+
+    /// Show a line the backend process of a service printed.
+    pub fn output(&self, service: &str, line: &str, stderr: bool) {
+        self.state.lock().unwrap().log(Some(service), line.to_string(), stderr);
+    }
+
+    /// Show a message that belongs to no tunnel in particular.
+    pub fn info(&self, message: &str) {
+        self.state.lock().unwrap().log(None, message.to_string(), false);
+    }
+
+    /// Whether the dashboard currently owns the terminal.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Show the dashboard until the user quits.
+    pub async fn run(self: Arc<Self>) -> io::Result<()> {
+        tokio::task::spawn_blocking(move || self.show()).await?
+    }
+
+    /// Update the tunnel of a service from its events, until the client stops.
+    async fn watch(self: Arc<Self>, service: Option<String>, mut events: broadcast::Receiver<ClientEvent>) {
+        let service = service.as_deref();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+
+            let mut state = self.state.lock().unwrap();
+            let Some(tunnel) = state.tunnel(service) else {
+                return;
+            };
+            let (message, error) = match event {
+                ClientEvent::ConnectionClosed { id, result: Err(err) } => {
+                    tunnel.errors += 1;
+                    (format!("Connection {} failed: {err}", short_id(id)), true)
+                }
+                ClientEvent::ConnectionOpened { .. } | ClientEvent::ConnectionClosed { .. } => continue,
+                ClientEvent::HealthChanged { healthy: true } => {
+                    tunnel.status = Status::Online;
+                    ("Local service is healthy again".to_string(), false)
+                }
+                ClientEvent::HealthChanged { healthy: false } => {
+                    tunnel.status = Status::Unhealthy;
+                    ("Local service is unhealthy, visitors are turned away".to_string(), true)
+                }
+                ClientEvent::ServerError { message } => {
+                    tunnel.errors += 1;
+                    (format!("Server error: {message}"), true)
+                }
+                ClientEvent::ReconnectFailed { error, retry_in } => {
+                    tunnel.errors += 1;
+                    tunnel.status = Status::Reconnecting;
+                    (format!("Could not reconnect: {error}, retrying in {}", format_duration(retry_in)), true)
+                }
+                ClientEvent::Reconnected { addr, port } => {
+                    tunnel.address = public_host(&addr, &tunnel.server).to_string();
+                    tunnel.port = port;
+                    tunnel.status = Status::Online;
+                    (format!("Reconnected, listening at {}", tunnel.public_address()), false)
+                }
+            };
+            state.log(service, message, error);
+        }
+    }
+
+    /// Take over the terminal, restoring it when the user quits.
+    fn show(&self) -> io::Result<()> {
+        let mut screen = Screen::open()?;
+        self.running.store(true, Ordering::Relaxed);
+        let result = self.interact(&mut screen);
+        self.running.store(false, Ordering::Relaxed);
+        result
+    }
+
+    fn interact(&self, screen: &mut Screen) -> io::Result<()> {
+        let mut view = View::default();
+        view.tunnels.select(Some(0));
+        let mut sampled = Instant::now();
+
+        loop {
+            if sampled.elapsed() >= SAMPLE_INTERVAL {
+                self.sample(sampled.elapsed());
+                sampled = Instant::now();
+            }
+            screen.draw(|frame| self.render(frame, &mut view))?;
+
+            if !event::poll(REDRAW_INTERVAL)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind == KeyEventKind::Press && !self.handle_key(key, &mut view) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Measure the throughput of every connection since the last sample.
+    fn sample(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        for tunnel in &mut state.tunnels {
+            tunnel.rates = tunnel
+                .control
+                .connections()
+                .into_iter()
+                .map(|connection| {
+                    let previous = tunnel.rates.get(&connection.id).copied().unwrap_or_default();
+                    (connection.id, previous.next(connection.sent, connection.received, elapsed))
+                })
+                .collect();
+        }
+    }
+
+    /// Act on a key press, returning whether the dashboard stays open.
+    fn handle_key(&self, key: KeyEvent, view: &mut View) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let tunnel_count = state.tunnels.len();
+        let selected = view.tunnels.selected().filter(|&index| index < tunnel_count);
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Tab | KeyCode::BackTab => {
+                view.focus = match view.focus {
+                    Focus::Tunnels => Focus::Connections,
+                    Focus::Connections => Focus::Tunnels,
+                };
+            }
+            KeyCode::Up | KeyCode::Down => {
+                let down = key.code == KeyCode::Down;
+                match view.focus {
+                    Focus::Tunnels => {
+                        step(&mut view.tunnels, tunnel_count, down);
+                        view.connections.select(None);
+                    }
+                    Focus::Connections => {
+                        let count = selected.map_or(0, |index| state.tunnels[index].control.connections().len());
+                        step(&mut view.connections, count, down);
+                    }
+                }
+            }
+            _ => {
+                let Some(index) = selected else {
+                    return true;
+                };
+                let (message, entry) = act(&mut state.tunnels[index], key.code, view.connections.selected());
+                if let Some(message) = message {
+                    view.show(message);
+                }
+                if let Some(entry) = entry {
+                    let service = state.tunnels[index].service.clone();
+                    state.log(service.as_deref(), entry, false);
+                }
+            }
+        }
+        true
+    }
+
+    fn render(&self, frame: &mut Frame<'_>, view: &mut View) {
+        let state = self.state.lock().unwrap();
+        let height = u16::try_from(state.tunnels.len()).unwrap_or(u16::MAX);
+        let [tunnels_area, connections_area, events_area, footer_area] = Layout::vertical([
+            Constraint::Length(height.saturating_add(3).min(12)),
+            Constraint::Fill(1),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        render_tunnels(frame, tunnels_area, &state.tunnels, view);
+        let selected = view
+            .tunnels
+            .selected()
+            .and_then(|index| state.tunnels.get(index));
+        render_connections(frame, connections_area, selected, view);
+        render_events(frame, events_area, &state.events);
+        drop(state);
+
+        let message = view
+            .message
+            .as_ref()
+            .filter(|(_, shown)| shown.elapsed() < MESSAGE_DURATION)
+            .map(|(message, _)| message.as_str());
+        render_footer(frame, footer_area, &KEYS, message);
+    }
+}
+
+/// Run the action of a key on a tunnel, returning a message for the footer
+/// and an entry for the events pane.
+fn act(tunnel: &mut Tunnel, key: KeyCode, connection: Option<usize>) -> (Option<String>, Option<String>) {
+    if key == KeyCode::Char('c') {
+        let address = tunnel.public_address();
+        return match copy_to_clipboard(&address) {
+            Ok(()) => (Some(format!("Copied {address}")), None),
+            Err(err) => (Some(format!("Could not copy the address: {err}")), None),
+        };
+    }
+    if !matches!(key, KeyCode::Char('k' | 'p' | 'r')) {
+        return (None, None);
+    }
+    if !tunnel.is_running() {
+        return (Some(format!("{} is no longer running", tunnel.name())), None);
+    }
+
+    match key {
+        KeyCode::Char('k') => {
+            let Some(connection) = connection.and_then(|index| tunnel.control.connections().get(index).copied())
+            else {
+                return (Some("Select a connection first, with tab and the arrow keys".to_string()), None);
+            };
+            if !tunnel.control.kill(connection.id) {
+                return (Some(format!("Connection {} is already closed", short_id(connection.id))), None);
+            }
+            let message = format!("Closed connection {}", short_id(connection.id));
+            (Some(message.clone()), Some(message))
+        }
+        KeyCode::Char('p') => {
+            let paused = !tunnel.control.is_paused();
+            tunnel.control.pause(paused);
+            let message = if paused { "Paused, visitors are turned away" } else { "Resumed" };
+            (Some(format!("{} {}", tunnel.name(), message.to_lowercase())), Some(message.to_string()))
+        }
+        _ => {
+            tunnel.status = Status::Reconnecting;
+            tunnel.control.reconnect();
+            (None, Some("Reconnecting".to_string()))
+        }
+    }
+}
+
+fn render_tunnels(frame: &mut Frame<'_>, area: Rect, tunnels: &[Tunnel], view: &mut View) {
+    let rows = tunnels.iter().map(|tunnel| {
+        let received = tunnel.rates.values().map(|rate| rate.received_rate).sum();
+        let sent = tunnel.rates.values().map(|rate| rate.sent_rate).sum();
+        Row::new(vec![
+            Span::from(tunnel.name().to_string()),
+            Span::from(tunnel.public_address()).cyan(),
+            tunnel.status(),
+            Span::from(
+                tunnel
+                    .control
+                    .rtt()
+                    .map_or_else(String::new, |rtt| format!("{} ms", rtt.as_millis())),
+            ),
+            Span::from(tunnel.control.connections().len().to_string()),
+            if tunnel.errors > 0 {
+                Span::from(tunnel.errors.to_string()).red()
+            } else {
+                Span::from("0")
+            },
+            Span::from(format_rate(received)),
+            Span::from(format_rate(sent)),
+        ])
+    });
+
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(16),
+            Constraint::Fill(1),
+            Constraint::Length(12),
+            Constraint::Length(8),
+            Constraint::Length(6),
+            Constraint::Length(7),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ],
+    )
+    .header(header(&["Service", "Public address", "Status", "RTT", "Conns", "Errors", "In", "Out"]))
+    .row_highlight_style(highlight_style())
+    .block(block(" Tunnels ".to_string(), view.focus == Focus::Tunnels));
+    frame.render_stateful_widget(table, area, &mut view.tunnels);
+}
+
+fn render_connections(frame: &mut Frame<'_>, area: Rect, tunnel: Option<&Tunnel>, view: &mut View) {
+    let connections = tunnel.map(|tunnel| tunnel.control.connections()).unwrap_or_default();
+    let rows = connections.iter().map(|connection: &ConnectionInfo| {
+        let rate = tunnel
+            .and_then(|tunnel| tunnel.rates.get(&connection.id))
+            .copied()
+            .unwrap_or_default();
+        Row::new(vec![
+            short_id(connection.id),
+            connection.peer.map_or_else(|| "unknown".to_string(), |peer| peer.to_string()),
+            format_age(connection.opened.elapsed()),
+            format_bytes(connection.received),
+            format_bytes(connection.sent),
+            format_rate(rate.received_rate),
+            format_rate(rate.sent_rate),
+        ])
+    });
+
+    let title = tunnel.map_or_else(|| " Connections ".to_string(), |tunnel| format!(" Connections of {} ", tunnel.name()));
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(10),
+            Constraint::Fill(1),
+            Constraint::Length(6),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ],
+    )
+    .header(header(&["ID", "Peer", "Age", "Received", "Sent", "In", "Out"]))
+    .row_highlight_style(highlight_style())
+    .block(block(title, view.focus == Focus::Connections));
+    frame.render_stateful_widget(table, area, &mut view.connections);
+}
+
+fn render_events(frame: &mut Frame<'_>, area: Rect, events: &VecDeque<LogEntry>) {
+    let lines = events
+        .iter()
+        .take(usize::from(area.height.saturating_sub(2)))
+        .map(|entry| {
+            let message = if entry.error {
+                Span::from(entry.message.as_str()).red()
+            } else {
+                Span::from(entry.message.as_str())
+            };
+            Line::from(vec![
+                Span::from(format!("{:>4} ", format_age(entry.time.elapsed()))).dark_gray(),
+                Span::from(entry.service.as_deref().map_or_else(String::new, |service| format!("{service} "))).bold(),
+                message,
+            ])
+        })
+        .collect::<Vec<_>>();
+    frame.render_widget(Paragraph::new(lines).block(block(" Events ".to_string(), false)), area);
+}
+
+/// First part of a connection ID, enough to tell connections apart.
+fn short_id(id: Uuid) -> String {
+    id.simple().to_string()[..8].to_string()
+}
@@ -0,0 +1,304 @@
+//! Interactive terminal dashboards, enabled with `--tui`.
+//!
+//! Dashboards draw from shared state that the running clients or the server
+//! update. The drawing loop runs on a blocking thread, since the terminal is
+//! read synchronously.
+
+pub mod client;
+pub mod server;
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode};
+use ratatui::layout::Rect;
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, TableState};
+use ratatui::{Frame, Terminal};
+
+/// Time between redraws, and the longest a key press waits to be handled.
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Time between throughput samples.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of events kept for the event pane.
+pub const EVENT_HISTORY: usize = 200;
+
+/// Time a message replaces the key bindings in the footer.
+pub const MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
+/// The terminal while a dashboard is shown on it, restored when dropped.
+pub struct Screen {
+    terminal: Terminal<CrosstermBackend<Box<dyn Write + Send>>>,
+
+    /// Output of the process, led onto the dashboard instead of the terminal.
+    #[cfg(unix)]
+    _captured: Option<capture::CapturedOutput>,
+}
+
+impl Screen {
+    /// Take over the terminal.
+    pub fn open() -> io::Result<Self> {
+        Self::with_writer(
+            Box::new(io::stdout()),
+            #[cfg(unix)]
+            None,
+        )
+    }
+
+    /// Take over the terminal, and pass every line the process writes to
+    /// stdout or stderr to `on_line` instead, e.g. the logs of the server.
+    pub fn capturing(on_line: impl Fn(String) + Send + 'static) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            let (captured, terminal) = capture::CapturedOutput::start(on_line)?;
+            Self::with_writer(Box::new(terminal), Some(captured))
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = on_line;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "This dashboard is only available on Unix",
+            ))
+        }
+    }
+
+    fn with_writer(
+        mut writer: Box<dyn Write + Send>,
+        #[cfg(unix)] captured: Option<capture::CapturedOutput>,
+    ) -> io::Result<Self> {
+        enable_raw_mode()?;
+        execute!(writer, EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(writer))?;
+        terminal.hide_cursor()?;
+        Ok(Self {
+            terminal,
+            #[cfg(unix)]
+            _captured: captured,
+        })
+    }
+
+    pub fn draw(&mut self, render: impl FnOnce(&mut Frame<'_>)) -> io::Result<()> {
+        self.terminal.draw(render).map(|_| ())
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+/// Redirection of stdout and stderr into a pipe.
+#[cfg(unix)]
+mod capture {
+    use std::fs::File;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    /// Original stdout and stderr of the process, while they lead into a pipe.
+    pub struct CapturedOutput {
+        stdout: OwnedFd,
+        stderr: OwnedFd,
+    }
+
+    impl CapturedOutput {
+        /// Redirect stdout and stderr, passing every line to `on_line`.
+        /// Returns the terminal stdout led to before.
+        pub fn start(on_line: impl Fn(String) + Send + 'static) -> io::Result<(Self, File)> {
+            io::stdout().flush()?;
+            io::stderr().flush()?;
+
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for both ends of the pipe.
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the descriptors were just created and are owned by nobody else.
+            let (read, write) = unsafe { (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+            let captured = Self {
+                stdout: io::stdout().as_fd().try_clone_to_owned()?,
+                stderr: io::stderr().as_fd().try_clone_to_owned()?,
+            };
+            let terminal = File::from(captured.stdout.try_clone()?);
+            redirect(&write, libc::STDOUT_FILENO)?;
+            redirect(&write, libc::STDERR_FILENO)?;
+
+            // Ends once stdout and stderr are restored and the pipe has no writer left.
+            std::thread::spawn(move || {
+                for line in BufReader::new(read).lines().map_while(Result::ok) {
+                    on_line(strip_escapes(&line));
+                }
+            });
+            Ok((captured, terminal))
+        }
+    }
+
+    impl Drop for CapturedOutput {
+        fn drop(&mut self) {
+            let _ = io::stdout().flush();
+            let _ = io::stderr().flush();
+            let _ = redirect(&self.stdout, libc::STDOUT_FILENO);
+            let _ = redirect(&self.stderr, libc::STDERR_FILENO);
+        }
+    }
+
+    /// Point a standard descriptor at the file of another one.
+    fn redirect(from: &OwnedFd, to: RawFd) -> io::Result<()> {
+        // SAFETY: `from` is open, and `to` is replaced atomically.
+        if unsafe { libc::dup2(from.as_raw_fd(), to) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Remove the color codes from a log line.
+    fn strip_escapes(line: &str) -> String {
+        let mut stripped = String::with_capacity(line.len());
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            if c != '\x1b' {
+                stripped.push(c);
+                continue;
+            }
+            // Skip `ESC [`, the parameters and the final letter.
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        }
+        stripped
+    }
+}
+
+/// Bytes of a connection at the last sample, and its throughput since the one before.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Throughput {
+    pub sent: u64,
+    pub received: u64,
+    pub sent_rate: f64,
+    pub received_rate: f64,
+}
+
+impl Throughput {
+    /// Next sample, after the connection transferred the given totals in `elapsed`.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn next(&self, sent: u64, received: u64, elapsed: Duration) -> Self {
+        let seconds = elapsed.as_secs_f64();
+        Self {
+            sent,
+            received,
+            sent_rate: sent.saturating_sub(self.sent) as f64 / seconds,
+            received_rate: received.saturating_sub(self.received) as f64 / seconds,
+        }
+    }
+}
+
+/// Style of the selected row in the focused table.
+#[must_use]
+pub const fn highlight_style() -> Style {
+    Style::new().bg(Color::DarkGray).add_modifier(Modifier::BOLD)
+}
+
+/// Move the selection of a table with a number of rows.
+pub fn step(state: &mut TableState, rows: usize, down: bool) {
+    let selected = match (state.selected(), down) {
+        _ if rows == 0 => None,
+        (None, _) => Some(0),
+        (Some(index), true) => Some((index + 1).min(rows - 1)),
+        (Some(index), false) => Some(index.saturating_sub(1).min(rows - 1)),
+    };
+    state.select(selected);
+}
+
+/// Bordered pane with a title, highlighted if it has the focus.
+#[must_use]
+pub fn block(title: String, focused: bool) -> Block<'static> {
+    let block = Block::bordered().title(Line::from(title).bold());
+    if focused {
+        block.border_style(Style::new().fg(Color::Cyan))
+    } else {
+        block
+    }
+}
+
+#[must_use]
+pub fn header(cells: &[&'static str]) -> Row<'static> {
+    Row::new(cells.to_vec()).bold()
+}
+
+/// Put text on the system clipboard of the terminal with an OSC 52 sequence,
+/// which also works over SSH.
+pub fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()
+}
+
+/// Draw the key bindings, or a message in their place.
+pub fn render_footer(frame: &mut Frame<'_>, area: Rect, keys: &[(&str, &str)], message: Option<&str>) {
+    let line = message.map_or_else(
+        || {
+            Line::from(
+                keys.iter()
+                    .flat_map(|(key, action)| [Span::from(format!(" {key} ")).reversed(), Span::from(format!(" {action}  "))])
+                    .collect::<Vec<_>>(),
+            )
+        },
+        |message| Line::from(format!(" {message}")).bold(),
+    );
+    frame.render_widget(Paragraph::new(line), area);
+}
+
+/// Format a number of bytes with a binary unit, like `1.5 MiB`.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Format a transfer rate in bytes per second, empty if nothing moves.
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn format_rate(rate: f64) -> String {
+    if rate < 1.0 {
+        return String::new();
+    }
+    format!("{}/s", format_bytes(rate as u64))
+}
+
+/// Format the age of something in the largest fitting unit, like `3m`.
+#[must_use]
+pub fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
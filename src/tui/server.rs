@@ -0,0 +1,391 @@
+//! Dashboard of a running server, for `tunneled server --tui`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::Stylize;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Paragraph, RenderDirection, Row, Sparkline, Table, TableState};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::commands::server::{Server, ServerError};
+use crate::core::control::{ConnectionInfo, ServerControl, SessionInfo};
+use crate::core::daemon;
+use crate::core::events::ServerEvent;
+use crate::tui::{
+    EVENT_HISTORY, MESSAGE_DURATION, REDRAW_INTERVAL, SAMPLE_INTERVAL, Screen, Throughput, block, format_age,
+    format_bytes, format_rate, header, highlight_style, render_footer, step,
+};
+
+/// Key bindings shown in the footer.
+const KEYS: [(&str, &str); 4] = [("↑↓", "select"), ("d", "disconnect"), ("b", "ban address"), ("q", "quit")];
+
+/// Number of bandwidth samples kept for the graphs, one per second.
+const BANDWIDTH_HISTORY: usize = 300;
+
+/// A client that could not open a tunnel.
+#[derive(Debug)]
+struct Failure {
+    time: Instant,
+    client: SocketAddr,
+    reason: String,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Most recent first.
+    failures: VecDeque<Failure>,
+
+    /// Lines the server logged, most recent first.
+    log: VecDeque<String>,
+
+    rates: HashMap<Uuid, Throughput>,
+
+    /// Bytes per second received from and sent to visitors, most recent first.
+    incoming: VecDeque<u64>,
+    outgoing: VecDeque<u64>,
+}
+
+impl State {
+    fn log(&mut self, line: String) {
+        self.log.truncate(EVENT_HISTORY - 1);
+        self.log.push_front(line);
+    }
+}
+
+/// Selection and messages of the dashboard, only touched by the drawing thread.
+#[derive(Debug, Default)]
+struct View {
+    sessions: TableState,
+    message: Option<(String, Instant)>,
+
+    /// Address waiting for the operator to confirm its ban.
+    ban: Option<IpAddr>,
+}
+
+impl View {
+    fn show(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+    }
+}
+
+/// Live dashboard of the clients of a server, shown with `--tui`.
+#[derive(Debug)]
+pub struct ServerDashboard {
+    control: ServerControl,
+    state: Mutex<State>,
+
+    /// Set once the server stopped, which closes the dashboard.
+    stopped: AtomicBool,
+}
+
+impl ServerDashboard {
+    /// Run a server with the dashboard in place of its logs, until the
+    /// operator quits or the process is asked to shut down.
+    pub async fn run(server: Server) -> Result<(), ServerError> {
+        let dashboard = Arc::new(Self {
+            control: server.control(),
+            state: Mutex::default(),
+            stopped: AtomicBool::new(false),
+        });
+        tokio::spawn(Arc::clone(&dashboard).watch(server.subscribe()));
+
+        let screen = Screen::capturing({
+            let dashboard = Arc::clone(&dashboard);
+            move |line| dashboard.state.lock().unwrap().log(line)
+        })?;
+        let (quit, quitted) = oneshot::channel();
+        let ui = tokio::task::spawn_blocking({
+            let dashboard = Arc::clone(&dashboard);
+            move || {
+                let result = dashboard.interact(screen);
+                let _ = quit.send(());
+                result
+            }
+        });
+
+        let result = server
+            .listen_until(async {
+                tokio::select! {
+                    _ = quitted => {}
+                    () = daemon::shutdown_signal() => {}
+                }
+            })
+            .await;
+        dashboard.stopped.store(true, Ordering::Relaxed);
+        ui.await.map_err(io::Error::other)??;
+        result
+    }
+
+    /// Record failed handshakes, until the server stops.
+    async fn watch(self: Arc<Self>, mut events: broadcast::Receiver<ServerEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(ServerEvent::HandshakeFailed { client, reason }) => {
+                    let mut state = self.state.lock().unwrap();
+                    state.failures.truncate(EVENT_HISTORY - 1);
+                    state.failures.push_front(Failure {
+                        time: Instant::now(),
+                        client,
+                        reason,
+                    });
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// Show the dashboard until the operator quits or the server stops,
+    /// restoring the terminal afterwards.
+    fn interact(&self, mut screen: Screen) -> io::Result<()> {
+        let mut view = View::default();
+        let mut sampled = Instant::now();
+
+        while !self.stopped.load(Ordering::Relaxed) {
+            if sampled.elapsed() >= SAMPLE_INTERVAL {
+                self.sample(sampled.elapsed());
+                sampled = Instant::now();
+            }
+            screen.draw(|frame| self.render(frame, &mut view))?;
+
+            if !event::poll(REDRAW_INTERVAL)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind == KeyEventKind::Press && !self.handle_key(key, &mut view) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Measure the throughput of every connection since the last sample.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn sample(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.rates = self
+            .control
+            .connections()
+            .into_iter()
+            .map(|(_, connection)| {
+                let previous = state.rates.get(&connection.id).copied().unwrap_or_default();
+                (connection.id, previous.next(connection.sent, connection.received, elapsed))
+            })
+            .collect();
+
+        let incoming = state.rates.values().map(|rate| rate.received_rate).sum::<f64>();
+        let outgoing = state.rates.values().map(|rate| rate.sent_rate).sum::<f64>();
+        state.incoming.truncate(BANDWIDTH_HISTORY - 1);
+        state.incoming.push_front(incoming as u64);
+        state.outgoing.truncate(BANDWIDTH_HISTORY - 1);
+        state.outgoing.push_front(outgoing as u64);
+    }
+
+    /// Act on a key press, returning whether the dashboard stays open.
+    fn handle_key(&self, key: KeyEvent, view: &mut View) -> bool {
+        if let Some(ip) = view.ban.take() {
+            if key.code == KeyCode::Char('y') {
+                let disconnected = self.control.ban(ip);
+                let message = format!("Banned {ip} until the server restarts, disconnected {disconnected} client(s)");
+                self.state.lock().unwrap().log(message.clone());
+                view.show(message);
+            } else {
+                view.show(format!("Kept {ip}"));
+            }
+            return true;
+        }
+
+        let sessions = self.control.sessions();
+        let selected = view.sessions.selected().and_then(|index| sessions.get(index));
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Down => step(&mut view.sessions, sessions.len(), key.code == KeyCode::Down),
+            KeyCode::Char('d' | 'b') if selected.is_none() => {
+                view.show("Select a client first, with the arrow keys".to_string());
+            }
+            KeyCode::Char('d') => {
+                let session = selected.unwrap();
+                if self.control.disconnect(session.id) {
+                    let message = format!("Disconnected {}", session.addr);
+                    self.state.lock().unwrap().log(message.clone());
+                    view.show(message);
+                } else {
+                    view.show(format!("{} is already gone", session.addr));
+                }
+            }
+            KeyCode::Char('b') => {
+                let ip = selected.unwrap().addr.ip();
+                view.ban = Some(ip);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn render(&self, frame: &mut Frame<'_>, view: &mut View) {
+        let sessions = self.control.sessions();
+        let height = u16::try_from(sessions.len()).unwrap_or(u16::MAX);
+        let [summary_area, sessions_area, bandwidth_area, bottom_area, footer_area] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(height.saturating_add(3).clamp(5, 16)),
+            Constraint::Length(6),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [failures_area, log_area] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(bottom_area);
+
+        let connections = self.control.connections();
+        let state = self.state.lock().unwrap();
+        self.render_summary(frame, summary_area, &sessions, connections.len());
+        render_sessions(frame, sessions_area, &sessions, &connections, &state.rates, view);
+        render_bandwidth(frame, bandwidth_area, &state);
+        render_failures(frame, failures_area, &state.failures);
+        render_log(frame, log_area, &state.log);
+        drop(state);
+
+        let confirm = view.ban.map(|ip| format!("Ban {ip} and disconnect its clients? y/n"));
+        let message = confirm.or_else(|| {
+            view.message
+                .as_ref()
+                .filter(|(_, shown)| shown.elapsed() < MESSAGE_DURATION)
+                .map(|(message, _)| message.clone())
+        });
+        render_footer(frame, footer_area, &KEYS, message.as_deref());
+    }
+
+    /// Draw the usage of the port range and the totals of the server.
+    fn render_summary(&self, frame: &mut Frame<'_>, area: Rect, sessions: &[SessionInfo], connections: usize) {
+        let range = self.control.port_range();
+        let ports = sessions.iter().map(|session| session.port).collect::<HashSet<_>>();
+        let in_range = ports.iter().filter(|port| range.contains(port)).count();
+        let size = usize::from(*range.end() - *range.start()) + 1;
+        let metrics = self.control.metrics();
+
+        let mut spans = vec![
+            Span::from(format!(" Ports {in_range}/{size} used")).bold(),
+            Span::from(format!(" ({}-{})", range.start(), range.end())).dark_gray(),
+        ];
+        if ports.len() > in_range {
+            spans.push(Span::from(format!(", {} static", ports.len() - in_range)));
+        }
+        spans.push(Span::from(format!(
+            "  ·  {} clients  ·  {connections} connections, {} closed  ·  {} in, {} out  ·  {} static port users  ·  {} banned",
+            sessions.len(),
+            metrics.closed,
+            format_bytes(metrics.bytes_in),
+            format_bytes(metrics.bytes_out),
+            self.control.static_port_users(),
+            self.control.banned().len(),
+        )));
+        frame.render_widget(Paragraph::new(Line::from(spans)), area);
+    }
+}
+
+fn render_sessions(
+    frame: &mut Frame<'_>,
+    area: Rect,
+    sessions: &[SessionInfo],
+    connections: &[(u16, ConnectionInfo)],
+    rates: &HashMap<Uuid, Throughput>,
+    view: &mut View,
+) {
+    let rows = sessions.iter().map(|session| {
+        let tunnel = connections
+            .iter()
+            .filter(|(port, _)| *port == session.port)
+            .map(|(_, connection)| rates.get(&connection.id).copied().unwrap_or_default())
+            .collect::<Vec<_>>();
+        Row::new(vec![
+            Span::from(session.addr.to_string()),
+            Span::from(session.transport.to_string()),
+            session
+                .user
+                .as_deref()
+                .map_or_else(|| Span::from("anonymous").dark_gray(), |user| Span::from(user.to_string())),
+            Span::from(session.port.to_string()).cyan(),
+            Span::from(session.name.clone().unwrap_or_default()),
+            Span::from(format_age(session.opened.elapsed())),
+            Span::from(tunnel.len().to_string()),
+            Span::from(format_rate(tunnel.iter().map(|rate| rate.received_rate).sum())),
+            Span::from(format_rate(tunnel.iter().map(|rate| rate.sent_rate).sum())),
+        ])
+    });
+
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Fill(1),
+            Constraint::Length(10),
+            Constraint::Length(16),
+            Constraint::Length(6),
+            Constraint::Length(16),
+            Constraint::Length(6),
+            Constraint::Length(6),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ],
+    )
+    .header(header(&["Client", "Transport", "User", "Port", "Shared as", "Age", "Conns", "In", "Out"]))
+    .row_highlight_style(highlight_style())
+    .block(block(format!(" Clients ({}) ", sessions.len()), true));
+    frame.render_stateful_widget(table, area, &mut view.sessions);
+}
+
+fn render_bandwidth(frame: &mut Frame<'_>, area: Rect, state: &State) {
+    let [incoming_area, outgoing_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(area);
+    for (history, title, area) in [
+        (&state.incoming, "In", incoming_area),
+        (&state.outgoing, "Out", outgoing_area),
+    ] {
+        #[allow(clippy::cast_precision_loss)]
+        let current = history.front().map_or(0.0, |&rate| rate as f64);
+        let title = format!(" {title} {} ", format_rate(current));
+        let sparkline = Sparkline::default()
+            .block(block(title, false))
+            .data(history.iter().copied())
+            .direction(RenderDirection::RightToLeft);
+        frame.render_widget(sparkline, area);
+    }
+}
+
+fn render_failures(frame: &mut Frame<'_>, area: Rect, failures: &VecDeque<Failure>) {
+    let lines = failures
+        .iter()
+        .take(usize::from(area.height.saturating_sub(2)))
+        .map(|failure| {
+            Line::from(vec![
+                Span::from(format!("{:>4} ", format_age(failure.time.elapsed()))).dark_gray(),
+                Span::from(format!("{} ", failure.client.ip())).bold(),
+                Span::from(failure.reason.as_str()).red(),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let title = format!(" Handshake failures ({}) ", failures.len());
+    frame.render_widget(Paragraph::new(lines).block(block(title, false)), area);
+}
+
+fn render_log(frame: &mut Frame<'_>, area: Rect, log: &VecDeque<String>) {
+    let lines = log
+        .iter()
+        .take(usize::from(area.height.saturating_sub(2)))
+        .map(|line| Line::from(line.as_str()))
+        .collect::<Vec<_>>();
+    frame.render_widget(Paragraph::new(lines).block(block(" Log ".to_string(), false)), area);
+}
+